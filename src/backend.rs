@@ -0,0 +1,148 @@
+use std::{path::Path, process::Command};
+
+use anyhow::{anyhow, bail, Result};
+use inkwell::{
+    module::Module,
+    passes::{PassBuilderOptions, PassManager, PassManagerBuilder},
+    targets::{
+        CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine, TargetTriple,
+    },
+    OptimizationLevel,
+};
+
+use crate::options::{OptLevel, Options};
+
+/// The garbage collector that `--gc` programs are linked against.
+const GC_RUNTIME: &str = include_str!("runtime/gc.c");
+
+/// Runs LLVM's standard function and module pipelines over the module, as
+/// clang would for the same level. Nothing is run at `-O0`.
+pub fn optimize(module: &Module, opt_level: OptLevel) {
+    let (level, size_level, inline_threshold) = match opt_level {
+        OptLevel::O0 => return,
+        OptLevel::O1 => (OptimizationLevel::Less, 0, 225),
+        OptLevel::O2 => (OptimizationLevel::Default, 0, 225),
+        OptLevel::O3 => (OptimizationLevel::Aggressive, 0, 275),
+        OptLevel::Os => (OptimizationLevel::Default, 1, 75),
+    };
+
+    let builder = PassManagerBuilder::create();
+    builder.set_optimization_level(level);
+    builder.set_size_level(size_level);
+    builder.set_inliner_with_threshold(inline_threshold);
+
+    // The generator spills every tuple to an alloca, so promote those to
+    // registers before anything else looks at the function.
+    let function_passes = PassManager::create(module);
+    function_passes.add_promote_memory_to_register_pass();
+    builder.populate_function_pass_manager(&function_passes);
+    function_passes.initialize();
+    for function in module.get_functions() {
+        function_passes.run_on(&function);
+    }
+    function_passes.finalize();
+
+    let module_passes = PassManager::create(());
+    builder.populate_module_pass_manager(&module_passes);
+    module_passes.run_on(module);
+}
+
+/// Turns the calls in functions using the collector into statepoints, so
+/// that the stack maps say where the live boxes are at each of them. This
+/// must come after any optimization, which could otherwise lose track of
+/// them.
+pub fn rewrite_statepoints(module: &Module, machine: &TargetMachine) -> Result<()> {
+    module
+        .run_passes("rewrite-statepoints-for-gc", machine, PassBuilderOptions::create())
+        .map_err(|e| anyhow!(e.to_string()))
+}
+
+fn codegen_level(opt_level: OptLevel) -> OptimizationLevel {
+    match opt_level {
+        OptLevel::O0 => OptimizationLevel::None,
+        OptLevel::O1 => OptimizationLevel::Less,
+        OptLevel::O2 | OptLevel::Os => OptimizationLevel::Default,
+        OptLevel::O3 => OptimizationLevel::Aggressive,
+    }
+}
+
+/// Creates a machine for the target, CPU and features given in the options,
+/// defaulting to the host. Modules should take their triple and data layout
+/// from it (see `Generator::set_target`) before anything is generated, so
+/// that struct layouts match the target.
+pub fn target_machine(options: &Options) -> Result<TargetMachine> {
+    let config = InitializationConfig::default();
+    Target::initialize_native(&config).map_err(|e| anyhow!(e))?;
+    // --target can also name these, whatever the host is.
+    Target::initialize_x86(&config);
+    Target::initialize_aarch64(&config);
+
+    let (triple, cpu, features) = match &options.target {
+        Some(target) => (
+            TargetTriple::create(target),
+            options.cpu.clone().unwrap_or_else(|| "generic".to_string()),
+            options.features.clone().unwrap_or_default(),
+        ),
+        None => (
+            TargetMachine::get_default_triple(),
+            options
+                .cpu
+                .clone()
+                .unwrap_or_else(|| TargetMachine::get_host_cpu_name().to_string()),
+            options
+                .features
+                .clone()
+                .unwrap_or_else(|| TargetMachine::get_host_cpu_features().to_string()),
+        ),
+    };
+
+    let target = Target::from_triple(&triple).map_err(|e| anyhow!(e.to_string()))?;
+    target
+        .create_target_machine(
+            &triple,
+            &cpu,
+            &features,
+            codegen_level(options.opt_level),
+            RelocMode::PIC,
+            CodeModel::Default,
+        )
+        .ok_or_else(|| anyhow!("Could not create a target machine for {}", triple))
+}
+
+/// Compiles the module to an object file and links it into an executable
+/// with the system C compiler, which also pulls in libc. When cross-compiling,
+/// `cc` must be able to link for the target; set `CC` to a cross linker such
+/// as `aarch64-linux-gnu-gcc`.
+///
+/// With a heap limit, the garbage collector's runtime is compiled and linked
+/// in too. It reads the stack maps through absolute addresses, so the
+/// executable is not position independent.
+pub fn link_executable(
+    module: &Module,
+    machine: &TargetMachine,
+    output: &Path,
+    gc_heap_limit: Option<u64>,
+) -> Result<()> {
+    let object = output.with_extension("o");
+    machine
+        .write_to_file(module, FileType::Object, &object)
+        .map_err(|e| anyhow!(e.to_string()))?;
+
+    let linker = std::env::var("CC").unwrap_or_else(|_| "cc".to_string());
+    let mut command = Command::new(&linker);
+    command.arg(&object);
+    if let Some(heap_limit) = gc_heap_limit {
+        let runtime = output.with_extension("gc.c");
+        std::fs::write(&runtime, GC_RUNTIME)?;
+        command
+            .args(["-O2", "-fno-omit-frame-pointer", "-no-pie"])
+            .arg(format!("-DSCRAP_HEAP_LIMIT={}", heap_limit))
+            .arg(&runtime);
+    }
+    let status = command.arg("-o").arg(output).status()?;
+    if !status.success() {
+        bail!("Linking {} failed", output.display());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,199 @@
+use std::fmt::Write;
+
+use crate::sir;
+
+/// Renders a C header declaring every exported global. Tuples become structs
+/// whose members have the same types, in the same order, as the LLVM structs
+/// built by `Generator::type_to_llvm`, so that C lays them out identically.
+/// Tuple parameters are passed as `const` pointers, and non-primitive results
+/// are written through a trailing `out` pointer, just as
+/// `declare_global_function` declares them. Doc comments are copied onto the
+/// prototypes, and the header starts with the rules for who owns what.
+pub fn c_header(module: &sir::Module, guard: &str) -> String {
+    let mut exports: Vec<_> = module
+        .globals
+        .iter()
+        .filter(|(_, global)| global.export)
+        .collect();
+    exports.sort_by_key(|(name, _)| name.as_str());
+
+    let mut header = CHeader {
+        typedefs: String::new(),
+        declared: Vec::new(),
+    };
+    let prototypes: Vec<_> = exports
+        .iter()
+        .map(|(name, global)| header.prototype(name, global))
+        .collect();
+
+    let mut out = String::new();
+    writeln!(out, "#ifndef {}", guard).unwrap();
+    writeln!(out, "#define {}", guard).unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "#include <stdint.h>").unwrap();
+    writeln!(out).unwrap();
+    write!(out, "{}", OWNERSHIP).unwrap();
+    writeln!(out).unwrap();
+    if !header.typedefs.is_empty() {
+        write!(out, "{}", header.typedefs).unwrap();
+    }
+    for prototype in prototypes {
+        writeln!(out, "{};", prototype).unwrap();
+    }
+    writeln!(out).unwrap();
+    writeln!(out, "#endif").unwrap();
+    out
+}
+
+/// How boxes and function values pass between C and scrap, which follows
+/// `Generator::write_call`: arguments are released by the caller once the
+/// call returns, and results are new references.
+const OWNERSHIP: &str = "\
+/*
+ * Arguments are only borrowed for the call: scrap retains whatever it keeps.
+ * Boxes and function values that are returned, directly or through `out`,
+ * come with a reference that the caller owns. A function value `f` is
+ * released with
+ *
+ *     if (f->release) f->release(f);
+ *
+ * which does nothing for a global used as a value. Boxes can't be released
+ * from C, so those returned to it are never freed. Under --gc, nothing needs
+ * releasing, but values returned to C may be collected by any later call
+ * into scrap.
+ */
+";
+
+struct CHeader {
+    typedefs: String,
+    /// The names of the typedefs written so far, in order.
+    declared: Vec<String>,
+}
+
+impl CHeader {
+    fn prototype(&mut self, name: &str, global: &sir::Global) -> String {
+        let mut params: Vec<_> = global
+            .arguments
+            .iter()
+            .map(|(argument, data_type)| self.param(data_type, argument))
+            .collect();
+
+        let return_type = match &global.return_type {
+            t @ sir::DataType::Primitive(_) => self.c_type(t),
+            t => {
+                params.push(format!("{} *out", self.c_type(t)));
+                "void".to_string()
+            }
+        };
+
+        if params.is_empty() {
+            params.push("void".to_string());
+        }
+        let mut prototype = String::new();
+        for line in global.doc.iter().flat_map(|doc| doc.lines()) {
+            if line.is_empty() {
+                prototype.push_str("//\n");
+            } else {
+                writeln!(prototype, "// {}", line).unwrap();
+            }
+        }
+        write!(prototype, "{} {}({})", return_type, global.symbol(name), params.join(", ")).unwrap();
+        prototype
+    }
+
+    fn param(&mut self, data_type: &sir::DataType, name: &str) -> String {
+        match data_type {
+            sir::DataType::Primitive(_) => format!("{} {}", self.c_type(data_type), name),
+            t => format!("const {} *{}", self.c_type(t), name),
+        }
+    }
+
+    /// The C spelling of a type, writing a typedef for it first if needed.
+    fn c_type(&mut self, data_type: &sir::DataType) -> String {
+        match data_type {
+            sir::DataType::Primitive(sir::PrimitiveDataType::I32) => "int32_t".to_string(),
+            sir::DataType::Primitive(sir::PrimitiveDataType::I64) => "int64_t".to_string(),
+            sir::DataType::Primitive(sir::PrimitiveDataType::Box(_)) => "void *".to_string(),
+            sir::DataType::Primitive(sir::PrimitiveDataType::Function {
+                argument_types,
+                return_type,
+            }) => {
+                // Function values point at closures, which C calls by
+                // passing the closure to its code, and releases by passing
+                // it to its release function, if it has one (see
+                // `closure_type`). What the closure captured follows, and
+                // is private to scrap.
+                let name = typedef_name(data_type);
+                if !self.declared.contains(&name) {
+                    let mut params: Vec<_> = argument_types
+                        .iter()
+                        .enumerate()
+                        .map(|(i, t)| self.param(t, &format!("arg_{}", i)))
+                        .collect();
+                    let c_return_type = match return_type.as_ref() {
+                        t @ sir::DataType::Primitive(_) => self.c_type(t),
+                        t => {
+                            params.push(format!("{} *out", self.c_type(t)));
+                            "void".to_string()
+                        }
+                    };
+                    params.push(format!("const struct {} *closure", name));
+                    writeln!(
+                        self.typedefs,
+                        "/* {} */\ntypedef struct {} {{\n    {} (*code)({});\n    \
+                         void (*release)(const struct {} *closure);\n}} {};\n",
+                        data_type,
+                        name,
+                        c_return_type,
+                        params.join(", "),
+                        name,
+                        name
+                    )
+                    .unwrap();
+                    self.declared.push(name.clone());
+                }
+                format!("const {} *", name)
+            }
+            sir::DataType::Tuple(elems) => {
+                let name = typedef_name(data_type);
+                if !self.declared.contains(&name) {
+                    let fields: Vec<_> = elems
+                        .iter()
+                        .enumerate()
+                        .map(|(i, t)| format!("    {} elem_{};\n", self.c_type(t), i))
+                        .collect();
+                    writeln!(
+                        self.typedefs,
+                        "/* {} */\ntypedef struct {} {{\n{}}} {};\n",
+                        data_type,
+                        name,
+                        fields.concat(),
+                        name
+                    )
+                    .unwrap();
+                    self.declared.push(name.clone());
+                }
+                name
+            }
+        }
+    }
+}
+
+/// A C identifier for a type, derived from its mangled name so that it is
+/// stable between compilations.
+fn typedef_name(data_type: &sir::DataType) -> String {
+    let mut mangled = String::new();
+    data_type.mangle(&mut mangled).unwrap();
+    let name: String = mangled
+        .chars()
+        .map(|c| match c {
+            '{' => 'T',
+            '}' => 'E',
+            '(' => 'A',
+            ')' => 'R',
+            ',' | '.' => '_',
+            c => c,
+        })
+        .collect();
+    format!("scrap_{}", name)
+}
@@ -0,0 +1,261 @@
+use std::{collections::HashMap, path::Path, rc::Rc};
+
+use inkwell::{
+    basic_block::BasicBlock,
+    context::Context,
+    debug_info::{
+        AsDIScope, DICompileUnit, DIFile, DIFlags, DIFlagsConstants, DILocation,
+        DISubprogram, DIType, DWARFEmissionKind, DWARFSourceLanguage, DebugInfoBuilder,
+    },
+    module::{FlagBehavior, Module},
+    targets::{TargetData, TargetMachine},
+    types::BasicTypeEnum,
+    values::{FunctionValue, PointerValue},
+};
+
+use crate::sir;
+
+const DW_ATE_ADDRESS: u32 = 0x01;
+const DW_ATE_SIGNED: u32 = 0x05;
+
+/// Builds DWARF metadata for a module: a compile unit for the program, a
+/// subprogram for each global, and variables for their parameters. Each type
+/// is described along with the LLVM type built for it by
+/// `Generator::type_to_llvm`, and takes its size and layout from the target
+/// machine's data layout, so that the debugger sees values where the
+/// generated code puts them.
+pub struct DebugInfo<'ctx> {
+    context: &'ctx Context,
+    builder: DebugInfoBuilder<'ctx>,
+    compile_unit: DICompileUnit<'ctx>,
+    target_data: TargetData,
+    files: HashMap<Rc<str>, DIFile<'ctx>>,
+    /// Types described so far, by mangled name.
+    types: HashMap<String, (DIType<'ctx>, u64, u32)>,
+}
+
+impl<'ctx> DebugInfo<'ctx> {
+    pub fn new(context: &'ctx Context, module: &Module<'ctx>, input: &Path, machine: &TargetMachine) -> Self {
+        let i32_type = context.i32_type();
+        module.add_basic_value_flag(
+            "Debug Info Version",
+            FlagBehavior::Warning,
+            i32_type.const_int(3, false),
+        );
+        module.add_basic_value_flag("Dwarf Version", FlagBehavior::Warning, i32_type.const_int(4, false));
+
+        let (file_name, directory) = split_path(&input.display().to_string());
+        let (builder, compile_unit) = module.create_debug_info_builder(
+            true,
+            DWARFSourceLanguage::C,
+            &file_name,
+            &directory,
+            "scrap",
+            false,
+            "",
+            0,
+            "",
+            DWARFEmissionKind::Full,
+            0,
+            false,
+            false,
+            "",
+            "",
+        );
+
+        Self {
+            context,
+            builder,
+            compile_unit,
+            target_data: machine.get_target_data(),
+            files: HashMap::new(),
+            types: HashMap::new(),
+        }
+    }
+
+    /// Attaches a subprogram describing the global to its function, and
+    /// returns it as the scope for the function's locations. The LLVM types
+    /// are those of the global's arguments and result.
+    pub fn subprogram(
+        &mut self,
+        func: FunctionValue<'ctx>,
+        name: &str,
+        global: &sir::Global,
+        argument_types: &[BasicTypeEnum<'ctx>],
+        return_type: BasicTypeEnum<'ctx>,
+    ) -> DISubprogram<'ctx> {
+        let file = self.file(&global.location.file);
+        let parameter_types: Vec<_> = global
+            .arguments
+            .iter()
+            .zip(argument_types)
+            .map(|((_, data_type), llvm_type)| self.data_type(data_type, *llvm_type))
+            .collect();
+        let return_type = self.data_type(&global.return_type, return_type);
+        let subroutine_type =
+            self.builder
+                .create_subroutine_type(file, Some(return_type), &parameter_types, DIFlags::ZERO);
+
+        let subprogram = self.builder.create_function(
+            self.compile_unit.as_debug_info_scope(),
+            name,
+            Some(&global.symbol(name)),
+            file,
+            global.location.line,
+            subroutine_type,
+            !global.public && !global.export,
+            true,
+            global.location.line,
+            DIFlags::PUBLIC,
+            false,
+        );
+        func.set_subprogram(subprogram);
+        subprogram
+    }
+
+    /// Describes a parameter, whose value has the given LLVM type and lives
+    /// at `storage`, declaring it at the end of `block`.
+    pub fn parameter(
+        &mut self,
+        subprogram: DISubprogram<'ctx>,
+        global: &sir::Global,
+        index: usize,
+        llvm_type: BasicTypeEnum<'ctx>,
+        storage: PointerValue<'ctx>,
+        block: BasicBlock<'ctx>,
+    ) {
+        let (name, data_type) = &global.arguments[index];
+        let file = self.file(&global.location.file);
+        let data_type = self.data_type(data_type, llvm_type);
+        let variable = self.builder.create_parameter_variable(
+            subprogram.as_debug_info_scope(),
+            name,
+            index as u32 + 1,
+            file,
+            global.location.line,
+            data_type,
+            true,
+            DIFlags::ZERO,
+        );
+        let location = self.location(subprogram, &global.location);
+        self.builder
+            .insert_declare_at_end(storage, Some(variable), None, location, block);
+    }
+
+    pub fn location(&self, scope: DISubprogram<'ctx>, location: &sir::Location) -> DILocation<'ctx> {
+        self.builder.create_debug_location(
+            self.context,
+            location.line,
+            location.column,
+            scope.as_debug_info_scope(),
+            None,
+        )
+    }
+
+    fn file(&mut self, path: &Rc<str>) -> DIFile<'ctx> {
+        if let Some(file) = self.files.get(path) {
+            return *file;
+        }
+        let (file_name, directory) = split_path(path);
+        let file = self.builder.create_file(&file_name, &directory);
+        self.files.insert(path.clone(), file);
+        file
+    }
+
+    fn data_type(&mut self, data_type: &sir::DataType, llvm_type: BasicTypeEnum<'ctx>) -> DIType<'ctx> {
+        self.describe(data_type, llvm_type).0
+    }
+
+    /// The debug type for a data type whose LLVM type is `llvm_type`, along
+    /// with its size and alignment in bits.
+    fn describe(&mut self, data_type: &sir::DataType, llvm_type: BasicTypeEnum<'ctx>) -> (DIType<'ctx>, u64, u32) {
+        let mut mangled = String::new();
+        data_type.mangle(&mut mangled).unwrap();
+        if let Some(described) = self.types.get(&mangled) {
+            return *described;
+        }
+
+        let size = self.target_data.get_abi_size(&llvm_type) * 8;
+        let align = self.target_data.get_abi_alignment(&llvm_type) * 8;
+        let debug_type = match data_type {
+            sir::DataType::Primitive(sir::PrimitiveDataType::I32) => self.basic_type("I32", size),
+            sir::DataType::Primitive(sir::PrimitiveDataType::I64) => self.basic_type("I64", size),
+            // Debuggers only need to show function values and boxes as
+            // addresses.
+            sir::DataType::Primitive(sir::PrimitiveDataType::Function { .. } | sir::PrimitiveDataType::Box(_)) => self
+                .builder
+                .create_basic_type(&data_type.to_string(), size, DW_ATE_ADDRESS, DIFlags::PUBLIC)
+                .unwrap()
+                .as_type(),
+            sir::DataType::Tuple(elems) => {
+                let file = self.compile_unit.get_file();
+                let scope = self.compile_unit.as_debug_info_scope();
+                let struct_type = llvm_type.into_struct_type();
+                let mut members = Vec::new();
+                for (i, elem) in elems.iter().enumerate() {
+                    let field_type = struct_type.get_field_type_at_index(i as u32).unwrap();
+                    let (elem_type, elem_size, elem_align) = self.describe(elem, field_type);
+                    let offset = self.target_data.offset_of_element(&struct_type, i as u32).unwrap() * 8;
+                    let member = self.builder.create_member_type(
+                        scope,
+                        &format!("elem_{}", i),
+                        file,
+                        0,
+                        elem_size,
+                        elem_align,
+                        offset,
+                        DIFlags::PUBLIC,
+                        elem_type,
+                    );
+                    members.push(member.as_type());
+                }
+                self.builder
+                    .create_struct_type(
+                        scope,
+                        &data_type.to_string(),
+                        file,
+                        0,
+                        size,
+                        align,
+                        DIFlags::PUBLIC,
+                        None,
+                        &members,
+                        0,
+                        None,
+                        &mangled,
+                    )
+                    .as_type()
+            }
+        };
+        let described = (debug_type, size, align);
+        self.types.insert(mangled, described);
+        described
+    }
+
+    fn basic_type(&self, name: &str, size_in_bits: u64) -> DIType<'ctx> {
+        self.builder
+            .create_basic_type(name, size_in_bits, DW_ATE_SIGNED, DIFlags::PUBLIC)
+            .unwrap()
+            .as_type()
+    }
+
+    /// Resolves forward references in the metadata. Must be called once
+    /// everything has been generated, before the module is written.
+    pub fn finalize(&self) {
+        self.builder.finalize();
+    }
+}
+
+/// Splits a path into the file name and directory that DWARF records.
+fn split_path(path: &str) -> (String, String) {
+    let path = Path::new(path);
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let directory = path
+        .parent()
+        .map(|parent| parent.display().to_string())
+        .unwrap_or_default();
+    (file_name, directory)
+}
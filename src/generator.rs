@@ -1,36 +1,597 @@
-use std::{fmt::Write, mem::{replace, swap}};
+use std::{collections::HashMap, fmt::Write, mem::{replace, swap, take}, path::Path};
 
+use anyhow::{anyhow, bail, Result};
 use inkwell::{
+    attributes::{Attribute, AttributeLoc},
     builder::Builder,
     context::Context,
-    module::Module,
-    types::{BasicType, BasicTypeEnum},
-    values::{BasicValue, BasicValueEnum, CallableValue, FunctionValue, PointerValue, BasicMetadataValueEnum},
-    AddressSpace, intrinsics::Intrinsic,
+    debug_info::DISubprogram,
+    module::{Linkage, Module},
+    targets::TargetMachine,
+    types::{BasicType, BasicTypeEnum, FunctionType, PointerType, StructType},
+    values::{BasicValue, BasicValueEnum, CallableValue, FunctionValue, GlobalValue, IntValue, PointerValue, BasicMetadataValueEnum},
+    AddressSpace, IntPredicate, intrinsics::Intrinsic,
 };
 
-use crate::sir;
+use crate::{
+    debug_info::DebugInfo,
+    options::Overflow,
+    sir,
+    visit::{self, Bindings},
+};
+
+const C_CALL_CONV: u32 = 0;
+
+/// The routine that generated code calls to panic (see `write_panic_routine`).
+const PANIC_ROUTINE: &str = "scrap_panic";
+/// The routine that allocates boxes (see `write_alloc_routine`).
+const ALLOC_ROUTINE: &str = "scrap_alloc";
+/// The routine that counts a new reference to a box (see
+/// `write_retain_routine`).
+const RETAIN_ROUTINE: &str = "scrap_retain";
+/// The routine that frees a box once nothing refers to it (see
+/// `write_free_routine`).
+const FREE_ROUTINE: &str = "scrap_free";
+/// A box's reference count is kept in the bytes just before its contents.
+const BOX_HEADER_SIZE: u64 = 8;
+/// The routine that releases a closure, whatever it captured (see
+/// `write_release_closure_routine`).
+const RELEASE_CLOSURE_ROUTINE: &str = "scrap_release_closure";
+/// The fields of a closure (see `closure_type`).
+const CLOSURE_CODE: u32 = 0;
+const CLOSURE_RELEASE: u32 = 1;
+const CLOSURE_CAPTURES: u32 = 2;
+/// Static closures start with a reference count this high, so that they are
+/// never freed.
+const STATIC_REFERENCE_COUNT: u64 = 1 << 62;
+/// The garbage collector's entry points, which are defined by the runtime in
+/// `runtime/gc.c`.
+const GC_ALLOC_ROUTINE: &str = "scrap_gc_alloc";
+const GC_PUSH_ROOT_ROUTINE: &str = "scrap_gc_push_root";
+const GC_POP_ROOTS_ROUTINE: &str = "scrap_gc_pop_roots";
+/// The LLVM GC strategy that functions using the collector are marked with.
+/// Under it, pointers in address space 1 are the ones to track.
+const GC_STRATEGY: &str = "statepoint-example";
+const GC_ADDRESS_SPACE: u16 = 1;
+/// The status a program exits with when it panics. This is the same as for
+/// Rust programs, and distinct from the usage error `main` exits with.
+const PANIC_EXIT_STATUS: u64 = 101;
 
 pub struct Generator<'ctx> {
     context: &'ctx Context,
     module: Module<'ctx>,
     builder: Builder<'ctx>,
 
+    globals: HashMap<String, FunctionValue<'ctx>>,
     current_function: Option<FunctionValue<'ctx>>,
+    /// The values of the enclosing scopes, outermost first. Non-primitive
+    /// values are pointers, as for function parameters.
+    locals: Vec<BasicValueEnum<'ctx>>,
+    /// How many uses of each local are still to be generated (see
+    /// `write_local_use`).
+    local_uses: Vec<usize>,
+    /// Temporary values in the current function that hold boxes, which are
+    /// released when it returns.
+    temporaries: Vec<(PointerValue<'ctx>, sir::DataType)>,
+    /// The boxes being built in the current function, innermost last: the
+    /// type of their contents, and a stack slot in which an `unbox` of the
+    /// last reference to a box of that type leaves it to be reused (see
+    /// `write_unbox_release`).
+    reuse_slots: Vec<(sir::DataType, PointerValue<'ctx>)>,
+    /// Release functions that have been declared but not yet written (see
+    /// `release_function`).
+    unwritten_releases: Vec<(FunctionValue<'ctx>, sir::DataType)>,
+    /// Thunks for partial applications that have been declared but not yet
+    /// written (see `write_partial_application`).
+    unwritten_thunks: Vec<Thunk<'ctx>>,
+    /// Whether boxes are left to the garbage collector (see `enable_gc`).
+    gc: bool,
+    /// How many stack slots the current function has made roots.
+    roots: u64,
+
+    debug_info: Option<DebugInfo<'ctx>>,
+    current_subprogram: Option<DISubprogram<'ctx>>,
+    overflow: Overflow,
 }
 
 impl<'ctx> Generator<'ctx> {
-    pub fn new(context: &'ctx Context) -> Self {
-        Self {
+    pub fn new(context: &'ctx Context, module_name: &str) -> Result<Self> {
+        let generator = Self {
             context,
-            module: context.create_module("scrap"),
+            module: context.create_module(module_name),
             builder: context.create_builder(),
+            globals: HashMap::new(),
             current_function: None,
+            locals: Vec::new(),
+            local_uses: Vec::new(),
+            temporaries: Vec::new(),
+            reuse_slots: Vec::new(),
+            unwritten_releases: Vec::new(),
+            unwritten_thunks: Vec::new(),
+            gc: false,
+            roots: 0,
+            debug_info: None,
+            current_subprogram: None,
+            overflow: Overflow::Trap,
+        };
+        generator.write_panic_routine();
+        generator.write_alloc_routine();
+        generator.write_retain_routine();
+        generator.write_free_routine();
+        generator.write_release_closure_routine()?;
+        Ok(generator)
+    }
+
+    /// Defines `void *scrap_alloc(int64_t size)`, which allocates a box with
+    /// a reference count of one, returning null if there is no memory.
+    fn write_alloc_routine(&self) {
+        let i64_type = self.context.i64_type();
+        let i8_ptr_type = self.context.i8_type().ptr_type(AddressSpace::default());
+        let func_type = i8_ptr_type.fn_type(&[i64_type.into()], false);
+        let func = self.module.add_function(ALLOC_ROUTINE, func_type, Some(Linkage::Internal));
+        let size = func.get_nth_param(0).unwrap().into_int_value();
+
+        let entry_block = self.context.append_basic_block(func, "entry");
+        let fail_block = self.context.append_basic_block(func, "fail");
+        let init_block = self.context.append_basic_block(func, "init");
+
+        self.builder.position_at_end(entry_block);
+        let malloc = self.libc_function("malloc", func_type);
+        let total = self
+            .builder
+            .build_int_add(size, i64_type.const_int(BOX_HEADER_SIZE, false), "");
+        let header = self
+            .builder
+            .build_call(malloc, &[total.into()], "header")
+            .try_as_basic_value()
+            .unwrap_left()
+            .into_pointer_value();
+        let is_null = self.builder.build_is_null(header, "");
+        self.builder.build_conditional_branch(is_null, fail_block, init_block);
+
+        self.builder.position_at_end(fail_block);
+        self.builder.build_return(Some(&i8_ptr_type.const_null()));
+
+        self.builder.position_at_end(init_block);
+        let count = self
+            .builder
+            .build_bitcast(header, i64_type.ptr_type(AddressSpace::default()), "")
+            .into_pointer_value();
+        self.builder.build_store(count, i64_type.const_int(1, false));
+        let offset = i64_type.const_int(BOX_HEADER_SIZE, false);
+        let contents = unsafe { self.builder.build_in_bounds_gep(header, &[offset], "box") };
+        self.builder.build_return(Some(&contents));
+    }
+
+    /// Defines `void scrap_retain(void *box)`, which counts another
+    /// reference to a box, if it isn't null.
+    fn write_retain_routine(&self) {
+        let i8_ptr_type = self.context.i8_type().ptr_type(AddressSpace::default());
+        let func_type = self.context.void_type().fn_type(&[i8_ptr_type.into()], false);
+        let func = self.module.add_function(RETAIN_ROUTINE, func_type, Some(Linkage::Internal));
+        let memory = func.get_nth_param(0).unwrap().into_pointer_value();
+
+        let entry_block = self.context.append_basic_block(func, "entry");
+        let count_block = self.context.append_basic_block(func, "count");
+        let done_block = self.context.append_basic_block(func, "done");
+
+        self.builder.position_at_end(entry_block);
+        let is_null = self.builder.build_is_null(memory, "");
+        self.builder.build_conditional_branch(is_null, done_block, count_block);
+
+        self.builder.position_at_end(count_block);
+        let count = self.build_reference_count(memory);
+        let value = self.builder.build_load(count, "").into_int_value();
+        let value = self
+            .builder
+            .build_int_add(value, value.get_type().const_int(1, false), "");
+        self.builder.build_store(count, value);
+        self.builder.build_unconditional_branch(done_block);
+
+        self.builder.position_at_end(done_block);
+        self.builder.build_return(None);
+    }
+
+    /// Defines `void scrap_free(void *box)`, which frees a box.
+    fn write_free_routine(&self) {
+        let i64_type = self.context.i64_type();
+        let i8_ptr_type = self.context.i8_type().ptr_type(AddressSpace::default());
+        let func_type = self.context.void_type().fn_type(&[i8_ptr_type.into()], false);
+        let func = self.module.add_function(FREE_ROUTINE, func_type, Some(Linkage::Internal));
+        let memory = func.get_nth_param(0).unwrap().into_pointer_value();
+
+        let entry_block = self.context.append_basic_block(func, "entry");
+        self.builder.position_at_end(entry_block);
+        let offset = i64_type.const_int((BOX_HEADER_SIZE as i64).wrapping_neg() as u64, true);
+        let header = unsafe { self.builder.build_in_bounds_gep(memory, &[offset], "header") };
+        let free = self.libc_function("free", func_type);
+        self.builder.build_call(free, &[header.into()], "");
+        self.builder.build_return(None);
+    }
+
+    /// Defines `void scrap_release_closure(void *closure)`, which releases a
+    /// function value by calling the release function that its closure
+    /// holds. Static closures have none.
+    fn write_release_closure_routine(&self) -> Result<()> {
+        let i64_type = self.context.i64_type();
+        let i8_ptr_type = self.context.i8_type().ptr_type(AddressSpace::default());
+        let func_type = self.context.void_type().fn_type(&[i8_ptr_type.into()], false);
+        let func = self
+            .module
+            .add_function(RELEASE_CLOSURE_ROUTINE, func_type, Some(Linkage::Internal));
+        let closure = func.get_nth_param(0).unwrap().into_pointer_value();
+
+        let entry_block = self.context.append_basic_block(func, "entry");
+        let load_block = self.context.append_basic_block(func, "load");
+        let release_block = self.context.append_basic_block(func, "release");
+        let done_block = self.context.append_basic_block(func, "done");
+
+        self.builder.position_at_end(entry_block);
+        let is_null = self.builder.build_is_null(closure, "");
+        self.builder.build_conditional_branch(is_null, done_block, load_block);
+
+        self.builder.position_at_end(load_block);
+        let header_type = self.type_to_llvm(&closure_type(Vec::new()));
+        let header = self
+            .builder
+            .build_bitcast(closure, header_type.ptr_type(AddressSpace::default()), "")
+            .into_pointer_value();
+        let release = self.build_field(header, CLOSURE_RELEASE)?;
+        let release = self.builder.build_load(release, "release").into_int_value();
+        let is_static = self
+            .builder
+            .build_int_compare(IntPredicate::EQ, release, i64_type.const_zero(), "");
+        self.builder.build_conditional_branch(is_static, done_block, release_block);
+
+        self.builder.position_at_end(release_block);
+        let release = self
+            .builder
+            .build_int_to_ptr(release, func_type.ptr_type(AddressSpace::default()), "");
+        let release = callable(release)?;
+        self.builder.build_call(release, &[closure.into()], "");
+        self.builder.build_unconditional_branch(done_block);
+
+        self.builder.position_at_end(done_block);
+        self.builder.build_return(None);
+        Ok(())
+    }
+
+    /// A pointer to the reference count of a (non-null) box.
+    fn build_reference_count(&self, memory: PointerValue<'ctx>) -> PointerValue<'ctx> {
+        let i64_type = self.context.i64_type();
+        let offset = i64_type.const_int((BOX_HEADER_SIZE as i64).wrapping_neg() as u64, true);
+        let header = unsafe { self.builder.build_in_bounds_gep(memory, &[offset], "") };
+        self.builder
+            .build_bitcast(header, i64_type.ptr_type(AddressSpace::default()), "count")
+            .into_pointer_value()
+    }
+
+    /// Defines `void scrap_panic(char *message, char *file, int32_t line,
+    /// int32_t column)`, which reports a panic on stderr and exits. It has
+    /// internal linkage, so every module has its own copy, which is dropped
+    /// if nothing panics.
+    fn write_panic_routine(&self) {
+        let i32_type = self.context.i32_type();
+        let i8_ptr_type = self.context.i8_type().ptr_type(AddressSpace::default());
+        let func_type = self.context.void_type().fn_type(
+            &[i8_ptr_type.into(), i8_ptr_type.into(), i32_type.into(), i32_type.into()],
+            false,
+        );
+        let func = self.module.add_function(PANIC_ROUTINE, func_type, Some(Linkage::Internal));
+        func.add_attribute(AttributeLoc::Function, self.enum_attribute("noreturn"));
+        func.add_attribute(AttributeLoc::Function, self.enum_attribute("cold"));
+
+        let entry_block = self.context.append_basic_block(func, "entry");
+        self.builder.position_at_end(entry_block);
+        let format = self.builder.build_global_string_ptr("%s:%d:%d: %s\n", "panic_format");
+        let dprintf = self.libc_function(
+            "dprintf",
+            i32_type.fn_type(&[i32_type.into(), i8_ptr_type.into()], true),
+        );
+        let param = |i| func.get_nth_param(i).unwrap().into();
+        self.builder.build_call(
+            dprintf,
+            &[
+                i32_type.const_int(2, false).into(),
+                format.as_pointer_value().into(),
+                param(1),
+                param(2),
+                param(3),
+                param(0),
+            ],
+            "",
+        );
+        let exit = self.libc_function("exit", self.context.void_type().fn_type(&[i32_type.into()], false));
+        self.builder
+            .build_call(exit, &[i32_type.const_int(PANIC_EXIT_STATUS, false).into()], "");
+        self.builder.build_unreachable();
+    }
+
+    /// Sets what the operators that don't say how to overflow do when they
+    /// do. Generated code traps by default.
+    pub fn set_overflow(&mut self, overflow: Overflow) {
+        self.overflow = overflow;
+    }
+
+    /// Sets the module's triple and data layout to the machine's. This must
+    /// happen before anything is generated, since `write_clone` sizes structs
+    /// using the data layout.
+    pub fn set_target(&self, machine: &TargetMachine) {
+        self.module.set_triple(&machine.get_triple());
+        self.module
+            .set_data_layout(&machine.get_target_data().get_data_layout());
+    }
+
+    /// Leaves boxes to the tracing garbage collector in `runtime/gc.c`
+    /// instead of counting references to them. This must happen before
+    /// anything is generated, since boxes become pointers into the
+    /// collector's address space, and `backend::rewrite_statepoints` must be
+    /// run over the finished module.
+    pub fn enable_gc(&mut self) {
+        self.gc = true;
+
+        let i8_ptr_type = self.context.i8_type().ptr_type(AddressSpace::default());
+        let i64_type = self.context.i64_type();
+        let void_type = self.context.void_type();
+        self.module.add_function(
+            GC_ALLOC_ROUTINE,
+            self.box_type().fn_type(&[i8_ptr_type.into()], false),
+            Some(Linkage::External),
+        );
+        // Registering roots can't collect, so these calls don't need to be
+        // statepoints.
+        let leaf = self.context.create_string_attribute("gc-leaf-function", "");
+        for (name, func_type) in [
+            (GC_PUSH_ROOT_ROUTINE, void_type.fn_type(&[i8_ptr_type.into(), i8_ptr_type.into()], false)),
+            (GC_POP_ROOTS_ROUTINE, void_type.fn_type(&[i64_type.into()], false)),
+        ] {
+            let func = self.module.add_function(name, func_type, Some(Linkage::External));
+            func.add_attribute(AttributeLoc::Function, leaf);
+        }
+        // LLVM labels the stack maps with a local symbol; the runtime needs
+        // to find it.
+        self.module.set_inline_assembly(".globl __LLVM_StackMaps");
+    }
+
+    /// Emits DWARF debug info for every global written from now on, in a
+    /// compile unit named after the program's input path. Types are laid out
+    /// as `machine` lays them out, which must be the machine passed to
+    /// `set_target`.
+    pub fn enable_debug_info(&mut self, input: &Path, machine: &TargetMachine) {
+        self.debug_info = Some(DebugInfo::new(self.context, &self.module, input, machine));
+    }
+
+    /// Declares a global under its mangled symbol (see `sir::Global::symbol`).
+    /// Globals that are neither public nor exported get internal linkage, so
+    /// that LLVM is free to inline and strip them. Like every function, exported
+    /// globals have LLVM's default calling convention, which is C's. Their tuple
+    /// parameters are marked as non-null, read-only pointers to C structs laid
+    /// out by `type_to_llvm`.
+    pub fn declare_global(&mut self, name: String, global: &sir::Global) -> Result<()> {
+        let symbol = global.symbol(&name);
+        let func = if global.arguments.is_empty() {
+            self.declare_global_constant(&symbol, &global.return_type)
+        } else {
+            self.declare_global_function(&symbol, &global.arguments, &global.return_type)
+        };
+
+        if !global.public && !global.export {
+            func.set_linkage(Linkage::Internal);
+        }
+
+        if self.gc {
+            func.set_gc(GC_STRATEGY);
+        }
+
+        if global.export {
+            let nonnull = self.enum_attribute("nonnull");
+            let readonly = self.enum_attribute("readonly");
+            for (i, (_, data_type)) in global.arguments.iter().enumerate() {
+                if !data_type.is_primitive() {
+                    func.add_attribute(AttributeLoc::Param(i as u32), nonnull);
+                    func.add_attribute(AttributeLoc::Param(i as u32), readonly);
+                }
+            }
+            if !global.return_type.is_primitive() {
+                func.add_attribute(AttributeLoc::Param(global.arguments.len() as u32), nonnull);
+            }
+        }
+
+        if !global.arguments.is_empty() {
+            self.declare_static_closure(func, &global.data_type(), func.get_linkage())?;
+        }
+
+        self.globals.insert(name, func);
+        Ok(())
+    }
+
+    /// Declares a C function under its source name with the C calling
+    /// convention. Tuple parameters are passed as pointers to structs, which
+    /// `type_to_llvm` lays out exactly as C would.
+    pub fn declare_extern(&mut self, name: String, declaration: &sir::Extern) -> Result<()> {
+        if !declaration.return_type.is_primitive() {
+            bail!(
+                "extern {} must return a primitive type, not {}",
+                name,
+                declaration.return_type
+            );
+        }
+
+        let func = match self.module.get_function(&name) {
+            Some(func) => func,
+            None => self.declare_global_function(&name, &declaration.arguments, &declaration.return_type),
+        };
+        func.set_call_conventions(C_CALL_CONV);
+
+        // Every module that uses an extern as a value has its own closure
+        // for it.
+        if self.module.get_global(&closure_symbol(&name)).is_none() {
+            self.declare_static_closure(func, &declaration.data_type(), Linkage::Internal)?;
+            self.write_static_closure(func)?;
+        }
+
+        self.globals.insert(name, func);
+        Ok(())
+    }
+
+    /// Declares the closure that refers to a function global when it is
+    /// used as a value, and the code in it, which calls the global. Both are
+    /// defined by `write_static_closure`.
+    fn declare_static_closure(
+        &self,
+        func: FunctionValue<'ctx>,
+        function_type: &sir::DataType,
+        linkage: Linkage,
+    ) -> Result<()> {
+        let (argument_types, return_type) = function_signature(function_type)?;
+        let symbol = func.get_name().to_string_lossy().into_owned();
+        let argument_types: Vec<_> = argument_types.iter().collect();
+        let code_type = self.function_type(&argument_types, return_type, true);
+        let code = self.module.add_function(&code_symbol(&symbol), code_type, Some(linkage));
+        if self.gc {
+            code.set_gc(GC_STRATEGY);
+        }
+        let closure = self.module.add_global(self.static_closure_type(), None, &closure_symbol(&symbol));
+        closure.set_linkage(linkage);
+        Ok(())
+    }
+
+    /// A static closure is laid out like a box holding a closure with no
+    /// captures, with a header that is never freed or collected.
+    fn static_closure_type(&self) -> StructType<'ctx> {
+        let i64_type = self.context.i64_type();
+        let header_type = if self.gc {
+            let i8_ptr_type = self.context.i8_type().ptr_type(AddressSpace::default());
+            self.context
+                .struct_type(&[i8_ptr_type.into(), i8_ptr_type.into(), i64_type.into()], false)
+        } else {
+            self.context.struct_type(&[i64_type.into()], false)
+        };
+        let contents_type = self.type_to_llvm(&closure_type(Vec::new()));
+        self.context.struct_type(&[header_type.into(), contents_type], false)
+    }
+
+    /// Defines a global's static closure, and its code, which passes its
+    /// arguments straight on to the global.
+    fn write_static_closure(&self, func: FunctionValue<'ctx>) -> Result<()> {
+        let symbol = func.get_name().to_string_lossy().into_owned();
+        let code = self.declared_function(&code_symbol(&symbol))?;
+        let entry_block = self.context.append_basic_block(code, "entry");
+        self.builder.position_at_end(entry_block);
+        let params = code.get_params();
+        let arguments = call_arguments(&params[..params.len() - 1]);
+        let result = self.builder.build_call(func, &arguments, "").try_as_basic_value().left();
+        match result {
+            Some(result) => self.builder.build_return(Some(&result)),
+            None => self.builder.build_return(None),
+        };
+
+        let i64_type = self.context.i64_type();
+        let header = if self.gc {
+            // The collector marks the closure, but never sweeps it, since it
+            // is not on its list of objects.
+            let i8_ptr_type = self.context.i8_type().ptr_type(AddressSpace::default());
+            self.context.const_struct(
+                &[
+                    i8_ptr_type.const_null().into(),
+                    self.layout(&closure_type(Vec::new())).into(),
+                    i64_type.const_zero().into(),
+                ],
+                false,
+            )
+        } else {
+            self.context
+                .const_struct(&[i64_type.const_int(STATIC_REFERENCE_COUNT, false).into()], false)
+        };
+        let code_address = code.as_global_value().as_pointer_value().const_to_int(i64_type);
+        let contents = self
+            .context
+            .const_struct(&[code_address.into(), i64_type.const_zero().into()], false);
+        let closure = self.declared_global(&closure_symbol(&symbol))?;
+        closure.set_initializer(&self.context.const_struct(&[header.into(), contents.into()], false));
+        Ok(())
+    }
+
+    /// The value of a function global: a pointer to its static closure's
+    /// contents.
+    fn static_closure(&self, name: &str) -> Result<PointerValue<'ctx>> {
+        let symbol = self.globals[name].get_name().to_string_lossy().into_owned();
+        let closure = self.declared_global(&closure_symbol(&symbol))?.as_pointer_value();
+        let i32_type = self.context.i32_type();
+        let contents = unsafe { closure.const_in_bounds_gep(&[i32_type.const_zero(), i32_type.const_int(1, false)]) };
+        let contents = contents.const_cast(self.context.i8_type().ptr_type(AddressSpace::default()));
+        if self.gc {
+            Ok(contents.const_address_space_cast(self.box_type()))
+        } else {
+            Ok(contents)
         }
     }
 
+    /// Whether a global is a function, with a static closure, rather than a
+    /// constant, which may itself hold a closure.
+    fn is_function_global(&self, name: &str) -> bool {
+        let symbol = self.globals[name].get_name().to_string_lossy().into_owned();
+        self.module.get_global(&closure_symbol(&symbol)).is_some()
+    }
+
+    /// Writes the body of a declared global, and checks it with LLVM's
+    /// verifier. Invalid IR is a bug in the generator, but it is reported as
+    /// an error against the global rather than left to crash later passes.
+    pub fn write_global(&mut self, name: &str, global: &sir::Global) -> Result<()> {
+        let func = self.globals[name];
+        if !global.arguments.is_empty() {
+            self.write_static_closure(func)?;
+        }
+        let argument_types: Vec<_> = global
+            .arguments
+            .iter()
+            .map(|(_, data_type)| self.type_to_llvm(data_type))
+            .collect();
+        let return_type = self.type_to_llvm(&global.return_type);
+        self.current_subprogram = self
+            .debug_info
+            .as_mut()
+            .map(|debug_info| debug_info.subprogram(func, name, global, &argument_types, return_type));
+        self.set_debug_location(&global.location);
 
-    pub fn declare_global_constant(&mut self, name: String, data_type: &sir::DataType) {
+        if global.arguments.is_empty() {
+            if global.return_type.is_primitive() {
+                self.write_global_primitive_constant(name, &global.body)?;
+            } else {
+                self.write_global_nonprimitive_constant(name, &global.body)?;
+            }
+        } else {
+            self.write_global_function(name, global)?;
+        }
+
+        self.current_subprogram = None;
+        self.builder.unset_current_debug_location();
+
+        if let Some(message) = self.verifier_message(func) {
+            bail!(
+                "{}: internal compiler error: generated invalid LLVM IR for {}: {}",
+                global.location,
+                name,
+                message
+            );
+        }
+        Ok(())
+    }
+
+    /// What LLVM's verifier has to say about a function that has just been
+    /// written, if it is invalid.
+    fn verifier_message(&self, func: FunctionValue<'ctx>) -> Option<String> {
+        if func.verify(false) {
+            return None;
+        }
+        // Only the module's verifier reports why.
+        match self.module.verify() {
+            Err(message) => Some(message.to_string()),
+            Ok(()) => Some("no reason given".to_string()),
+        }
+    }
+
+    fn declare_global_constant(&mut self, symbol: &str, data_type: &sir::DataType) -> FunctionValue<'ctx> {
         let func_type = match data_type {
             sir::DataType::Primitive(t) => self.primitive_type_to_llvm(t).fn_type(&[], false),
             t => self
@@ -38,58 +599,84 @@ impl<'ctx> Generator<'ctx> {
                 .void_type()
                 .fn_type(&[self.type_to_llvm_reference(t).into()], false),
         };
-        self.module.add_function(&name, func_type, None);
+        self.module.add_function(symbol, func_type, None)
     }
 
-    pub fn write_global_primitive_constant(&mut self, name: &str, value: &sir::Expression) {
-        let func = self.module.get_function(name).unwrap();
+    pub fn write_global_primitive_constant(&mut self, name: &str, value: &sir::Expression) -> Result<()> {
+        let func = self.globals[name];
 
         let entry_block = self.context.append_basic_block(func, "entry");
 
+        self.current_function = Some(func);
+
         self.builder.position_at_end(entry_block);
-        let result = self.write_expression(value);
+        let result = self.write_expression(value)?;
+        self.write_function_exit()?;
         self.builder.build_return(Some(&result));
+
+        self.current_function = None;
+        Ok(())
     }
 
-    pub fn write_global_nonprimitive_constant(&mut self, name: &str, value: &sir::Expression) {
-        let func = self.module.get_function(name).unwrap();
+    pub fn write_global_nonprimitive_constant(&mut self, name: &str, value: &sir::Expression) -> Result<()> {
+        let func = self.globals[name];
 
         let entry_block = self.context.append_basic_block(func, "entry");
 
+        self.current_function = Some(func);
+
         self.builder.position_at_end(entry_block);
         self.write_expression_into(
             value,
             func.get_nth_param(0).unwrap().into_pointer_value(),
-        );
+        )?;
+        self.write_function_exit()?;
         self.builder.build_return(None);
+
+        self.current_function = None;
+        Ok(())
     }
 
-    pub fn declare_global_function(
+    fn declare_global_function(
         &mut self,
-        name: String,
+        symbol: &str,
         arguments: &[(String, sir::DataType)],
         return_type: &sir::DataType,
-    ) {
-        let mut param_types: Vec<_> = arguments
+    ) -> FunctionValue<'ctx> {
+        let argument_types: Vec<_> = arguments.iter().map(|(_, data_type)| data_type).collect();
+        let func_type = self.function_type(&argument_types, return_type, false);
+        self.module.add_function(symbol, func_type, None)
+    }
+
+    /// The LLVM type of a function. Non-primitive arguments are passed as
+    /// pointers, and a non-primitive result is written through a trailing
+    /// `out` pointer. Closures' code takes the closure after everything else.
+    fn function_type(
+        &self,
+        argument_types: &[&sir::DataType],
+        return_type: &sir::DataType,
+        closure: bool,
+    ) -> FunctionType<'ctx> {
+        let mut param_types: Vec<_> = argument_types
             .iter()
-            .map(|(_, data_type)| self.type_to_llvm_reference(data_type).into())
+            .map(|data_type| self.type_to_llvm_reference(data_type).into())
             .collect();
+        if !return_type.is_primitive() {
+            param_types.push(self.type_to_llvm_reference(return_type).into());
+        }
+        if closure {
+            param_types.push(self.box_type().into());
+        }
 
-        let func_type = match return_type {
-            sir::DataType::Primitive(t) => {
-                self.primitive_type_to_llvm(t).fn_type(&param_types, false)
-            }
-            t => {
-                param_types.push(self.type_to_llvm_reference(t).into());
-                self.context.void_type().fn_type(&param_types, false)
-            }
-        };
-
-        self.module.add_function(&name, func_type, None);
+        match return_type {
+            sir::DataType::Primitive(t) => self.primitive_type_to_llvm(t).fn_type(&param_types, false),
+            _ => self.context.void_type().fn_type(&param_types, false),
+        }
     }
 
-    pub fn write_global_function(&mut self, name: &str, value: &sir::Expression) {
-        let func = self.module.get_function(name).unwrap();
+    pub fn write_global_function(&mut self, name: &str, global: &sir::Global) -> Result<()> {
+        let func = self.globals[name];
+        let value = &global.body;
 
         let entry_block = self.context.append_basic_block(func, "entry");
 
@@ -97,17 +684,265 @@ impl<'ctx> Generator<'ctx> {
 
         self.builder.position_at_end(entry_block);
 
+        let argument_types: Vec<_> = global
+            .arguments
+            .iter()
+            .map(|(_, data_type)| self.type_to_llvm(data_type))
+            .collect();
+        if let (Some(debug_info), Some(subprogram)) = (self.debug_info.as_mut(), self.current_subprogram) {
+            // Tuple parameters already point at their value; primitives are
+            // spilled so that the debugger has somewhere to find them.
+            for (i, (_, data_type)) in global.arguments.iter().enumerate() {
+                let param = func.get_nth_param(i as u32).unwrap();
+                let storage = if data_type.is_primitive() {
+                    let slot = self.builder.build_alloca(param.get_type(), "");
+                    self.builder.build_store(slot, param);
+                    slot
+                } else {
+                    param.into_pointer_value()
+                };
+                debug_info.parameter(subprogram, global, i, argument_types[i], storage, entry_block);
+            }
+        }
+
         eprintln!("{:?}", value.data_type());
         if value.data_type().is_primitive() {
-            let result = self.write_expression(value);
+            let result = self.write_expression(value)?;
+            self.write_function_exit()?;
             self.builder.build_return(Some(&result));
         } else {
             let out = func.get_last_param().unwrap().into_pointer_value();
-            self.write_expression_into(value, out);
+            self.write_expression_into(value, out)?;
+            self.write_function_exit()?;
             self.builder.build_return(None);
         }
 
         self.current_function = None;
+        Ok(())
+    }
+
+    /// Emits a C `main(argc, argv)` that calls the named global. Each of the
+    /// global's parameters must be an `I64`, and is parsed from the matching
+    /// command-line argument; if one isn't a number, the usage is printed
+    /// and the program exits with 2. An `I64` or `I32` result becomes the exit status;
+    /// any other result is printed to stdout and the program exits with 0.
+    pub fn write_entry_point(&mut self, name: &str, global: &sir::Global) -> Result<()> {
+        let non_i64 = global
+            .arguments
+            .iter()
+            .find(|(_, data_type)| !matches!(data_type, sir::DataType::Primitive(sir::PrimitiveDataType::I64)));
+        if let Some((argument, data_type)) = non_i64 {
+            bail!("Parameter {} of entry point {} must be I64, not {}", argument, name, data_type);
+        }
+
+        let i32_type = self.context.i32_type();
+        let i8_ptr_type = self.context.i8_type().ptr_type(AddressSpace::default());
+        let argv_type = i8_ptr_type.ptr_type(AddressSpace::default());
+        let main = self.module.add_function(
+            "main",
+            i32_type.fn_type(&[i32_type.into(), argv_type.into()], false),
+            None,
+        );
+        let argc = main.get_nth_param(0).unwrap().into_int_value();
+        let argv = main.get_nth_param(1).unwrap().into_pointer_value();
+
+        let entry_block = self.context.append_basic_block(main, "entry");
+        let run_block = self.context.append_basic_block(main, "run");
+        let usage_block = self.context.append_basic_block(main, "usage");
+
+        self.builder.position_at_end(entry_block);
+        let expected_argc = i32_type.const_int(global.arguments.len() as u64 + 1, false);
+        let argc_ok = self.builder.build_int_compare(IntPredicate::EQ, argc, expected_argc, "");
+        self.builder.build_conditional_branch(argc_ok, run_block, usage_block);
+
+        self.builder.position_at_end(usage_block);
+        let usage: String = global
+            .arguments
+            .iter()
+            .map(|(argument, _)| format!(" <{}>", argument))
+            .collect();
+        let usage = self.builder.build_global_string_ptr(&format!("usage: %s{}\n", usage), "usage");
+        let program = self.build_argv_load(argv, 0);
+        let dprintf = self.libc_function(
+            "dprintf",
+            i32_type.fn_type(&[i32_type.into(), i8_ptr_type.into()], true),
+        );
+        self.builder.build_call(
+            dprintf,
+            &[i32_type.const_int(2, false).into(), usage.as_pointer_value().into(), program.into()],
+            "",
+        );
+        self.builder.build_return(Some(&i32_type.const_int(2, false)));
+
+        self.builder.position_at_end(run_block);
+        let strtoll = self.libc_function(
+            "strtoll",
+            self.context.i64_type().fn_type(&[i8_ptr_type.into(), argv_type.into(), i32_type.into()], false),
+        );
+        let end_slot = self.builder.build_alloca(i8_ptr_type, "end");
+        let errno = self.build_errno();
+        let mut arguments: Vec<BasicMetadataValueEnum<'ctx>> = Vec::new();
+        for i in 0..global.arguments.len() {
+            let argument = self.build_argv_load(argv, i + 1);
+            self.builder.build_store(errno, i32_type.const_zero());
+            let value = self.builder
+                .build_call(
+                    strtoll,
+                    &[argument.into(), end_slot.into(), i32_type.const_int(10, false).into()],
+                    "",
+                )
+                .try_as_basic_value()
+                .unwrap_left();
+            arguments.push(value.into());
+
+            // The whole argument must be a number that fits in an I64.
+            let end = self.builder.build_load(end_slot, "").into_pointer_value();
+            let is_empty = self.builder.build_int_compare(
+                IntPredicate::EQ,
+                self.builder.build_ptr_to_int(end, self.context.i64_type(), ""),
+                self.builder.build_ptr_to_int(argument, self.context.i64_type(), ""),
+                "",
+            );
+            let rest = self.builder.build_load(end, "").into_int_value();
+            let has_rest = self.builder.build_int_compare(IntPredicate::NE, rest, rest.get_type().const_zero(), "");
+            let error = self.builder.build_load(errno, "").into_int_value();
+            let has_error = self.builder.build_int_compare(IntPredicate::NE, error, i32_type.const_zero(), "");
+            let invalid = self.builder.build_or(self.builder.build_or(is_empty, has_rest, ""), has_error, "");
+            let parsed_block = self.context.append_basic_block(main, "parsed");
+            self.builder.build_conditional_branch(invalid, usage_block, parsed_block);
+            self.builder.position_at_end(parsed_block);
+        }
+
+        let func = self.globals[name];
+        let status = match &global.return_type {
+            sir::DataType::Primitive(sir::PrimitiveDataType::I64) => {
+                let result = self.builder
+                    .build_call(func, &arguments, "")
+                    .try_as_basic_value()
+                    .unwrap_left()
+                    .into_int_value();
+                self.builder.build_int_truncate(result, i32_type, "")
+            }
+            sir::DataType::Primitive(sir::PrimitiveDataType::I32) => self.builder
+                .build_call(func, &arguments, "")
+                .try_as_basic_value()
+                .unwrap_left()
+                .into_int_value(),
+            data_type => {
+                let result = self.builder.build_alloca(self.type_to_llvm(data_type), "");
+                // The program exits straight after, so the root is never
+                // popped.
+                self.write_push_root(data_type, result)?;
+                if data_type.is_primitive() {
+                    let value = self.builder
+                        .build_call(func, &arguments, "")
+                        .try_as_basic_value()
+                        .unwrap_left();
+                    self.builder.build_store(result, value);
+                } else {
+                    arguments.push(result.into());
+                    self.builder.build_call(func, &arguments, "");
+                }
+                self.write_print(data_type, result)?;
+                i32_type.const_zero()
+            }
+        };
+        self.builder.build_return(Some(&status));
+
+        if let Some(message) = self.verifier_message(main) {
+            bail!(
+                "internal compiler error: generated invalid LLVM IR for the entry point {}: {}",
+                name,
+                message
+            );
+        }
+        Ok(())
+    }
+
+    /// A pointer to the C library's `errno`, which is thread-local, and so
+    /// is only reachable through a function, whose name depends on the
+    /// platform.
+    fn build_errno(&self) -> PointerValue<'ctx> {
+        let triple = self.module.get_triple();
+        let name = if triple.as_str().to_string_lossy().contains("apple") {
+            "__error"
+        } else {
+            "__errno_location"
+        };
+        let i32_ptr_type = self.context.i32_type().ptr_type(AddressSpace::default());
+        let errno_location = self.libc_function(name, i32_ptr_type.fn_type(&[], false));
+        self.builder
+            .build_call(errno_location, &[], "errno")
+            .try_as_basic_value()
+            .unwrap_left()
+            .into_pointer_value()
+    }
+
+    fn build_argv_load(&mut self, argv: PointerValue<'ctx>, index: usize) -> PointerValue<'ctx> {
+        let index = self.context.i64_type().const_int(index as u64, false);
+        let ptr = unsafe { self.builder.build_in_bounds_gep(argv, &[index], "") };
+        self.builder.build_load(ptr, "").into_pointer_value()
+    }
+
+    /// Prints a value of the given type to stdout with a single `printf`,
+    /// using the same tuple syntax as the source language. Used both for the
+    /// entry point's result and for `print`.
+    fn write_print(&mut self, data_type: &sir::DataType, value: PointerValue<'ctx>) -> Result<()> {
+        let mut format = String::new();
+        let mut arguments = Vec::new();
+        self.build_print_arguments(data_type, value, &mut format, &mut arguments)?;
+        format.push('\n');
+
+        let format = self.builder.build_global_string_ptr(&format, "format");
+        arguments.insert(0, format.as_pointer_value().into());
+        let i8_ptr_type = self.context.i8_type().ptr_type(AddressSpace::default());
+        let printf = self.libc_function("printf", self.context.i32_type().fn_type(&[i8_ptr_type.into()], true));
+        self.builder.build_call(printf, &arguments, "");
+        Ok(())
+    }
+
+    fn build_print_arguments(
+        &mut self,
+        data_type: &sir::DataType,
+        value: PointerValue<'ctx>,
+        format: &mut String,
+        arguments: &mut Vec<BasicMetadataValueEnum<'ctx>>,
+    ) -> Result<()> {
+        match data_type {
+            sir::DataType::Primitive(sir::PrimitiveDataType::Function { .. }) => {
+                format.push_str("<function>")
+            }
+            sir::DataType::Primitive(sir::PrimitiveDataType::Box(_)) => format.push_str("<box>"),
+            sir::DataType::Primitive(sir::PrimitiveDataType::I32) => {
+                format.push_str("%d");
+                arguments.push(self.builder.build_load(value, "").into());
+            }
+            sir::DataType::Primitive(sir::PrimitiveDataType::I64) => {
+                format.push_str("%lld");
+                arguments.push(self.builder.build_load(value, "").into());
+            }
+            sir::DataType::Tuple(elems) => {
+                format.push('(');
+                for (i, elem) in elems.iter().enumerate() {
+                    if i > 0 {
+                        format.push_str(", ");
+                    }
+                    let field = self.build_field(value, i as u32)?;
+                    self.build_print_arguments(elem, field, format, arguments)?;
+                }
+                if elems.len() == 1 {
+                    format.push(',');
+                }
+                format.push(')');
+            }
+        }
+        Ok(())
+    }
+
+    fn libc_function(&self, name: &str, func_type: FunctionType<'ctx>) -> FunctionValue<'ctx> {
+        self.module
+            .get_function(name)
+            .unwrap_or_else(|| self.module.add_function(name, func_type, Some(Linkage::External)))
     }
 
     // pub fn write_global_primitive_constant(&self, name: &str, data_type: &sir::DataType, value: &sir::Expression) {
@@ -139,140 +974,500 @@ impl<'ctx> Generator<'ctx> {
     //     builder.build_return(Some(&value_value));
     // }
 
-    fn write_expression(&mut self, expr: &sir::Expression) -> BasicValueEnum<'ctx> {
-        match expr {
+    /// Writes an expression, attributing its instructions to its location.
+    /// Those that come after its operands are written are attributed to it
+    /// again once they have been.
+    fn write_expression(&mut self, expr: &sir::Expression) -> Result<BasicValueEnum<'ctx>> {
+        if let Some(location) = expr.location() {
+            self.set_debug_location(location);
+        }
+        let value = match expr {
+            sir::Expression::Assert { condition, location } => {
+                let value = self.write_expression(condition)?.into_int_value();
+                self.set_debug_location(location);
+                let is_zero = self.builder.build_int_compare(
+                    IntPredicate::EQ,
+                    value,
+                    value.get_type().const_zero(),
+                    "",
+                );
+                self.write_trap_if(is_zero, location, "assertion failed")?;
+                value.as_basic_value_enum()
+            }
+            sir::Expression::Boxed { value, location } => {
+                let data_type = value.data_type();
+                let target_type = self.type_to_llvm(data_type.as_ref());
+                // The contents come first, so that if they are built from
+                // the last reference to a box of the same type, that box's
+                // memory is reused for this one.
+                let reuse_slot = self.push_reuse_slot(data_type.as_ref())?;
+                let temp = self.build_stack_slot(data_type.as_ref())?;
+                self.write_expression_into(value, temp)?;
+                self.set_debug_location(location);
+                let memory = match reuse_slot {
+                    Some(reuse_slot) => {
+                        self.reuse_slots.pop();
+                        self.write_reuse_or_alloc(data_type.as_ref(), reuse_slot, location)?
+                    }
+                    None => self.write_alloc(data_type.as_ref(), location)?,
+                };
+                let contents = self
+                    .builder
+                    .build_bitcast(memory, self.box_contents_type(target_type), "")
+                    .into_pointer_value();
+                self.write_copy(data_type.as_ref(), temp, contents);
+                memory.as_basic_value_enum()
+            }
             sir::Expression::BinaryOperation {
-                operation: sir::BinaryOperation::Add,
+                operation,
                 left,
                 right,
+                location,
             } => {
                 let left = self
-                    .write_expression(left.as_ref())
+                    .write_expression(left.as_ref())?
                     .into_int_value();
                 let right = self
-                    .write_expression(right.as_ref())
+                    .write_expression(right.as_ref())?
                     .into_int_value();
-                self.builder
-                    .build_int_add(left, right, "add")
+                self.set_debug_location(location);
+                self.write_binary_operation(operation, left, right, location)?
                     .as_basic_value_enum()
             }
             sir::Expression::Call {
                 function,
                 arguments,
+                location,
             } => {
-                let data_type = expr.data_type();
-                let sir::DataType::Primitive(sir::PrimitiveDataType::Function { return_type, .. }) = data_type.as_ref() else {panic!("non function")};
+                let data_type = function.data_type();
+                let (_, return_type) = function_signature(data_type.as_ref())?;
                 if return_type.is_primitive() {
-                    let function: CallableValue<'ctx> = self
-                        .write_expression(function)
-                        .into_pointer_value()
-                        .try_into()
-                        .unwrap();
-                    let arguments: Vec<BasicMetadataValueEnum<'ctx>> = arguments.iter()
-                        .map(|argument| self.write_expression(argument).into())
-                        .collect();
-
-                    self.builder
-                        .build_call(function, &arguments, "")
-                        .try_as_basic_value()
-                        .unwrap_left()
+                    self.write_call(function, arguments, None, location)?
+                        .ok_or_else(|| anyhow!("internal compiler error: a call to a {} returned nothing", data_type))?
                 } else {
-                    let temp = self.builder.build_alloca(self.type_to_llvm(return_type.as_ref()), "");
-                    self.write_expression_into(expr, temp);
+                    let temp = self.build_temporary(return_type)?;
+                    self.write_expression_into(expr, temp)?;
                     temp.as_basic_value_enum()
                 }
             }
-            sir::Expression::FunctionParam { index, .. } => self
-                .current_function
-                .unwrap()
-                .get_nth_param(*index)
-                .unwrap(),
+            sir::Expression::FunctionParam { index, data_type } => {
+                // Parameters are borrowed from the caller.
+                let value = self.current_function.unwrap().get_nth_param(*index).unwrap();
+                self.write_retain_primitive(data_type, value)?;
+                value
+            }
             sir::Expression::GlobalReference {
                 name,
                 data_type: sir::DataType::Primitive(sir::PrimitiveDataType::Function { .. }),
-            } => self
-                .module
-                .get_function(name)
-                .unwrap()
-                .as_global_value()
-                .as_pointer_value()
-                .as_basic_value_enum(),
+            } if self.is_function_global(name) => self.static_closure(name)?.as_basic_value_enum(),
             sir::Expression::GlobalReference { name, .. } => self
                 .builder
-                .build_call(self.module.get_function(name).unwrap().clone(), &[], "")
+                .build_call(self.globals[name], &[], "")
                 .try_as_basic_value()
                 .unwrap_left(),
+            sir::Expression::I32Literal(val) => self
+                .context
+                .i32_type()
+                .const_int(*val as u64, true)
+                .as_basic_value_enum(),
             sir::Expression::I64Literal(val) => self
                 .context
                 .i64_type()
                 .const_int(*val as u64, true)
                 .as_basic_value_enum(),
+            sir::Expression::Local { index, data_type } if data_type.is_primitive() => {
+                self.write_local_use(*index, data_type)?
+            }
+            sir::Expression::Local { index, .. } => self.locals[*index as usize],
             sir::Expression::MemberAccess { left, member } => {
                 let data_type = left.data_type();
-                let left = self.write_expression(left);
-                let index = data_type.field_index(member).unwrap();
-                let ptr = self.builder.build_struct_gep(left.into_pointer_value(), index as u32, "").unwrap();
-                if data_type.field_type(&member).unwrap().is_primitive() {
-                    self.builder.build_load(ptr, "").as_basic_value_enum()
+                let left = self.write_expression(left)?;
+                let index = data_type
+                    .field_index(member)
+                    .ok_or_else(|| anyhow!("internal compiler error: {} has no member {}", data_type, member))?;
+                let ptr = self.build_field(left.into_pointer_value(), index as u32)?;
+                let field_type = data_type.field_type(&member).unwrap();
+                if field_type.is_primitive() {
+                    let value = self.builder.build_load(ptr, "").as_basic_value_enum();
+                    self.write_retain_primitive(field_type, value)?;
+                    value
                 } else {
                     ptr.as_basic_value_enum()
                 }
             }
+            sir::Expression::Null { data_type } => self
+                .type_to_llvm(data_type)
+                .into_pointer_type()
+                .const_null()
+                .as_basic_value_enum(),
+            sir::Expression::PartialApplication {
+                function,
+                arguments,
+                location,
+            } => self.write_partial_application(function, arguments, location)?,
+            sir::Expression::Panic {
+                message,
+                data_type,
+                location,
+            } if data_type.is_primitive() => {
+                self.write_panic_expression(message, location)?;
+                // Nothing after a panic runs, so this is never used.
+                match self.type_to_llvm(data_type) {
+                    BasicTypeEnum::IntType(t) => t.get_undef().as_basic_value_enum(),
+                    t => t.into_pointer_type().get_undef().as_basic_value_enum(),
+                }
+            }
+            sir::Expression::Print { value, location } if value.data_type().is_primitive() => {
+                let data_type = value.data_type();
+                let result = self.write_expression(value)?;
+                let slot = self.build_stack_slot(data_type.as_ref())?;
+                self.builder.build_store(slot, result);
+                self.set_debug_location(location);
+                self.write_print(data_type.as_ref(), slot)?;
+                result
+            }
+            sir::Expression::Scope { value, body, .. } => {
+                self.push_local(value, body)?;
+                let result = self.write_expression(body)?;
+                self.pop_local();
+                result
+            }
+            sir::Expression::UnaryOperation {
+                operation,
+                operand,
+                location,
+            } => {
+                let operand = self.write_expression(operand)?.into_int_value();
+                self.set_debug_location(location);
+                let result = match operation {
+                    sir::UnaryOperation::Complement => self.builder.build_not(operand, "not"),
+                    sir::UnaryOperation::Negate => {
+                        let zero = operand.get_type().const_zero();
+                        self.write_arithmetic("sub", self.overflow, zero, operand, location)?
+                    }
+                    sir::UnaryOperation::Not => {
+                        let is_zero = self.builder.build_int_compare(
+                            IntPredicate::EQ,
+                            operand,
+                            operand.get_type().const_zero(),
+                            "",
+                        );
+                        self.builder.build_int_z_extend(is_zero, operand.get_type(), "lnot")
+                    }
+                };
+                result.as_basic_value_enum()
+            }
+            sir::Expression::Unbox { value, location } => {
+                let data_type = expr.data_type();
+                let (contents, memory) = self.write_unbox(value, location)?;
+                // The contents are copied out before the box is released,
+                // in case that frees it.
+                if data_type.is_primitive() {
+                    let result = self.builder.build_load(contents, "").as_basic_value_enum();
+                    self.write_unbox_release(value.data_type().as_ref(), memory, |generator| {
+                        generator.write_retain_primitive(data_type.as_ref(), result)
+                    })?;
+                    result
+                } else {
+                    let temp = self.build_temporary(data_type.as_ref())?;
+                    self.write_copy(data_type.as_ref(), contents, temp);
+                    self.write_unbox_release(value.data_type().as_ref(), memory, |generator| {
+                        generator.write_count_boxes(data_type.as_ref(), temp, true)
+                    })?;
+                    temp.as_basic_value_enum()
+                }
+            }
             e => {
                 let data_type = e.data_type();
-                let temp = self.builder.build_alloca(self.type_to_llvm(data_type.as_ref()), "");
-                self.write_expression_into(e, temp);
+                let temp = self.build_temporary(data_type.as_ref())?;
+                self.write_expression_into(e, temp)?;
                 temp.as_basic_value_enum()
             },
-        }
+        };
+        Ok(value)
     }
 
-    fn write_expression_into(&mut self, expr: &sir::Expression, out: PointerValue<'ctx>) {
+    fn write_expression_into(&mut self, expr: &sir::Expression, out: PointerValue<'ctx>) -> Result<()> {
+        if let Some(location) = expr.location() {
+            self.set_debug_location(location);
+        }
         match expr {
             sir::Expression::Call {
                 function,
                 arguments,
+                location,
             } => {
-                let function: CallableValue<'ctx> = self
-                    .write_expression(function)
-                    .into_pointer_value()
-                    .try_into()
-                    .unwrap();
-                let mut arguments: Vec<BasicMetadataValueEnum<'ctx>> = arguments.iter()
-                    .map(|argument| self.write_expression(argument).into())
-                    .collect();
-                arguments.push(out.into());
-                self.builder.build_call(function, &arguments, "");
+                self.write_call(function, arguments, Some(out), location)?;
             }
             sir::Expression::FunctionParam { index, data_type } => {
                 let input = self.current_function.unwrap().get_nth_param(*index).unwrap().into_pointer_value();
-                self.write_clone(data_type, input, out);
+                self.write_clone(data_type, input, out)?;
             }
             sir::Expression::GlobalReference {
                 data_type: sir::DataType::Primitive(sir::PrimitiveDataType::Function { .. }),
                 ..
             } => {
-                let value = self.write_expression(expr);
+                let value = self.write_expression(expr)?;
                 self.builder.build_store(out, value);
             }
             sir::Expression::GlobalReference { name, .. } => {
-                self.builder
-                    .build_call(self.module.get_function(name).unwrap().clone(), &[out.into()], "");
+                self.builder.build_call(self.globals[name], &[out.into()], "");
+            }
+            sir::Expression::Local { index, data_type } if !data_type.is_primitive() => {
+                let input = self.locals[*index as usize].into_pointer_value();
+                self.write_clone(data_type, input, out)?;
+            }
+            sir::Expression::Panic { message, location, .. } => {
+                self.write_panic_expression(message, location)?;
+            }
+            sir::Expression::Unbox { value, location } if !expr.data_type().is_primitive() => {
+                let data_type = expr.data_type();
+                let (contents, memory) = self.write_unbox(value, location)?;
+                self.write_copy(data_type.as_ref(), contents, out);
+                self.write_unbox_release(value.data_type().as_ref(), memory, |generator| {
+                    generator.write_count_boxes(data_type.as_ref(), out, true)
+                })?;
+            }
+            sir::Expression::Print { value, location } => {
+                self.write_expression_into(value, out)?;
+                self.set_debug_location(location);
+                self.write_print(value.data_type().as_ref(), out)?;
+            }
+            sir::Expression::Scope { value, body, .. } => {
+                self.push_local(value, body)?;
+                self.write_expression_into(body, out)?;
+                self.pop_local();
             }
             sir::Expression::Tuple { values } => {
                 for (i, value) in values.iter().enumerate() {
-                    let dest = self.builder.build_struct_gep(out, i as u32, "").unwrap();
-                    self.write_expression_into(value, dest);
+                    let dest = self.build_field(out, i as u32)?;
+                    self.write_expression_into(value, dest)?;
                 }
             }
             e => {
-                let value = self.write_expression(e);
+                let value = self.write_expression(e)?;
                 self.builder.build_store(out, value);
             }
         }
+        Ok(())
     }
 
-    fn type_to_llvm(&self, data_type: &sir::DataType) -> BasicTypeEnum<'ctx> {
+    /// Calls a function, with `out` for a non-primitive result, returning a
+    /// primitive one. Function globals are called directly, and any other
+    /// function value through its closure.
+    fn write_call(
+        &mut self,
+        function: &sir::Expression,
+        arguments: &[sir::Expression],
+        out: Option<PointerValue<'ctx>>,
+        location: &sir::Location,
+    ) -> Result<Option<BasicValueEnum<'ctx>>> {
+        let function_type = function.data_type();
+        let closure = match function {
+            sir::Expression::GlobalReference { name, .. } if self.is_function_global(name) => None,
+            function => Some(self.write_expression(function)?.into_pointer_value()),
+        };
+        let values = arguments
+            .iter()
+            .map(|argument| self.write_expression(argument))
+            .collect::<Result<Vec<_>>>()?;
+        let mut call_arguments = call_arguments(&values);
+        call_arguments.extend(out.map(BasicMetadataValueEnum::from));
+
+        self.set_debug_location(location);
+        let result = match (function, closure) {
+            (_, Some(closure)) => {
+                call_arguments.push(closure.into());
+                let code = self.build_closure_code(closure, function_type.as_ref())?;
+                self.builder.build_call(code, &call_arguments, "")
+            }
+            (sir::Expression::GlobalReference { name, .. }, None) => {
+                self.builder.build_call(self.globals[name], &call_arguments, "")
+            }
+            _ => unreachable!(),
+        };
+        self.write_release_arguments(arguments, &values)?;
+        if let Some(closure) = closure {
+            self.write_release_primitive(function_type.as_ref(), closure.as_basic_value_enum())?;
+        }
+        Ok(result.try_as_basic_value().left())
+    }
+
+    /// The code in a (non-null) closure, for a function of the given type.
+    fn build_closure_code(
+        &self,
+        closure: PointerValue<'ctx>,
+        function_type: &sir::DataType,
+    ) -> Result<CallableValue<'ctx>> {
+        let (argument_types, return_type) = function_signature(function_type)?;
+        let argument_types: Vec<_> = argument_types.iter().collect();
+        let code_type = self.function_type(&argument_types, return_type, true);
+        let header_type = self.type_to_llvm(&closure_type(Vec::new()));
+        let header = self
+            .builder
+            .build_bitcast(closure, self.box_contents_type(header_type), "")
+            .into_pointer_value();
+        let code = self.build_field(header, CLOSURE_CODE)?;
+        let code = self.builder.build_load(code, "code").into_int_value();
+        callable(
+            self.builder
+                .build_int_to_ptr(code, code_type.ptr_type(AddressSpace::default()), ""),
+        )
+    }
+
+    /// Captures the function and the arguments given to it in a new closure,
+    /// allocated like a box. Its code is a thunk, written later by
+    /// `write_thunk`, which calls the function with them and its own
+    /// arguments.
+    fn write_partial_application(
+        &mut self,
+        function: &sir::Expression,
+        arguments: &[Option<sir::Expression>],
+        location: &sir::Location,
+    ) -> Result<BasicValueEnum<'ctx>> {
+        let function_type = function.data_type().into_owned();
+        let global = match function {
+            sir::Expression::GlobalReference { name, .. } if self.is_function_global(name) => {
+                Some(name.clone())
+            }
+            _ => None,
+        };
+        let mut captures = Vec::new();
+        let mut captured = Vec::new();
+        if global.is_none() {
+            captures.push(function_type.clone());
+            captured.push(function);
+        }
+        for argument in arguments.iter().flatten() {
+            captures.push(argument.data_type().into_owned());
+            captured.push(argument);
+        }
+        let closure_type = closure_type(captures);
+
+        // The thunk takes the arguments that weren't given.
+        let (argument_types, return_type) = function_signature(&function_type)?;
+        let awaited_types: Vec<_> = argument_types
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| arguments.get(*i).map_or(true, Option::is_none))
+            .map(|(_, argument_type)| argument_type)
+            .collect();
+        let thunk_type = self.function_type(&awaited_types, return_type, true);
+        let thunk = self.module.add_function("scrap_thunk", thunk_type, Some(Linkage::Internal));
+        if self.gc {
+            thunk.set_gc(GC_STRATEGY);
+        }
+        self.unwritten_thunks.push(Thunk {
+            func: thunk,
+            global,
+            function_type: function_type.clone(),
+            given: arguments.iter().map(Option::is_some).collect(),
+            closure_type: closure_type.clone(),
+        });
+
+        // As with a box, the contents come first.
+        let i64_type = self.context.i64_type();
+        let temp = self.build_stack_slot(&closure_type)?;
+        let code = self.build_field(temp, CLOSURE_CODE)?;
+        let code_address = thunk.as_global_value().as_pointer_value().const_to_int(i64_type);
+        self.builder.build_store(code, code_address);
+        let release = self.build_field(temp, CLOSURE_RELEASE)?;
+        let release_address = if self.gc {
+            i64_type.const_zero()
+        } else {
+            let release_function = self.release_function(&sir::BoxType::new(closure_type.clone()));
+            release_function.as_global_value().as_pointer_value().const_to_int(i64_type)
+        };
+        self.builder.build_store(release, release_address);
+        for (i, value) in captured.into_iter().enumerate() {
+            let field = self.build_field(temp, CLOSURE_CAPTURES + i as u32)?;
+            self.write_expression_into(value, field)?;
+        }
+
+        self.set_debug_location(location);
+        let memory = self.write_alloc(&closure_type, location)?;
+        let contents = self
+            .builder
+            .build_bitcast(memory, self.box_contents_type(self.type_to_llvm(&closure_type)), "")
+            .into_pointer_value();
+        self.write_copy(&closure_type, temp, contents);
+        Ok(memory.as_basic_value_enum())
+    }
+
+    /// Writes a partial application's thunk. It borrows its closure, and so
+    /// lends the captured values to the function without retaining them.
+    fn write_thunk(&mut self, thunk: Thunk<'ctx>) -> Result<()> {
+        let func = thunk.func;
+        let entry_block = self.context.append_basic_block(func, "entry");
+        self.current_function = Some(func);
+        self.builder.position_at_end(entry_block);
+
+        let (argument_types, return_type) = function_signature(&thunk.function_type)?;
+        let mut params = func.get_params();
+        let closure = params.pop().unwrap().into_pointer_value();
+        let out = if return_type.is_primitive() {
+            None
+        } else {
+            Some(params.pop().unwrap().into_pointer_value())
+        };
+        let mut params = params.into_iter();
+
+        let contents = self
+            .builder
+            .build_bitcast(closure, self.box_contents_type(self.type_to_llvm(&thunk.closure_type)), "")
+            .into_pointer_value();
+        let mut field = CLOSURE_CAPTURES;
+        let mut captured = |generator: &mut Self| {
+            let ptr = generator.build_field(contents, field);
+            field += 1;
+            ptr
+        };
+        let function = match thunk.global {
+            Some(_) => None,
+            None => {
+                let ptr = captured(self)?;
+                Some(self.builder.build_load(ptr, "function").into_pointer_value())
+            }
+        };
+        let mut arguments = Vec::new();
+        for (i, argument_type) in argument_types.iter().enumerate() {
+            if !thunk.given.get(i).copied().unwrap_or(false) {
+                arguments.push(params.next().unwrap());
+            } else if argument_type.is_primitive() {
+                let ptr = captured(self)?;
+                arguments.push(self.builder.build_load(ptr, ""));
+            } else {
+                // Callees expect tuples on the stack, not in a box.
+                let ptr = captured(self)?;
+                let slot = self.build_stack_slot(argument_type)?;
+                self.write_copy(argument_type, ptr, slot);
+                arguments.push(slot.as_basic_value_enum());
+            }
+        }
+
+        let mut call_arguments = call_arguments(&arguments);
+        call_arguments.extend(out.map(BasicMetadataValueEnum::from));
+        let result = match (&thunk.global, function) {
+            (Some(name), _) => self.builder.build_call(self.globals[name], &call_arguments, ""),
+            (None, Some(function)) => {
+                call_arguments.push(function.into());
+                let code = self.build_closure_code(function, &thunk.function_type)?;
+                self.builder.build_call(code, &call_arguments, "")
+            }
+            _ => unreachable!(),
+        };
+        let result = result.try_as_basic_value().left();
+        self.write_function_exit()?;
+        match result {
+            Some(result) => self.builder.build_return(Some(&result)),
+            None => self.builder.build_return(None),
+        };
+
+        self.current_function = None;
+        Ok(())
+    }
+
+    pub fn type_to_llvm(&self, data_type: &sir::DataType) -> BasicTypeEnum<'ctx> {
         match data_type {
             sir::DataType::Primitive(t) => self.primitive_type_to_llvm(t),
             sir::DataType::Tuple(members) => {
@@ -286,33 +1481,109 @@ impl<'ctx> Generator<'ctx> {
 
     fn primitive_type_to_llvm(&self, data_type: &sir::PrimitiveDataType) -> BasicTypeEnum<'ctx> {
         match data_type {
-            sir::PrimitiveDataType::Function {
-                argument_types,
-                return_type,
-            } => {
-                let mut param_types: Vec<_> = argument_types
-                    .iter()
-                    .map(|argument_type| self.type_to_llvm_reference(argument_type).into())
-                    .collect();
-                match return_type.as_ref() {
-                    sir::DataType::Primitive(return_type) => {
-                        let return_type = self.primitive_type_to_llvm(return_type);
-                        return_type
-                            .fn_type(&param_types, false)
-                            .ptr_type(AddressSpace::default())
-                            .as_basic_type_enum()
-                    }
-                    t => {
-                        param_types.push(self.type_to_llvm_reference(t).into());
-                        self.context
-                            .void_type()
-                            .fn_type(&param_types, false)
-                            .ptr_type(AddressSpace::default())
-                            .as_basic_type_enum()
+            // Function values point at closures, which are laid out like
+            // boxes.
+            sir::PrimitiveDataType::Function { .. } => self.box_type().as_basic_type_enum(),
+            sir::PrimitiveDataType::I32 => self.context.i32_type().as_basic_type_enum(),
+            sir::PrimitiveDataType::I64 => self.context.i64_type().as_basic_type_enum(),
+            // Boxes are cast to pointers to their contents when they are
+            // used, so that types can contain boxes of themselves.
+            sir::PrimitiveDataType::Box(_) => self.box_type().as_basic_type_enum(),
+        }
+    }
+
+    fn box_address_space(&self) -> AddressSpace {
+        if self.gc {
+            AddressSpace::from(GC_ADDRESS_SPACE)
+        } else {
+            AddressSpace::default()
+        }
+    }
+
+    fn box_type(&self) -> PointerType<'ctx> {
+        self.context.i8_type().ptr_type(self.box_address_space())
+    }
+
+    /// A function that has already been declared in the module.
+    fn declared_function(&self, name: &str) -> Result<FunctionValue<'ctx>> {
+        self.module
+            .get_function(name)
+            .ok_or_else(|| anyhow!("internal compiler error: {} has not been declared", name))
+    }
+
+    /// A global variable that has already been declared in the module.
+    fn declared_global(&self, name: &str) -> Result<GlobalValue<'ctx>> {
+        self.module
+            .get_global(name)
+            .ok_or_else(|| anyhow!("internal compiler error: {} has not been declared", name))
+    }
+
+    /// A pointer to a field of the struct that `ptr` points to.
+    fn build_field(&self, ptr: PointerValue<'ctx>, index: u32) -> Result<PointerValue<'ctx>> {
+        self.builder.build_struct_gep(ptr, index, "").map_err(|_| {
+            anyhow!(
+                "internal compiler error: no field {} in {}",
+                index,
+                ptr.get_type().print_to_string().to_string_lossy()
+            )
+        })
+    }
+
+    /// The type that a box is cast to, to get at contents of the given type.
+    fn box_contents_type(&self, target_type: BasicTypeEnum<'ctx>) -> PointerType<'ctx> {
+        target_type.ptr_type(self.box_address_space())
+    }
+
+    /// A `struct scrap_layout` (see `runtime/gc.c`) for values of the given
+    /// type: their size, and the offsets of the boxes in them, which is how
+    /// the collector finds its way from box to box.
+    fn layout(&self, data_type: &sir::DataType) -> PointerValue<'ctx> {
+        let mut name = String::from("scrap_layout$");
+        data_type.mangle(&mut name).unwrap();
+        let i8_ptr_type = self.context.i8_type().ptr_type(AddressSpace::default());
+        if let Some(global) = self.module.get_global(&name) {
+            return global.as_pointer_value().const_cast(i8_ptr_type);
+        }
+
+        let i64_type = self.context.i64_type();
+        let llvm_type = self.type_to_llvm(data_type);
+        let mut offsets = Vec::new();
+        let base = llvm_type.ptr_type(AddressSpace::default()).const_null();
+        self.box_offsets(data_type, base, &mut offsets);
+        let layout = self.context.const_struct(
+            &[
+                llvm_type.size_of().unwrap().as_basic_value_enum(),
+                i64_type.const_int(offsets.len() as u64, false).as_basic_value_enum(),
+                i64_type.const_array(&offsets).as_basic_value_enum(),
+            ],
+            false,
+        );
+        let global = self.module.add_global(layout.get_type(), None, &name);
+        global.set_linkage(Linkage::Internal);
+        global.set_constant(true);
+        global.set_initializer(&layout);
+        global.as_pointer_value().const_cast(i8_ptr_type)
+    }
+
+    /// The offsets of the boxes and closures in a value, as constants, given
+    /// a null pointer to it.
+    fn box_offsets(&self, data_type: &sir::DataType, ptr: PointerValue<'ctx>, offsets: &mut Vec<IntValue<'ctx>>) {
+        match data_type {
+            sir::DataType::Primitive(sir::PrimitiveDataType::Box(_) | sir::PrimitiveDataType::Function { .. }) => {
+                offsets.push(ptr.const_to_int(self.context.i64_type()))
+            }
+            sir::DataType::Primitive(_) => {}
+            sir::DataType::Tuple(elements) => {
+                let i32_type = self.context.i32_type();
+                for (i, element) in elements.iter().enumerate() {
+                    if element.contains_references() {
+                        let field = unsafe {
+                            ptr.const_in_bounds_gep(&[i32_type.const_zero(), i32_type.const_int(i as u64, false)])
+                        };
+                        self.box_offsets(element, field, offsets);
                     }
                 }
             }
-            sir::PrimitiveDataType::I64 => self.context.i64_type().as_basic_type_enum(),
         }
     }
 
@@ -326,18 +1597,753 @@ impl<'ctx> Generator<'ctx> {
         }
     }
 
-    fn write_clone(&mut self, data_type: &sir::DataType, input: PointerValue<'ctx>, out: PointerValue<'ctx>) {
-        let i8_ptr_type = self.context.i8_type().ptr_type(AddressSpace::default()).into();
+    /// Allocates a box for a value of the given type, panicking if there is
+    /// no memory for it.
+    fn write_alloc(&mut self, data_type: &sir::DataType, location: &sir::Location) -> Result<PointerValue<'ctx>> {
+        let (routine, argument): (_, BasicMetadataValueEnum<'ctx>) = if self.gc {
+            (GC_ALLOC_ROUTINE, self.layout(data_type).into())
+        } else {
+            (ALLOC_ROUTINE, self.type_to_llvm(data_type).size_of().unwrap().into())
+        };
+        let memory = self
+            .builder
+            .build_call(self.declared_function(routine)?, &[argument], "box")
+            .try_as_basic_value()
+            .unwrap_left()
+            .into_pointer_value();
+        let is_null = self.builder.build_is_null(memory, "");
+        self.write_trap_if(is_null, location, "out of memory")?;
+        Ok(memory)
+    }
+
+    /// A pointer to the contents of a box, panicking if it is null, and the
+    /// box itself, which the caller must release.
+    fn write_unbox(
+        &mut self,
+        value: &sir::Expression,
+        location: &sir::Location,
+    ) -> Result<(PointerValue<'ctx>, PointerValue<'ctx>)> {
+        let data_type = value.data_type();
+        let target_type = self.type_to_llvm(box_type_of(data_type.as_ref())?.target());
+        let memory = self.write_expression(value)?.into_pointer_value();
+        self.set_debug_location(location);
+        let is_null = self.builder.build_is_null(memory, "");
+        self.write_trap_if(is_null, location, "unboxed a null box")?;
+        let contents = self
+            .builder
+            .build_bitcast(memory, self.box_contents_type(target_type), "")
+            .into_pointer_value();
+        Ok((contents, memory))
+    }
+
+    /// Opens a reuse slot for a box of the given type that is about to be
+    /// built, which starts out empty. There are none with the garbage
+    /// collector, which frees nothing itself.
+    fn push_reuse_slot(&mut self, data_type: &sir::DataType) -> Result<Option<PointerValue<'ctx>>> {
+        if self.gc {
+            return Ok(None);
+        }
+        let box_type = sir::DataType::Primitive(sir::PrimitiveDataType::Box(sir::BoxType::new(data_type.clone())));
+        let slot = self.build_stack_slot(&box_type)?;
+        self.builder.build_store(slot, self.box_type().const_null());
+        self.reuse_slots.push((data_type.clone(), slot));
+        Ok(Some(slot))
+    }
+
+    /// Takes the box left in a reuse slot, if any, or else allocates one.
+    /// A box left there still has a reference count of one.
+    fn write_reuse_or_alloc(
+        &mut self,
+        data_type: &sir::DataType,
+        reuse_slot: PointerValue<'ctx>,
+        location: &sir::Location,
+    ) -> Result<PointerValue<'ctx>> {
+        let function = self.current_function.unwrap();
+        let alloc_block = self.context.append_basic_block(function, "alloc");
+        let done_block = self.context.append_basic_block(function, "");
+
+        let kept = self.builder.build_load(reuse_slot, "kept").into_pointer_value();
+        let kept_block = self.builder.get_insert_block().unwrap();
+        let is_empty = self.builder.build_is_null(kept, "");
+        self.builder.build_conditional_branch(is_empty, alloc_block, done_block);
+
+        self.builder.position_at_end(alloc_block);
+        let allocated = self.write_alloc(data_type, location)?;
+        let allocated_block = self.builder.get_insert_block().unwrap();
+        self.builder.build_unconditional_branch(done_block);
+
+        self.builder.position_at_end(done_block);
+        let memory = self.builder.build_phi(kept.get_type(), "box");
+        memory.add_incoming(&[(&kept, kept_block), (&allocated, allocated_block)]);
+        Ok(memory.as_basic_value().into_pointer_value())
+    }
+
+    /// Gives up the reference to a box that `write_unbox` returned, once
+    /// its contents have been copied out. If that was the last reference,
+    /// and a box of the same type is being built around the `unbox`, the
+    /// copy takes over the contents' references and the box is left in the
+    /// reuse slot. Otherwise, `retain_copy` counts the copy's references
+    /// and the box is released.
+    fn write_unbox_release(
+        &mut self,
+        data_type: &sir::DataType,
+        memory: PointerValue<'ctx>,
+        retain_copy: impl FnOnce(&mut Self) -> Result<()>,
+    ) -> Result<()> {
+        let box_type = box_type_of(data_type)?;
+        let reuse_slot = self
+            .reuse_slots
+            .iter()
+            .rev()
+            .find(|(target, _)| target == box_type.target())
+            .map(|(_, slot)| *slot);
+        let Some(reuse_slot) = reuse_slot else {
+            retain_copy(self)?;
+            return self.write_release(box_type, memory);
+        };
+
+        let function = self.current_function.unwrap();
+        let reuse_block = self.context.append_basic_block(function, "reuse");
+        let release_block = self.context.append_basic_block(function, "release");
+        let done_block = self.context.append_basic_block(function, "");
+
+        let count = self.build_reference_count(memory);
+        let count = self.builder.build_load(count, "").into_int_value();
+        let is_unique = self
+            .builder
+            .build_int_compare(IntPredicate::EQ, count, count.get_type().const_int(1, false), "");
+        let kept = self.builder.build_load(reuse_slot, "kept").into_pointer_value();
+        let is_empty = self.builder.build_is_null(kept, "");
+        let reuse = self.builder.build_and(is_unique, is_empty, "");
+        self.builder.build_conditional_branch(reuse, reuse_block, release_block);
+
+        self.builder.position_at_end(reuse_block);
+        self.builder.build_store(reuse_slot, memory);
+        self.builder.build_unconditional_branch(done_block);
+
+        self.builder.position_at_end(release_block);
+        retain_copy(self)?;
+        self.write_release(box_type, memory)?;
+        self.builder.build_unconditional_branch(done_block);
+
+        self.builder.position_at_end(done_block);
+        Ok(())
+    }
+
+    /// Copies a non-primitive value. Any boxes in it are shared with the
+    /// copy, rather than copied themselves, and so are retained.
+    fn write_clone(&mut self, data_type: &sir::DataType, input: PointerValue<'ctx>, out: PointerValue<'ctx>) -> Result<()> {
+        self.write_copy(data_type, input, out);
+        self.write_count_boxes(data_type, out, true)
+    }
+
+    /// Moves a value from one place to another, along with its references
+    /// to any boxes.
+    fn write_copy(&mut self, data_type: &sir::DataType, input: PointerValue<'ctx>, out: PointerValue<'ctx>) {
+        // Either side may be a box, which with the garbage collector is in
+        // an address space of its own.
+        let input_type = self.context.i8_type().ptr_type(input.get_type().get_address_space());
+        let out_type = self.context.i8_type().ptr_type(out.get_type().get_address_space());
         let i64_type = self.context.i64_type().into();
-        let i1_type = self.context.bool_type().into();
         let size = self.type_to_llvm(data_type).size_of().unwrap();
-        let memcpy = Intrinsic::find("llvm.memcpy.p0i8.p0i8.i64").unwrap().get_declaration(&self.module, &[i8_ptr_type, i8_ptr_type, i64_type, i1_type]).unwrap();
-        let input = self.builder.build_bitcast(input, i8_ptr_type, "");
-        let out = self.builder.build_bitcast(out , i8_ptr_type, "");
+        let memcpy = Intrinsic::find("llvm.memcpy").unwrap().get_declaration(&self.module, &[out_type.into(), input_type.into(), i64_type]).unwrap();
+        let input = self.builder.build_bitcast(input, input_type, "");
+        let out = self.builder.build_bitcast(out , out_type, "");
         self.builder.build_call(memcpy, &[out.into(), input.into(), size.into(), self.context.bool_type().const_zero().into()], "");
     }
 
-    pub fn build(self) -> Module<'ctx> {
-        self.module
+    /// Binds the value of a scope to the next local while its body is
+    /// written. A box that the body never uses is released straight away.
+    fn push_local(&mut self, value: &sir::Expression, body: &sir::Expression) -> Result<()> {
+        let data_type = value.data_type();
+        let index = self.locals.len() as u32;
+        let value = self.write_expression(value)?;
+        let uses = count_local_uses(body, index);
+        if uses == 0 {
+            self.write_release_primitive(data_type.as_ref(), value)?;
+        }
+        self.locals.push(value);
+        self.local_uses.push(uses);
+        Ok(())
+    }
+
+    fn pop_local(&mut self) {
+        self.locals.pop();
+        self.local_uses.pop();
+    }
+
+    /// Uses a primitive local. The last use takes over the scope's reference
+    /// to a box, and the others retain it.
+    fn write_local_use(&mut self, index: u32, data_type: &sir::DataType) -> Result<BasicValueEnum<'ctx>> {
+        let value = self.locals[index as usize];
+        let uses = &mut self.local_uses[index as usize];
+        *uses -= 1;
+        if *uses > 0 {
+            self.write_retain_primitive(data_type, value)?;
+        }
+        Ok(value)
+    }
+
+    /// Stack memory for a value, allocated in the entry block so that the
+    /// function's frame has a fixed size. With the garbage collector, any
+    /// boxes in it are roots until the function returns, since statepoints
+    /// only track values in registers; it starts out zeroed so that the
+    /// collector never sees garbage in it.
+    fn build_stack_slot(&mut self, data_type: &sir::DataType) -> Result<PointerValue<'ctx>> {
+        let llvm_type = self.type_to_llvm(data_type);
+        let entry_block = self.current_function.unwrap().get_first_basic_block().unwrap();
+        let builder = self.context.create_builder();
+        match entry_block.get_first_instruction() {
+            Some(instruction) => builder.position_before(&instruction),
+            None => builder.position_at_end(entry_block),
+        }
+        let slot = builder.build_alloca(llvm_type, "");
+        if self.write_push_root(data_type, slot)? {
+            self.roots += 1;
+        }
+        Ok(slot)
+    }
+
+    /// With the garbage collector, makes any boxes in a stack slot roots,
+    /// zeroing it first. Returns whether it did.
+    fn write_push_root(&self, data_type: &sir::DataType, slot: PointerValue<'ctx>) -> Result<bool> {
+        if !self.gc || !data_type.contains_references() {
+            return Ok(false);
+        }
+        self.builder.build_store(slot, self.type_to_llvm(data_type).const_zero());
+        let i8_ptr_type = self.context.i8_type().ptr_type(AddressSpace::default());
+        let slot = self.builder.build_bitcast(slot, i8_ptr_type, "");
+        self.builder.build_call(
+            self.declared_function(GC_PUSH_ROOT_ROUTINE)?,
+            &[slot.into(), self.layout(data_type).into()],
+            "",
+        );
+        Ok(true)
+    }
+
+    /// Stack memory for a temporary value. Any boxes in it are released when
+    /// the function returns.
+    fn build_temporary(&mut self, data_type: &sir::DataType) -> Result<PointerValue<'ctx>> {
+        let temp = self.build_stack_slot(data_type)?;
+        if data_type.contains_references() {
+            self.temporaries.push((temp, data_type.clone()));
+        }
+        Ok(temp)
+    }
+
+    /// Releases the current function's temporaries, and stops treating its
+    /// stack slots as roots, before it returns.
+    fn write_function_exit(&mut self) -> Result<()> {
+        for (temp, data_type) in take(&mut self.temporaries) {
+            self.write_count_boxes(&data_type, temp, false)?;
+        }
+        let roots = take(&mut self.roots);
+        if roots > 0 {
+            self.builder.build_call(
+                self.declared_function(GC_POP_ROOTS_ROUTINE)?,
+                &[self.context.i64_type().const_int(roots, false).into()],
+                "",
+            );
+        }
+        Ok(())
     }
+
+    /// Releases the boxes passed to a call once it returns, since callees
+    /// only borrow their arguments.
+    fn write_release_arguments(&mut self, arguments: &[sir::Expression], values: &[BasicValueEnum<'ctx>]) -> Result<()> {
+        for (argument, value) in arguments.iter().zip(values) {
+            self.write_release_primitive(argument.data_type().as_ref(), *value)?;
+        }
+        Ok(())
+    }
+
+    /// Closures are retained just like boxes.
+    fn write_retain_primitive(&mut self, data_type: &sir::DataType, value: BasicValueEnum<'ctx>) -> Result<()> {
+        if let sir::DataType::Primitive(sir::PrimitiveDataType::Box(_) | sir::PrimitiveDataType::Function { .. }) = data_type {
+            self.write_retain(value.into_pointer_value())?;
+        }
+        Ok(())
+    }
+
+    fn write_release_primitive(&mut self, data_type: &sir::DataType, value: BasicValueEnum<'ctx>) -> Result<()> {
+        match data_type {
+            sir::DataType::Primitive(sir::PrimitiveDataType::Box(box_type)) => {
+                self.write_release(box_type, value.into_pointer_value())
+            }
+            sir::DataType::Primitive(sir::PrimitiveDataType::Function { .. }) => {
+                self.write_release_closure(value.into_pointer_value())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Retains, or releases, every box and closure that the value at `ptr`
+    /// holds itself.
+    fn write_count_boxes(&mut self, data_type: &sir::DataType, ptr: PointerValue<'ctx>, retain: bool) -> Result<()> {
+        if self.gc {
+            return Ok(());
+        }
+        match data_type {
+            sir::DataType::Primitive(t @ (sir::PrimitiveDataType::Box(_) | sir::PrimitiveDataType::Function { .. })) => {
+                let value = self.builder.build_load(ptr, "");
+                if retain {
+                    self.write_retain(value.into_pointer_value())?;
+                } else {
+                    self.write_release_primitive(&sir::DataType::Primitive(t.clone()), value)?;
+                }
+            }
+            sir::DataType::Primitive(_) => {}
+            sir::DataType::Tuple(elements) => {
+                for (i, element) in elements.iter().enumerate() {
+                    if element.contains_references() {
+                        let field = self.build_field(ptr, i as u32)?;
+                        self.write_count_boxes(element, field, retain)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_retain(&self, memory: PointerValue<'ctx>) -> Result<()> {
+        if self.gc {
+            return Ok(());
+        }
+        self.builder
+            .build_call(self.declared_function(RETAIN_ROUTINE)?, &[memory.into()], "");
+        Ok(())
+    }
+
+    fn write_release(&mut self, box_type: &sir::BoxType, memory: PointerValue<'ctx>) -> Result<()> {
+        if self.gc {
+            return Ok(());
+        }
+        let release = self.release_function(box_type);
+        self.builder.build_call(release, &[memory.into()], "");
+        Ok(())
+    }
+
+    fn write_release_closure(&self, closure: PointerValue<'ctx>) -> Result<()> {
+        if self.gc {
+            return Ok(());
+        }
+        self.builder
+            .build_call(self.declared_function(RELEASE_CLOSURE_ROUTINE)?, &[closure.into()], "");
+        Ok(())
+    }
+
+    /// The function that releases a reference to a box of the given type.
+    /// It is declared on first use, but written by `build`, since that use
+    /// is usually in the middle of writing another function.
+    fn release_function(&mut self, box_type: &sir::BoxType) -> FunctionValue<'ctx> {
+        let mut name = String::from("scrap_release$");
+        sir::DataType::Primitive(sir::PrimitiveDataType::Box(box_type.clone()))
+            .mangle(&mut name)
+            .unwrap();
+        if let Some(func) = self.module.get_function(&name) {
+            return func;
+        }
+
+        let i8_ptr_type = self.context.i8_type().ptr_type(AddressSpace::default());
+        let func_type = self.context.void_type().fn_type(&[i8_ptr_type.into()], false);
+        let func = self.module.add_function(&name, func_type, Some(Linkage::Internal));
+        self.unwritten_releases.push((func, box_type.target().clone()));
+        func
+    }
+
+    /// Writes `void scrap_release$<type>(void *box)`, which drops a reference
+    /// to a box, if it isn't null. Once the last one goes, the boxes in its
+    /// contents are released in turn, and it is freed.
+    fn write_release_function(&mut self, func: FunctionValue<'ctx>, target: &sir::DataType) -> Result<()> {
+        let memory = func.get_nth_param(0).unwrap().into_pointer_value();
+
+        let entry_block = self.context.append_basic_block(func, "entry");
+        let count_block = self.context.append_basic_block(func, "count");
+        let free_block = self.context.append_basic_block(func, "free");
+        let done_block = self.context.append_basic_block(func, "done");
+
+        self.builder.position_at_end(entry_block);
+        let is_null = self.builder.build_is_null(memory, "");
+        self.builder.build_conditional_branch(is_null, done_block, count_block);
+
+        self.builder.position_at_end(count_block);
+        let count = self.build_reference_count(memory);
+        let value = self.builder.build_load(count, "").into_int_value();
+        let value = self
+            .builder
+            .build_int_sub(value, value.get_type().const_int(1, false), "");
+        self.builder.build_store(count, value);
+        let is_last = self
+            .builder
+            .build_int_compare(IntPredicate::EQ, value, value.get_type().const_zero(), "");
+        self.builder.build_conditional_branch(is_last, free_block, done_block);
+
+        self.builder.position_at_end(free_block);
+        let target_type = self.type_to_llvm(target);
+        let contents = self
+            .builder
+            .build_bitcast(memory, target_type.ptr_type(AddressSpace::default()), "")
+            .into_pointer_value();
+        self.write_count_boxes(target, contents, false)?;
+        self.builder
+            .build_call(self.declared_function(FREE_ROUTINE)?, &[memory.into()], "");
+        self.builder.build_unconditional_branch(done_block);
+
+        self.builder.position_at_end(done_block);
+        self.builder.build_return(None);
+        Ok(())
+    }
+
+    fn write_binary_operation(
+        &self,
+        operation: &sir::BinaryOperation,
+        left: IntValue<'ctx>,
+        right: IntValue<'ctx>,
+        location: &sir::Location,
+    ) -> Result<IntValue<'ctx>> {
+        let value = match operation {
+            sir::BinaryOperation::Add => self.write_arithmetic("add", self.overflow, left, right, location)?,
+            sir::BinaryOperation::AddSaturating => {
+                self.write_arithmetic("add", Overflow::Saturate, left, right, location)?
+            }
+            sir::BinaryOperation::AddWrapping => self.write_arithmetic("add", Overflow::Wrap, left, right, location)?,
+            sir::BinaryOperation::BitAnd => self.builder.build_and(left, right, "and"),
+            sir::BinaryOperation::BitOr => self.builder.build_or(left, right, "or"),
+            sir::BinaryOperation::BitXor => self.builder.build_xor(left, right, "xor"),
+            sir::BinaryOperation::Divide => self.write_division(left, right, location)?,
+            sir::BinaryOperation::Multiply => self.write_arithmetic("mul", self.overflow, left, right, location)?,
+            sir::BinaryOperation::MultiplySaturating => {
+                self.write_arithmetic("mul", Overflow::Saturate, left, right, location)?
+            }
+            sir::BinaryOperation::MultiplyWrapping => {
+                self.write_arithmetic("mul", Overflow::Wrap, left, right, location)?
+            }
+            sir::BinaryOperation::Subtract => self.write_arithmetic("sub", self.overflow, left, right, location)?,
+            sir::BinaryOperation::SubtractSaturating => {
+                self.write_arithmetic("sub", Overflow::Saturate, left, right, location)?
+            }
+            sir::BinaryOperation::SubtractWrapping => {
+                self.write_arithmetic("sub", Overflow::Wrap, left, right, location)?
+            }
+            shift => self.write_shift(shift, left, right),
+        };
+        Ok(value)
+    }
+
+    /// LLVM shifts by the width of the type or more are poison, so those
+    /// amounts are handled separately, giving what shifting one bit at a time
+    /// would. Shift amounts are unsigned.
+    fn write_shift(
+        &self,
+        operation: &sir::BinaryOperation,
+        left: IntValue<'ctx>,
+        right: IntValue<'ctx>,
+    ) -> IntValue<'ctx> {
+        let int_type = left.get_type();
+        let bits = int_type.get_bit_width() as u64;
+        let too_far = self.builder.build_int_compare(
+            IntPredicate::UGE,
+            right,
+            int_type.const_int(bits, false),
+            "too_far",
+        );
+        if *operation == sir::BinaryOperation::ShiftRight {
+            // Shifting by one less than the width already fills every bit
+            // with the sign.
+            let amount = self
+                .builder
+                .build_select(too_far, int_type.const_int(bits - 1, false), right, "")
+                .into_int_value();
+            return self.builder.build_right_shift(left, amount, true, "ashr");
+        }
+
+        let shifted = match operation {
+            sir::BinaryOperation::ShiftLeft => self.builder.build_left_shift(left, right, "shl"),
+            _ => self.builder.build_right_shift(left, right, false, "lshr"),
+        };
+        // `select` only propagates poison from the operand it chooses.
+        self.builder
+            .build_select(too_far, int_type.const_zero(), shifted, "")
+            .into_int_value()
+    }
+
+    /// Writes a signed `add`, `sub` or `mul` that overflows as asked.
+    fn write_arithmetic(
+        &self,
+        operation: &str,
+        overflow: Overflow,
+        left: IntValue<'ctx>,
+        right: IntValue<'ctx>,
+        location: &sir::Location,
+    ) -> Result<IntValue<'ctx>> {
+        let int_type = left.get_type();
+        let value = match overflow {
+            Overflow::Wrap => match operation {
+                "add" => self.builder.build_int_add(left, right, "add"),
+                "sub" => self.builder.build_int_sub(left, right, "sub"),
+                _ => self.builder.build_int_mul(left, right, "mul"),
+            },
+            Overflow::Saturate => {
+                let mut arguments: Vec<BasicMetadataValueEnum> = vec![left.into(), right.into()];
+                let name = match operation {
+                    "add" => "llvm.sadd.sat",
+                    "sub" => "llvm.ssub.sat",
+                    // There is no `smul.sat`, but a fixed point multiply with
+                    // no fractional bits is the same thing.
+                    _ => {
+                        arguments.push(self.context.i32_type().const_zero().into());
+                        "llvm.smul.fix.sat"
+                    }
+                };
+                let intrinsic = Intrinsic::find(name)
+                    .unwrap()
+                    .get_declaration(&self.module, &[int_type.into()])
+                    .unwrap();
+                self.builder
+                    .build_call(intrinsic, &arguments, operation)
+                    .try_as_basic_value()
+                    .unwrap_left()
+                    .into_int_value()
+            }
+            Overflow::Trap => {
+                let intrinsic = Intrinsic::find(&format!("llvm.s{}.with.overflow", operation))
+                    .unwrap()
+                    .get_declaration(&self.module, &[int_type.into()])
+                    .unwrap();
+                let result = self
+                    .builder
+                    .build_call(intrinsic, &[left.into(), right.into()], "")
+                    .try_as_basic_value()
+                    .unwrap_left()
+                    .into_struct_value();
+                let overflowed = self
+                    .builder
+                    .build_extract_value(result, 1, "overflowed")
+                    .unwrap()
+                    .into_int_value();
+                self.write_trap_if(overflowed, location, "arithmetic overflow")?;
+                self.builder
+                    .build_extract_value(result, 0, operation)
+                    .unwrap()
+                    .into_int_value()
+            }
+        };
+        Ok(value)
+    }
+
+    /// Division by zero panics. The only signed division that overflows is
+    /// the smallest value by -1, which LLVM leaves undefined, so it is checked
+    /// for whatever `overflow` says.
+    fn write_division(
+        &self,
+        left: IntValue<'ctx>,
+        right: IntValue<'ctx>,
+        location: &sir::Location,
+    ) -> Result<IntValue<'ctx>> {
+        let int_type = left.get_type();
+        let is_zero = self
+            .builder
+            .build_int_compare(IntPredicate::EQ, right, int_type.const_zero(), "");
+        self.write_trap_if(is_zero, location, "division by zero")?;
+
+        let bits = int_type.get_bit_width();
+        let min = int_type.const_int(1 << (bits - 1), false);
+        let is_min = self.builder.build_int_compare(IntPredicate::EQ, left, min, "");
+        let is_minus_one = self
+            .builder
+            .build_int_compare(IntPredicate::EQ, right, int_type.const_all_ones(), "");
+        let overflowed = self.builder.build_and(is_min, is_minus_one, "overflowed");
+
+        if self.overflow == Overflow::Trap {
+            self.write_trap_if(overflowed, location, "arithmetic overflow")?;
+            return Ok(self.builder.build_int_signed_div(left, right, "div"));
+        }
+        // Dividing by 1 instead gives the wrapped result.
+        let divisor = self
+            .builder
+            .build_select(overflowed, int_type.const_int(1, false), right, "")
+            .into_int_value();
+        let quotient = self.builder.build_int_signed_div(left, divisor, "div");
+        if self.overflow == Overflow::Wrap {
+            return Ok(quotient);
+        }
+        let max = int_type.const_int((1 << (bits - 1)) - 1, false);
+        Ok(self
+            .builder
+            .build_select(overflowed, max, quotient, "")
+            .into_int_value())
+    }
+
+    /// Branches to a block that panics with `message` at `location` if
+    /// `condition` holds, and continues building after the branch.
+    fn write_trap_if(&self, condition: IntValue<'ctx>, location: &sir::Location, message: &str) -> Result<()> {
+        let function = self.current_function.unwrap();
+        let trap = self.context.append_basic_block(function, "trap");
+        let rest = self.context.append_basic_block(function, "");
+        self.builder.build_conditional_branch(condition, trap, rest);
+
+        self.builder.position_at_end(trap);
+        self.write_panic(message, location)?;
+        self.builder.position_at_end(rest);
+        Ok(())
+    }
+
+    /// Panics, and continues building in a block that is never reached, for
+    /// the code that would use the panic's value.
+    fn write_panic_expression(&self, message: &str, location: &sir::Location) -> Result<()> {
+        self.write_panic(message, location)?;
+        let unreachable = self
+            .context
+            .append_basic_block(self.current_function.unwrap(), "after_panic");
+        self.builder.position_at_end(unreachable);
+        Ok(())
+    }
+
+    /// Calls the panic routine, ending the current block.
+    fn write_panic(&self, message: &str, location: &sir::Location) -> Result<()> {
+        let message = self.builder.build_global_string_ptr(message, "panic_message");
+        let file = self.builder.build_global_string_ptr(&location.file, "panic_file");
+        let i32_type = self.context.i32_type();
+        self.set_debug_location(location);
+        self.builder.build_call(
+            self.declared_function(PANIC_ROUTINE)?,
+            &[
+                message.as_pointer_value().into(),
+                file.as_pointer_value().into(),
+                i32_type.const_int(location.line as u64, false).into(),
+                i32_type.const_int(location.column as u64, false).into(),
+            ],
+            "",
+        );
+        self.builder.build_unreachable();
+        Ok(())
+    }
+
+    /// Attributes the instructions built from now on to a source location,
+    /// if debug info is enabled.
+    fn set_debug_location(&self, location: &sir::Location) {
+        if let (Some(debug_info), Some(subprogram)) = (&self.debug_info, self.current_subprogram) {
+            self.builder
+                .set_current_debug_location(debug_info.location(subprogram, location));
+        }
+    }
+
+    fn enum_attribute(&self, name: &str) -> Attribute {
+        self.context
+            .create_enum_attribute(Attribute::get_named_enum_kind_id(name), 0)
+    }
+
+    /// Finishes the module and verifies it as a whole. Any globals whose
+    /// symbols appear in the verifier's message are named in the error.
+    pub fn build(mut self) -> Result<Module<'ctx>> {
+        for thunk in take(&mut self.unwritten_thunks) {
+            self.write_thunk(thunk)?;
+        }
+        // Writing one release function can declare others.
+        while let Some((func, target)) = self.unwritten_releases.pop() {
+            self.write_release_function(func, &target)?;
+        }
+
+        if let Some(debug_info) = &self.debug_info {
+            debug_info.finalize();
+        }
+
+        if let Err(message) = self.module.verify() {
+            let message = message.to_string();
+            let mut culprits: Vec<_> = self
+                .globals
+                .iter()
+                .filter(|(_, func)| message.contains(func.get_name().to_string_lossy().as_ref()))
+                .map(|(name, _)| name.as_str())
+                .collect();
+            culprits.sort();
+            if culprits.is_empty() {
+                bail!("internal compiler error: generated an invalid LLVM module:\n{}", message);
+            }
+            bail!(
+                "internal compiler error: generated an invalid LLVM module in {}:\n{}",
+                culprits.join(", "),
+                message
+            );
+        }
+        Ok(self.module)
+    }
+}
+
+/// The argument and result types of a function type.
+fn function_signature(data_type: &sir::DataType) -> Result<(&[sir::DataType], &sir::DataType)> {
+    match data_type {
+        sir::DataType::Primitive(sir::PrimitiveDataType::Function { argument_types, return_type }) => {
+            Ok((argument_types.as_slice(), return_type.as_ref()))
+        }
+        _ => bail!("internal compiler error: {} is not a function type", data_type),
+    }
+}
+
+/// The box type that `data_type` must be.
+fn box_type_of(data_type: &sir::DataType) -> Result<&sir::BoxType> {
+    match data_type {
+        sir::DataType::Primitive(sir::PrimitiveDataType::Box(box_type)) => Ok(box_type),
+        _ => bail!("internal compiler error: {} is not a box type", data_type),
+    }
+}
+
+/// A pointer to code, as something that can be called.
+fn callable(pointer: PointerValue) -> Result<CallableValue> {
+    pointer.try_into().map_err(|_| {
+        anyhow!(
+            "internal compiler error: {} cannot be called",
+            pointer.get_type().print_to_string().to_string_lossy()
+        )
+    })
+}
+
+/// A function value points at a closure: the address of the code to call,
+/// the address of the function that releases the closure (0 for static
+/// closures, which are never freed), and the values that it has captured.
+/// The code takes the function's arguments, then the closure.
+fn closure_type(captures: Vec<sir::DataType>) -> sir::DataType {
+    let address = sir::DataType::Primitive(sir::PrimitiveDataType::I64);
+    let mut fields = vec![address.clone(), address];
+    fields.extend(captures);
+    sir::DataType::Tuple(fields)
+}
+
+/// The symbol of the code for a global's static closure, given the global's
+/// own symbol.
+pub fn code_symbol(symbol: &str) -> String {
+    format!("{}$code", symbol)
+}
+
+fn closure_symbol(symbol: &str) -> String {
+    format!("{}$closure", symbol)
+}
+
+/// A partial application's code, which calls `function` with the arguments
+/// its closure captured where `given` says, and its own arguments elsewhere.
+struct Thunk<'ctx> {
+    func: FunctionValue<'ctx>,
+    /// The global called, or `None` if the function value was captured.
+    global: Option<String>,
+    function_type: sir::DataType,
+    given: Vec<bool>,
+    closure_type: sir::DataType,
+}
+
+fn call_arguments<'ctx>(values: &[BasicValueEnum<'ctx>]) -> Vec<BasicMetadataValueEnum<'ctx>> {
+    values.iter().map(|value| (*value).into()).collect()
+}
+
+/// How many times a scope's body uses the local at `index`. Nothing
+/// branches, so each use is reached exactly once.
+fn count_local_uses(body: &sir::Expression, index: u32) -> usize {
+    let mut uses = 0;
+    visit::try_for_each(body, &mut Bindings::default(), |expression, _| {
+        if matches!(expression, sir::Expression::Local { index: i, .. } if *i == index) {
+            uses += 1;
+        }
+        Ok(())
+    })
+    .unwrap();
+    uses
 }
@@ -0,0 +1,533 @@
+use std::{fmt, rc::Rc};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{options::Overflow, sir};
+
+#[derive(Clone, Debug)]
+pub enum Value {
+    Primitive(PrimitiveValue),
+    Tuple(Vec<Value>),
+}
+
+impl Value {
+    pub fn i64(val: i64) -> Self {
+        Value::Primitive(PrimitiveValue::I64(val))
+    }
+
+    fn as_integer(&self) -> Option<i64> {
+        match self {
+            Value::Primitive(PrimitiveValue::I32(val)) => Some(*val as i64),
+            Value::Primitive(PrimitiveValue::I64(val)) => Some(*val),
+            _ => None,
+        }
+    }
+
+    fn field(&self, index: usize) -> Option<&Value> {
+        match self {
+            Value::Primitive(_) => None,
+            Value::Tuple(elems) => elems.get(index),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Primitive(val) => val.fmt(f),
+            Value::Tuple(elems) => {
+                write!(f, "(")?;
+                for (i, elem) in elems.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    elem.fmt(f)?;
+                }
+                if elems.len() == 1 {
+                    write!(f, ",")?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum PrimitiveValue {
+    Function(String),
+    /// A function value awaiting the arguments that are `None`, and any
+    /// after them.
+    PartialApplication {
+        function: Rc<Value>,
+        arguments: Vec<Option<Value>>,
+    },
+    I32(i32),
+    I64(i64),
+    /// A box, which shares its contents with its copies.
+    Box(Option<Rc<Value>>),
+}
+
+impl fmt::Display for PrimitiveValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PrimitiveValue::Function(name) => write!(f, "{}", name),
+            PrimitiveValue::PartialApplication { function, arguments } => {
+                write!(f, "{}(", function)?;
+                for (i, argument) in arguments.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    match argument {
+                        Some(argument) => argument.fmt(f)?,
+                        None => write!(f, "_")?,
+                    }
+                }
+                write!(f, ")")
+            }
+            PrimitiveValue::I32(val) => write!(f, "{}i32", val),
+            PrimitiveValue::I64(val) => write!(f, "{}i64", val),
+            PrimitiveValue::Box(Some(value)) => write!(f, "box({})", value),
+            PrimitiveValue::Box(None) => write!(f, "null"),
+        }
+    }
+}
+
+/// The status that a program exits with when it panics, interpreted or
+/// compiled.
+pub const PANIC_EXIT_STATUS: i32 = 101;
+
+/// A panic in the program being interpreted, such as a failed assertion, as
+/// opposed to an error in the interpreter itself.
+#[derive(Debug)]
+pub struct Panic {
+    /// Where the panic happened, as `file:line:column`.
+    pub location: String,
+    pub message: String,
+}
+
+impl Panic {
+    fn at(location: &sir::Location, message: &str) -> anyhow::Error {
+        Panic {
+            location: location.to_string(),
+            message: message.to_string(),
+        }
+        .into()
+    }
+}
+
+impl fmt::Display for Panic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+impl std::error::Error for Panic {}
+
+/// Evaluates a module directly from its SIR. The module must already have
+/// been through `build_function_params`, `build_global_references`,
+/// `bind_locals` and `build_partial_applications`.
+pub struct Interpreter<'m> {
+    module: &'m sir::Module,
+    overflow: Overflow,
+}
+
+impl<'m> Interpreter<'m> {
+    pub fn new(module: &'m sir::Module) -> Self {
+        Self {
+            module,
+            overflow: Overflow::Trap,
+        }
+    }
+
+    pub fn set_overflow(&mut self, overflow: Overflow) {
+        self.overflow = overflow;
+    }
+
+    pub fn evaluate_global(&self, name: &str) -> Result<Value> {
+        if self.module.externs.contains_key(name) {
+            return Ok(Value::Primitive(PrimitiveValue::Function(name.to_string())));
+        }
+        let global = self.global(name)?;
+        if global.arguments.is_empty() {
+            self.evaluate(&global.body, &[], &mut Vec::new())
+        } else {
+            Ok(Value::Primitive(PrimitiveValue::Function(name.to_string())))
+        }
+    }
+
+    pub fn call_global(&self, name: &str, arguments: &[Value]) -> Result<Value> {
+        if self.module.externs.contains_key(name) {
+            bail!("{} is an extern C function, which the interpreter cannot call", name);
+        }
+        let global = self.global(name)?;
+        if global.arguments.len() != arguments.len() {
+            bail!(
+                "{} expects {} arguments, got {}",
+                name,
+                global.arguments.len(),
+                arguments.len()
+            );
+        }
+        self.evaluate(&global.body, arguments, &mut Vec::new())
+    }
+
+    /// Calls a function value, filling in the holes of partial applications
+    /// with the first arguments.
+    fn call(&self, function: Value, arguments: Vec<Value>) -> Result<Value> {
+        match function {
+            Value::Primitive(PrimitiveValue::Function(name)) => self.call_global(&name, &arguments),
+            Value::Primitive(PrimitiveValue::PartialApplication { function, arguments: given }) => {
+                let mut arguments = arguments.into_iter();
+                let mut filled = Vec::new();
+                for argument in given {
+                    match argument {
+                        Some(argument) => filled.push(argument),
+                        None => filled.push(
+                            arguments
+                                .next()
+                                .ok_or_else(|| anyhow!("Called {} with too few arguments", function))?,
+                        ),
+                    }
+                }
+                filled.extend(arguments);
+                self.call(function.as_ref().clone(), filled)
+            }
+            _ => bail!("Called a non-function value"),
+        }
+    }
+
+    fn global(&self, name: &str) -> Result<&'m sir::Global> {
+        self.module
+            .globals
+            .get(name)
+            .ok_or_else(|| anyhow!("Unknown global {}", name))
+    }
+
+    /// `locals` holds the values of the enclosing scopes, outermost first.
+    fn evaluate(&self, expr: &sir::Expression, params: &[Value], locals: &mut Vec<Value>) -> Result<Value> {
+        match expr {
+            sir::Expression::Assert { condition, location } => {
+                let value = self.evaluate(condition, params, locals)?;
+                if value.as_integer() == Some(0) {
+                    return Err(Panic::at(location, "assertion failed"));
+                }
+                Ok(value)
+            }
+            sir::Expression::BinaryOperation {
+                operation,
+                left,
+                right,
+                location,
+            } => {
+                let data_type = left.data_type();
+                let bits = integer_bits(&data_type);
+                let left = self.evaluate_integer(left, params, locals)?;
+                let right = self.evaluate_integer(right, params, locals)?;
+                // Shift amounts are unsigned, so negative ones are as large
+                // as they can be.
+                let shift = right as u64;
+                // Arithmetic is done exactly, and the result then made to fit
+                // the type as the operation's overflow behaviour says.
+                let (wide_left, wide_right) = (left as i128, right as i128);
+                let fit = |exact, overflow| fit_integer(exact, bits, overflow, location);
+                let result = match operation {
+                    sir::BinaryOperation::Add => fit(wide_left + wide_right, self.overflow)?,
+                    sir::BinaryOperation::AddSaturating => fit(wide_left + wide_right, Overflow::Saturate)?,
+                    sir::BinaryOperation::AddWrapping => fit(wide_left + wide_right, Overflow::Wrap)?,
+                    sir::BinaryOperation::BitAnd => left & right,
+                    sir::BinaryOperation::BitOr => left | right,
+                    sir::BinaryOperation::BitXor => left ^ right,
+                    sir::BinaryOperation::Divide => {
+                        if right == 0 {
+                            return Err(Panic::at(location, "division by zero"));
+                        }
+                        fit(wide_left / wide_right, self.overflow)?
+                    }
+                    sir::BinaryOperation::Multiply => fit(wide_left * wide_right, self.overflow)?,
+                    sir::BinaryOperation::MultiplySaturating => fit(wide_left * wide_right, Overflow::Saturate)?,
+                    sir::BinaryOperation::MultiplyWrapping => fit(wide_left * wide_right, Overflow::Wrap)?,
+                    sir::BinaryOperation::ShiftLeft if shift >= bits => 0,
+                    sir::BinaryOperation::ShiftLeft => left << shift,
+                    sir::BinaryOperation::ShiftRight => left >> shift.min(bits - 1),
+                    sir::BinaryOperation::ShiftRightLogical if shift >= bits => 0,
+                    sir::BinaryOperation::ShiftRightLogical => {
+                        // Only the low `bits` bits are shifted down.
+                        let mask = u64::MAX >> (64 - bits);
+                        ((left as u64 & mask) >> shift) as i64
+                    }
+                    sir::BinaryOperation::Subtract => fit(wide_left - wide_right, self.overflow)?,
+                    sir::BinaryOperation::SubtractSaturating => fit(wide_left - wide_right, Overflow::Saturate)?,
+                    sir::BinaryOperation::SubtractWrapping => fit(wide_left - wide_right, Overflow::Wrap)?,
+                };
+                Ok(integer_value(&data_type, result))
+            }
+            sir::Expression::Boxed { value, .. } => {
+                let value = self.evaluate(value, params, locals)?;
+                Ok(Value::Primitive(PrimitiveValue::Box(Some(Rc::new(value)))))
+            }
+            sir::Expression::Call {
+                function,
+                arguments,
+                ..
+            } => {
+                let function = self.evaluate(function, params, locals)?;
+                let arguments = arguments
+                    .iter()
+                    .map(|argument| self.evaluate(argument, params, locals))
+                    .collect::<Result<Vec<_>>>()?;
+                self.call(function, arguments)
+            }
+            sir::Expression::FunctionParam { index, .. } => params
+                .get(*index as usize)
+                .cloned()
+                .ok_or_else(|| anyhow!("Function parameter {} out of range", index)),
+            sir::Expression::GlobalReference { name, .. } => self.evaluate_global(name),
+            sir::Expression::I32Literal(val) => Ok(Value::Primitive(PrimitiveValue::I32(*val))),
+            sir::Expression::I64Literal(val) => Ok(Value::i64(*val)),
+            sir::Expression::Local { index, .. } => locals
+                .get(*index as usize)
+                .cloned()
+                .ok_or_else(|| anyhow!("Local {} out of range", index)),
+            sir::Expression::MemberAccess { left, member } => {
+                let data_type = left.data_type();
+                let index = data_type
+                    .field_index(member)
+                    .ok_or_else(|| anyhow!("No member {} in {:?}", member, data_type))?;
+                let left = self.evaluate(left, params, locals)?;
+                left.field(index)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("No member {} in {}", member, left))
+            }
+            sir::Expression::Null { .. } => Ok(Value::Primitive(PrimitiveValue::Box(None))),
+            sir::Expression::PartialApplication {
+                function,
+                arguments,
+                ..
+            } => {
+                let function = self.evaluate(function, params, locals)?;
+                let arguments = arguments
+                    .iter()
+                    .map(|argument| {
+                        argument
+                            .as_ref()
+                            .map(|argument| self.evaluate(argument, params, locals))
+                            .transpose()
+                    })
+                    .collect::<Result<_>>()?;
+                Ok(Value::Primitive(PrimitiveValue::PartialApplication {
+                    function: Rc::new(function),
+                    arguments,
+                }))
+            }
+            sir::Expression::Panic { message, location, .. } => Err(Panic::at(location, message)),
+            sir::Expression::Print { value, .. } => {
+                let value = self.evaluate(value, params, locals)?;
+                let mut output = String::new();
+                write_output(&value, &mut output);
+                println!("{}", output);
+                Ok(value)
+            }
+            sir::Expression::Reference { name, .. } => bail!("Unresolved reference {}", name),
+            sir::Expression::Scope { value, body, .. } => {
+                let value = self.evaluate(value, params, locals)?;
+                locals.push(value);
+                let result = self.evaluate(body, params, locals);
+                locals.pop();
+                result
+            }
+            sir::Expression::Tuple { values } => Ok(Value::Tuple(
+                values
+                    .iter()
+                    .map(|value| self.evaluate(value, params, locals))
+                    .collect::<Result<_>>()?,
+            )),
+            sir::Expression::UnaryOperation {
+                operation,
+                operand,
+                location,
+            } => {
+                let data_type = operand.data_type();
+                let bits = integer_bits(&data_type);
+                let operand = self.evaluate_integer(operand, params, locals)?;
+                let result = match operation {
+                    sir::UnaryOperation::Complement => !operand,
+                    sir::UnaryOperation::Negate => {
+                        fit_integer(-(operand as i128), bits, self.overflow, location)?
+                    }
+                    sir::UnaryOperation::Not => (operand == 0) as i64,
+                };
+                Ok(integer_value(&data_type, result))
+            }
+            sir::Expression::Unbox { value, location } => match self.evaluate(value, params, locals)? {
+                Value::Primitive(PrimitiveValue::Box(Some(value))) => Ok(value.as_ref().clone()),
+                Value::Primitive(PrimitiveValue::Box(None)) => Err(Panic::at(location, "unboxed a null box")),
+                value => bail!("Expected a box, got {}", value),
+            },
+        }
+    }
+
+    fn evaluate_integer(
+        &self,
+        expr: &sir::Expression,
+        params: &[Value],
+        locals: &mut Vec<Value>,
+    ) -> Result<i64> {
+        let value = self.evaluate(expr, params, locals)?;
+        value
+            .as_integer()
+            .ok_or_else(|| anyhow!("Expected an integer, got {}", value))
+    }
+}
+
+fn integer_bits(data_type: &sir::DataType) -> u64 {
+    match data_type {
+        sir::DataType::Primitive(sir::PrimitiveDataType::I32) => 32,
+        _ => 64,
+    }
+}
+
+/// Makes the exact result of an operation on `bits`-bit integers fit in that
+/// many bits.
+fn fit_integer(exact: i128, bits: u64, overflow: Overflow, location: &sir::Location) -> Result<i64> {
+    let min = -(1i128 << (bits - 1));
+    let max = (1i128 << (bits - 1)) - 1;
+    if (min..=max).contains(&exact) {
+        return Ok(exact as i64);
+    }
+    match overflow {
+        Overflow::Trap => Err(Panic::at(location, "arithmetic overflow")),
+        // `integer_value` truncates this further for I32.
+        Overflow::Wrap => Ok(exact as i64),
+        Overflow::Saturate => Ok(exact.clamp(min, max) as i64),
+    }
+}
+
+/// Truncates the result of 64-bit arithmetic to the given type.
+fn integer_value(data_type: &sir::DataType, val: i64) -> Value {
+    match data_type {
+        sir::DataType::Primitive(sir::PrimitiveDataType::I32) => Value::Primitive(PrimitiveValue::I32(val as i32)),
+        _ => Value::i64(val),
+    }
+}
+
+/// Formats a value as compiled programs print it, without the literal
+/// suffixes that `Display` adds.
+fn write_output(value: &Value, out: &mut String) {
+    match value {
+        Value::Primitive(PrimitiveValue::Function(_) | PrimitiveValue::PartialApplication { .. }) => {
+            out.push_str("<function>")
+        }
+        Value::Primitive(PrimitiveValue::Box(_)) => out.push_str("<box>"),
+        Value::Primitive(PrimitiveValue::I32(val)) => out.push_str(&val.to_string()),
+        Value::Primitive(PrimitiveValue::I64(val)) => out.push_str(&val.to_string()),
+        Value::Tuple(elems) => {
+            out.push('(');
+            for (i, elem) in elems.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                write_output(elem, out);
+            }
+            if elems.len() == 1 {
+                out.push(',');
+            }
+            out.push(')');
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{options::Options, parser, passes};
+
+    const SOURCE: &str = "
+big: I64 = 9223372036854775807i64
+small: I32 = 2147483647i32
+sum: I64 = big + 1i64
+wrapped: I64 = big +% 1i64
+saturated: I32 = small *| 2i32
+negated: I32 = -(-small - 1i32)
+shifted_left: I32 = 1i32 << 31i32
+shifted_out: I32 = 1i32 << 32i32
+shifted_right: I32 = -8i32 >> 1i32
+shifted_right_logical: I32 = -8i32 >>> 28i32
+shifted_right_far: I64 = -1i64 >> 100i64
+quotient: I64 = 7i64 / -2i64
+divided_by_zero: I64 = 1i64 / (1i64 - 1i64)
+subtract(a: I64, b: I64): I64 = a - b
+from_ten: (I64): I64 = subtract(10i64, _)
+to_ten: (I64): I64 = subtract(_, 10i64)
+applied: (I64, I64) = (from_ten(3i64), to_ten(3i64))
+";
+
+    fn parse_module(text: &str) -> sir::Module {
+        let mut module = parser::parse(text, &"test.scrap".into(), parser::source_file).unwrap().1;
+        passes::Registry::new().run(&mut module, &Options::default()).unwrap();
+        module
+    }
+
+    fn evaluate(name: &str, overflow: Overflow) -> Result<String> {
+        let module = parse_module(SOURCE);
+        let mut interpreter = Interpreter::new(&module);
+        interpreter.set_overflow(overflow);
+        Ok(interpreter.evaluate_global(name)?.to_string())
+    }
+
+    fn panic_message(error: anyhow::Error) -> String {
+        error.downcast::<Panic>().expect("expected a panic").message
+    }
+
+    #[test]
+    fn overflow_traps_wraps_or_saturates() {
+        let error = evaluate("sum", Overflow::Trap).unwrap_err();
+        assert_eq!(panic_message(error), "arithmetic overflow");
+        assert_eq!(evaluate("sum", Overflow::Wrap).unwrap(), "-9223372036854775808i64");
+        assert_eq!(evaluate("sum", Overflow::Saturate).unwrap(), "9223372036854775807i64");
+    }
+
+    #[test]
+    fn explicit_operators_ignore_the_overflow_mode() {
+        for overflow in [Overflow::Trap, Overflow::Wrap, Overflow::Saturate] {
+            assert_eq!(evaluate("wrapped", overflow).unwrap(), "-9223372036854775808i64");
+            assert_eq!(evaluate("saturated", overflow).unwrap(), "2147483647i32");
+        }
+    }
+
+    #[test]
+    fn i32_arithmetic_overflows_at_32_bits() {
+        assert_eq!(evaluate("negated", Overflow::Wrap).unwrap(), "-2147483648i32");
+        let error = evaluate("negated", Overflow::Trap).unwrap_err();
+        assert_eq!(panic_message(error), "arithmetic overflow");
+    }
+
+    #[test]
+    fn shifts_by_the_width_or_more_fill_the_value() {
+        assert_eq!(evaluate("shifted_left", Overflow::Trap).unwrap(), "-2147483648i32");
+        assert_eq!(evaluate("shifted_out", Overflow::Trap).unwrap(), "0i32");
+        assert_eq!(evaluate("shifted_right", Overflow::Trap).unwrap(), "-4i32");
+        assert_eq!(evaluate("shifted_right_logical", Overflow::Trap).unwrap(), "15i32");
+        assert_eq!(evaluate("shifted_right_far", Overflow::Trap).unwrap(), "-1i64");
+    }
+
+    #[test]
+    fn division_truncates_and_panics_on_zero() {
+        assert_eq!(evaluate("quotient", Overflow::Trap).unwrap(), "-3i64");
+        for overflow in [Overflow::Trap, Overflow::Wrap, Overflow::Saturate] {
+            let error = evaluate("divided_by_zero", overflow).unwrap_err();
+            let panic = error.downcast::<Panic>().unwrap();
+            assert_eq!(panic.message, "division by zero");
+            assert_eq!(panic.location, "test.scrap:14:29");
+        }
+    }
+
+    #[test]
+    fn partial_applications_fill_their_holes_in_order() {
+        assert_eq!(evaluate("applied", Overflow::Trap).unwrap(), "(7i64, -7i64)");
+        let module = parse_module(SOURCE);
+        let interpreter = Interpreter::new(&module);
+        let from_ten = interpreter.evaluate_global("from_ten").unwrap();
+        let result = interpreter.call(from_ten, vec![Value::i64(4)]).unwrap();
+        assert_eq!(result.to_string(), "6i64");
+    }
+}
@@ -0,0 +1,21 @@
+//! The scrap compiler. The `scrap` binary is a thin wrapper around this
+//! library; other programs can use it to load and compile scrap programs, and
+//! to add passes of their own with `passes::Registry::register`.
+
+#[cfg(feature = "llvm")]
+pub mod backend;
+pub mod c_header;
+#[cfg(feature = "llvm")]
+pub mod debug_info;
+#[cfg(feature = "llvm")]
+pub mod generator;
+pub mod interpreter;
+pub mod loader;
+pub mod options;
+pub mod parser;
+pub mod passes;
+pub mod printer;
+#[cfg(feature = "llvm")]
+pub mod repl;
+pub mod sir;
+pub mod visit;
@@ -0,0 +1,246 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::{
+    options::Options,
+    parser::{parse, source_file},
+    passes::{inject_prelude::PRELUDE_MODULE, Registry, SourceModule},
+    sir,
+    visit::{self, Bindings},
+};
+
+const EXTENSION: &str = "scrap";
+
+/// Loads a program from a file or a root directory. Every `.scrap` file
+/// under the root is a module, named by its path relative to the root, so
+/// `shapes/circle.scrap` is `shapes.circle`; each is only read once it is
+/// imported. Starting from the entry module
+/// (the given file, or `main.scrap` in the given directory), each module and
+/// everything it imports is parsed, run through the registry's source passes
+/// and merged into a single module. Globals of imported modules are named by
+/// their module path, e.g. `geometry.area`; those of the entry module keep
+/// their plain names, but only the entry module itself can use them.
+pub fn load_program(input: &Path, registry: &Registry, options: &Options) -> Result<sir::Module> {
+    let (root, entry) = if input.is_dir() {
+        (input.to_path_buf(), "main".to_string())
+    } else {
+        // A bare file name has an empty parent rather than none.
+        let root = match input.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let entry = input
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or_else(|| anyhow!("Invalid source file name {}", input.display()))?
+            .to_string();
+        (root, entry)
+    };
+
+    let mut loader = Loader {
+        registry,
+        options,
+        root,
+        loaded: HashSet::new(),
+        stack: Vec::new(),
+        program: sir::Module {
+            globals: HashMap::new(),
+            externs: HashMap::new(),
+        },
+        owners: HashMap::new(),
+        uses: Vec::new(),
+    };
+    loader.load(&entry, true)?;
+    loader.check_visibility()?;
+    check_export_symbols(&loader.program)?;
+    Ok(loader.program)
+}
+
+/// Checks that no two exported globals have the same symbol, as
+/// `geometry.area` and `geometry_area` would, and that none is exported as
+/// `main`, which is the generated entry point's.
+fn check_export_symbols(program: &sir::Module) -> Result<()> {
+    let mut exports: Vec<_> = program.globals.iter().filter(|(_, global)| global.export).collect();
+    exports.sort_by_key(|(name, _)| name.as_str());
+    let mut symbols = HashMap::new();
+    for (name, global) in exports {
+        if global.symbol(name) == "main" {
+            bail!("{} can't be exported as main, which is the entry point's symbol", name);
+        }
+        if let Some(other) = symbols.insert(global.symbol(name), name) {
+            bail!("{} and {} are both exported as {}", other, name, global.symbol(name));
+        }
+    }
+    Ok(())
+}
+
+/// The source files at a path: the path itself if it is a file, or every
+/// `.scrap` file under it if it is a directory, sorted.
+pub fn source_files(path: &Path) -> Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut files = HashMap::new();
+    discover(path, path, &mut files)?;
+    let mut files: Vec<_> = files.into_values().collect();
+    files.sort();
+    Ok(files)
+}
+
+fn discover(root: &Path, dir: &Path, files: &mut HashMap<String, PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir).with_context(|| format!("Reading {}", dir.display()))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            discover(root, &path, files)?;
+        } else if path.extension().and_then(|extension| extension.to_str()) == Some(EXTENSION) {
+            let name = path
+                .strip_prefix(root)?
+                .with_extension("")
+                .iter()
+                .map(|component| component.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(".");
+            files.insert(name, path);
+        }
+    }
+    Ok(())
+}
+
+struct Loader<'a> {
+    registry: &'a Registry,
+    options: &'a Options,
+    root: PathBuf,
+    loaded: HashSet<String>,
+    /// The chain of modules currently being loaded, used to report cycles.
+    stack: Vec<String>,
+    program: sir::Module,
+    /// The module that defines each global in the program.
+    owners: HashMap<String, String>,
+    /// The globals that each module refers to, in the order the modules
+    /// were loaded, checked once every module is in the program.
+    uses: Vec<(String, String)>,
+}
+
+impl Loader<'_> {
+    fn load(&mut self, name: &str, is_entry: bool) -> Result<()> {
+        if let Some(position) = self.stack.iter().position(|loading| loading == name) {
+            let mut cycle = self.stack[position..].to_vec();
+            cycle.push(name.to_string());
+            bail!("Import cycle: {}", cycle.join(" -> "));
+        }
+        if self.loaded.contains(name) {
+            return Ok(());
+        }
+
+        let path = self.module_path(name);
+        if !path.is_file() {
+            bail!("No module named {} in {}", name, self.root.display());
+        }
+        let text = fs::read_to_string(&path).with_context(|| format!("Reading {}", path.display()))?;
+        let file = path.display().to_string().into();
+        let (imports, mut module) = parse(&text, &file, source_file)?;
+
+        self.stack.push(name.to_string());
+        for import in imports.iter() {
+            self.load(import, false)?;
+        }
+        self.stack.pop();
+        self.loaded.insert(name.to_string());
+
+        let mut source = SourceModule {
+            name,
+            is_entry,
+            imports: &imports,
+            module: &mut module,
+        };
+        self.registry.run_source(&mut source, self.options)?;
+        if !is_entry {
+            check_qualified(&module, &imports)?;
+        }
+        self.record_uses(name, &module)?;
+
+        for (global_name, global) in module.globals {
+            if imports.contains(&global_name) {
+                bail!("{} in {} has the same name as an imported module", global_name, name);
+            }
+            self.owners.insert(global_name.clone(), name.to_string());
+            self.program.globals.insert(global_name, global);
+        }
+        self.program.externs.extend(module.externs);
+
+        Ok(())
+    }
+
+    /// The file holding a module: `shapes.circle` is `shapes/circle.scrap`
+    /// under the root.
+    fn module_path(&self, name: &str) -> PathBuf {
+        let mut path = self.root.clone();
+        path.extend(name.split('.'));
+        path.set_extension(EXTENSION);
+        path
+    }
+
+    /// Records the names that a module's globals refer to, apart from
+    /// those bound by parameters and scopes.
+    fn record_uses(&mut self, module_name: &str, module: &sir::Module) -> Result<()> {
+        for global in module.globals.values() {
+            let mut bindings = Bindings::for_global(global);
+            visit::try_for_each(&global.body, &mut bindings, |expression, bindings| {
+                if let sir::Expression::Reference { name, .. } = expression {
+                    if !bindings.is_bound(name) {
+                        self.uses.push((module_name.to_string(), name.clone()));
+                    }
+                }
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Checks that each module only refers to its own globals and the public
+    /// globals of other modules, the entry module's included. This waits
+    /// until every module has been merged, since the entry module is merged
+    /// last.
+    fn check_visibility(&self) -> Result<()> {
+        for (module_name, name) in self.uses.iter() {
+            let Some(global) = self.program.globals.get(name) else {
+                continue;
+            };
+            if !global.public && self.owners.get(name) != Some(module_name) {
+                bail!("{} is private, but is used by {}", name, module_name);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks that an imported module names nothing outside itself without
+/// qualifying it. Once its source passes have run, every reference that is
+/// not bound by a parameter or scope must be to one of its own globals or
+/// externs, to the prelude, or to a module it imports. Anything else would
+/// otherwise find whatever global the entry module, whose globals keep their
+/// plain names, happens to define.
+fn check_qualified(module: &sir::Module, imports: &[String]) -> Result<()> {
+    for global in module.globals.values() {
+        let mut bindings = Bindings::for_global(global);
+        visit::try_for_each(&global.body, &mut bindings, |expression, bindings| {
+            let sir::Expression::Reference { name, location } = expression else {
+                return Ok(());
+            };
+            let is_qualified = module.globals.contains_key(name)
+                || module.externs.contains_key(name)
+                || name.starts_with(&format!("{}.", PRELUDE_MODULE))
+                || imports.iter().any(|import| name.starts_with(&format!("{}.", import)));
+            if !is_qualified && !bindings.is_bound(name) {
+                bail!("{}: unknown name {}", location, name);
+            }
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
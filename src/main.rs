@@ -1,63 +1,198 @@
 use std::path::Path;
 
+#[cfg(feature = "llvm")]
 use inkwell::context::Context;
-use nom::Parser;
-
-use crate::{
-    generator::Generator,
-    parser::module,
-    passes::{
-        build_function_params::build_function_params,
-        build_global_references::build_global_references, remove_scopes::remove_scopes,
-    },
-};
 
-mod generator;
-mod parser;
-mod passes;
-mod sir;
+#[cfg(feature = "llvm")]
+use scrap::{backend, generator::Generator, repl};
+use scrap::{
+    c_header::c_header,
+    interpreter::{Interpreter, Panic, PANIC_EXIT_STATUS},
+    loader::{load_program, source_files},
+    options::{Emit, Options},
+    passes::Registry,
+    printer::format_source,
+    sir,
+};
 
 fn main() -> anyhow::Result<()> {
-    let text = r#"
-add(pair: (I64, I64)): I64 = pair.elem_0 + pair.elem_1
-pair(first: (I64, I64), second: (I64, I64)): ((I64, I64), (I64, I64)) = (first, second)
-    "#;
+    let args: Vec<_> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("repl") {
+        return start_repl();
+    }
+    if args.first().map(String::as_str) == Some("fmt") {
+        return format(&args[1..]);
+    }
+    let options = Options::parse(args)?;
+
+    let registry = Registry::new();
+    if options.print_passes {
+        for pass in registry.passes() {
+            match pass.prerequisites() {
+                [] => println!("{}", pass.name()),
+                prerequisites => println!("{} (after {})", pass.name(), prerequisites.join(", ")),
+            }
+        }
+        return Ok(());
+    }
 
-    let parsed = module.parse(text)?;
-    println!("{:#?}", parsed);
-    let mut parsed = parsed.1;
+    let input = options
+        .input
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("usage: scrap [options] <file or directory>"))?;
 
-    remove_scopes(&mut parsed);
-    build_function_params(&mut parsed);
-    build_global_references(&mut parsed);
-    println!("{:#?}", parsed);
+    let mut parsed = load_program(input, &registry, &options)?;
+    registry.run(&mut parsed, &options)?;
 
-    let context = Context::create();
-    let mut generator = Generator::new(&context);
+    if options.interpret {
+        run(&parsed, &options)
+    } else if options.emit == Emit::CHeader {
+        write_c_header(&parsed, &options)
+    } else {
+        compile(&parsed, &options)
+    }
+}
 
-    for (name, global) in parsed.globals.iter() {
-        if global.arguments.is_empty() {
-            generator.declare_global_constant(name.clone(), &global.return_type);
-        } else {
-            generator.declare_global_function(name.clone(), &global.arguments, &global.return_type);
+fn run(parsed: &sir::Module, options: &Options) -> anyhow::Result<()> {
+    let mut interpreter = Interpreter::new(parsed);
+    interpreter.set_overflow(options.overflow());
+
+    let mut names: Vec<_> = parsed
+        .globals
+        .iter()
+        .filter(|(_, global)| global.arguments.is_empty())
+        .map(|(name, _)| name)
+        .collect();
+    names.sort();
+
+    for name in names {
+        match interpreter.evaluate_global(name) {
+            Ok(value) => println!("{} = {}", name, value),
+            // Exits the way a compiled program would.
+            Err(error) if error.is::<Panic>() => {
+                eprintln!("{}", error);
+                std::process::exit(PANIC_EXIT_STATUS);
+            }
+            Err(error) => return Err(error),
         }
     }
 
-    for (name, global) in parsed.globals.iter() {
-        if global.arguments.is_empty() {
-            if let sir::DataType::Primitive(_) = global.return_type {
-                generator.write_global_primitive_constant(name, &global.body);
+    Ok(())
+}
+
+/// Rewrites source files in the standard layout, or with `--check`, fails
+/// if any of them would change.
+fn format(args: &[String]) -> anyhow::Result<()> {
+    let check = args.iter().any(|arg| arg == "--check");
+    let paths: Vec<_> = args.iter().filter(|arg| *arg != "--check").collect();
+    if paths.is_empty() {
+        anyhow::bail!("usage: scrap fmt [--check] <file or directory>...");
+    }
+
+    let mut unformatted = Vec::new();
+    for path in paths {
+        for file in source_files(Path::new(path))? {
+            let text = std::fs::read_to_string(&file)?;
+            let formatted = format_source(&text, &file.display().to_string().into())?;
+            if formatted == text {
+                continue;
+            }
+            if check {
+                println!("{} is not formatted", file.display());
+                unformatted.push(file);
             } else {
-                generator.write_global_nonprimitive_constant(name, &global.body);
+                std::fs::write(&file, formatted)?;
             }
-        } else {
-            generator.write_global_function(name, &global.body);
         }
     }
 
-    let module = generator.build();
+    if !unformatted.is_empty() {
+        anyhow::bail!("{} files are not formatted; run scrap fmt", unformatted.len());
+    }
+    Ok(())
+}
+
+fn write_c_header(parsed: &sir::Module, options: &Options) -> anyhow::Result<()> {
+    match &options.output {
+        Some(output) => {
+            let guard = output
+                .file_name()
+                .map(|name| name.to_string_lossy().to_uppercase())
+                .unwrap_or_default()
+                .replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+            std::fs::write(output, c_header(parsed, &guard))?;
+        }
+        None => print!("{}", c_header(parsed, "SCRAP_H")),
+    }
+    Ok(())
+}
+
+#[cfg(feature = "llvm")]
+fn compile(parsed: &sir::Module, options: &Options) -> anyhow::Result<()> {
+    let context = Context::create();
+    let machine = backend::target_machine(options)?;
+    let mut generator = Generator::new(&context, "scrap")?;
+    generator.set_target(&machine);
+    generator.set_overflow(options.overflow());
+    if options.gc {
+        let triple = machine.get_triple();
+        let triple = triple.as_str().to_string_lossy();
+        if !triple.starts_with("x86_64") {
+            anyhow::bail!("--gc is only supported on x86-64, not {}", triple);
+        }
+        generator.enable_gc();
+    }
+    if options.debug_info {
+        generator.enable_debug_info(options.input.as_ref().unwrap(), &machine);
+    }
+
+    for (name, declaration) in parsed.externs.iter() {
+        generator.declare_extern(name.clone(), declaration)?;
+    }
+
+    for (name, global) in parsed.globals.iter() {
+        generator.declare_global(name.clone(), global)?;
+    }
+
+    for (name, global) in parsed.globals.iter() {
+        generator.write_global(name, global)?;
+    }
+
+    if let Some(name) = options.entry() {
+        let entry = parsed
+            .globals
+            .get(name)
+            .ok_or_else(|| anyhow::anyhow!("no global named {}", name))?;
+        generator.write_entry_point(name, entry)?;
+    }
+
+    let module = generator.build()?;
+    backend::optimize(&module, options.opt_level);
+    if options.gc {
+        backend::rewrite_statepoints(&module, &machine)?;
+    }
     println!("{}", module.to_string());
     module.write_bitcode_to_path(&Path::new("scrap.ll"));
 
+    if let Some(output) = &options.output {
+        let gc_heap_limit = options.gc.then(|| options.heap_limit());
+        backend::link_executable(&module, &machine, output, gc_heap_limit)?;
+    }
+
     Ok(())
 }
+
+#[cfg(feature = "llvm")]
+fn start_repl() -> anyhow::Result<()> {
+    repl::run()
+}
+
+#[cfg(not(feature = "llvm"))]
+fn start_repl() -> anyhow::Result<()> {
+    anyhow::bail!("scrap was built without LLVM support; the REPL needs its JIT")
+}
+
+#[cfg(not(feature = "llvm"))]
+fn compile(_parsed: &sir::Module, _options: &Options) -> anyhow::Result<()> {
+    anyhow::bail!("scrap was built without LLVM support; use --interpret")
+}
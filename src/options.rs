@@ -0,0 +1,195 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Result};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Emit {
+    /// LLVM IR, and an executable if `-o` is given.
+    Llvm,
+    /// A C header for the exported globals, written to `-o` or stdout.
+    CHeader,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OptLevel {
+    O0,
+    O1,
+    O2,
+    O3,
+    /// Like `O2`, but avoiding optimizations that grow the code.
+    Os,
+}
+
+/// What `+`, `-`, `*`, `/` and prefix `-` do when the result does not fit in
+/// its type. The `%` and `|` forms of the operators always wrap and saturate.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Panic, reporting the location of the operator.
+    Trap,
+    Wrap,
+    /// Clamp the result to the smallest or largest value of the type.
+    Saturate,
+}
+
+pub struct Options {
+    /// A source file, or a root directory containing `main.scrap`.
+    pub input: Option<PathBuf>,
+    pub interpret: bool,
+    pub emit: Emit,
+    /// The global that the generated C `main` calls, if `--entry` names one
+    /// (see `Options::entry`).
+    pub entry: Option<String>,
+    /// Where to link an executable, if anywhere.
+    pub output: Option<PathBuf>,
+    /// Whether to emit DWARF debug info.
+    pub debug_info: bool,
+    pub opt_level: OptLevel,
+    /// The overflow behaviour asked for, if any (see `Options::overflow`).
+    pub overflow: Option<Overflow>,
+    /// Whether to manage boxes with a tracing garbage collector rather than
+    /// by counting references to them.
+    pub gc: bool,
+    /// The most memory the collector may hand out, in bytes (see
+    /// `Options::heap_limit`).
+    pub heap_limit: Option<u64>,
+    /// The LLVM target triple to compile for, or the host's if `None`.
+    pub target: Option<String>,
+    /// The CPU to tune for. Defaults to the host CPU when compiling for the
+    /// host, and to `generic` otherwise.
+    pub cpu: Option<String>,
+    /// LLVM target features such as `+avx2,-sse4.1`. Defaults to the host's
+    /// when compiling for the host, and to none otherwise.
+    pub features: Option<String>,
+    /// Passes after which to print the SIR to stderr.
+    pub dump_after: Vec<String>,
+    /// Passes not to run.
+    pub skip: Vec<String>,
+    /// List the registered passes and exit.
+    pub print_passes: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            input: None,
+            interpret: false,
+            emit: Emit::Llvm,
+            entry: None,
+            output: None,
+            debug_info: false,
+            opt_level: OptLevel::O0,
+            overflow: None,
+            gc: false,
+            heap_limit: None,
+            target: None,
+            cpu: None,
+            features: None,
+            dump_after: Vec::new(),
+            skip: Vec::new(),
+            print_passes: false,
+        }
+    }
+}
+
+impl Options {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut options = Options::default();
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with('-') => {
+                    (flag.to_string(), Some(value.to_string()))
+                }
+                _ => (arg, None),
+            };
+            let mut value = || {
+                inline_value
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| anyhow!("{} expects a value", flag))
+            };
+
+            match flag.as_str() {
+                "--interpret" => options.interpret = true,
+                "--emit" => {
+                    options.emit = match value()?.as_str() {
+                        "llvm" => Emit::Llvm,
+                        "c-header" => Emit::CHeader,
+                        emit => bail!("Unknown --emit kind {}", emit),
+                    }
+                }
+                "--entry" => options.entry = Some(value()?),
+                "-o" => options.output = Some(value()?.into()),
+                "-g" => options.debug_info = true,
+                "-O0" => options.opt_level = OptLevel::O0,
+                "-O1" => options.opt_level = OptLevel::O1,
+                "-O2" => options.opt_level = OptLevel::O2,
+                "-O3" => options.opt_level = OptLevel::O3,
+                "-Os" => options.opt_level = OptLevel::Os,
+                "--overflow" => {
+                    options.overflow = Some(match value()?.as_str() {
+                        "trap" => Overflow::Trap,
+                        "wrap" => Overflow::Wrap,
+                        "saturate" => Overflow::Saturate,
+                        overflow => bail!("Unknown --overflow behaviour {}", overflow),
+                    })
+                }
+                "--gc" => options.gc = true,
+                "--heap-limit" => {
+                    let limit = value()?;
+                    options.heap_limit = Some(
+                        limit
+                            .parse()
+                            .map_err(|_| anyhow!("--heap-limit expects a number of bytes, not {}", limit))?,
+                    );
+                }
+                "--target" => options.target = Some(value()?),
+                "--cpu" => options.cpu = Some(value()?),
+                "--features" => options.features = Some(value()?),
+                "--dump-after" => options.dump_after.push(value()?),
+                "--skip" => options.skip.push(value()?),
+                "--print-passes" => options.print_passes = true,
+                _ if flag.starts_with('-') => bail!("Unknown option {}", flag),
+                _ if options.input.is_some() => bail!("Unexpected argument {}", flag),
+                _ => options.input = Some(flag.into()),
+            }
+        }
+
+        // The collector's runtime walks x86-64 frames. A host build is
+        // checked once the target machine is known.
+        if let (true, Some(target)) = (options.gc, &options.target) {
+            if !target.starts_with("x86_64") {
+                bail!("--gc is only supported on x86-64, not {}", target);
+            }
+        }
+
+        Ok(options)
+    }
+
+    /// The overflow behaviour to use. Unless `--overflow` says otherwise,
+    /// unoptimized builds trap and optimized ones wrap.
+    pub fn overflow(&self) -> Overflow {
+        self.overflow.unwrap_or(match self.opt_level {
+            OptLevel::O0 => Overflow::Trap,
+            _ => Overflow::Wrap,
+        })
+    }
+
+    /// The global to wrap in a C `main`, if the program needs one: the one
+    /// named by `--entry`, or `main` when linking an executable. Without
+    /// either, the program is compiled as a library for C to call.
+    pub fn entry(&self) -> Option<&str> {
+        match &self.entry {
+            Some(entry) => Some(entry),
+            None if self.output.is_some() => Some("main"),
+            None => None,
+        }
+    }
+
+    /// The heap limit for `--gc`, which is 64 MiB unless `--heap-limit` says
+    /// otherwise.
+    pub fn heap_limit(&self) -> u64 {
+        self.heap_limit.unwrap_or(64 << 20)
+    }
+}
@@ -1,70 +1,378 @@
+use std::{
+    cell::{OnceCell, RefCell},
+    collections::{BTreeMap, HashMap},
+    rc::Rc,
+};
+
+use anyhow::anyhow;
 use nom::{
     bytes::complete::tag,
-    character::complete::{multispace0, satisfy},
-    combinator::{opt, recognize},
-    error::ParseError,
+    character::complete::{anychar, char, multispace0, not_line_ending, satisfy},
+    combinator::{all_consuming, opt, recognize, verify},
+    error::{Error, ErrorKind, ParseError},
     multi::{many0, separated_list0, separated_list1},
-    sequence::{delimited, preceded, separated_pair, terminated, tuple},
-    AsChar, IResult, InputTakeAtPosition, Parser,
+    sequence::{delimited, pair, preceded, separated_pair, terminated, tuple},
+    AsChar, IResult, Parser,
 };
+use nom_locate::{position, LocatedSpan};
 
 use crate::sir::{self, DataType};
 
-pub fn module(input: &str) -> IResult<&str, sir::Module> {
-    preceded(multispace0, many0(global))
-        .map(|globals| sir::Module {
-            globals: globals.into_iter().collect(),
+/// Parser input: source text that tracks its line and column, along with the
+/// file it came from.
+pub type Span<'a> = LocatedSpan<&'a str, &'a Source>;
+
+/// A named type's definition, which is set when its declaration is parsed,
+/// and where it was first named.
+type TypeDefinition = (Rc<OnceCell<DataType>>, sir::Location);
+
+/// The file being parsed, shared by every span into it.
+pub struct Source {
+    pub file: Rc<str>,
+    /// The comments skipped so far, by offset. Backtracking may skip the
+    /// same comment more than once.
+    comments: RefCell<BTreeMap<usize, String>>,
+    /// The bindings in blocks parsed so far, by offset.
+    bindings: RefCell<BTreeMap<usize, Binding>>,
+    /// The definition of each type named so far.
+    types: RefCell<HashMap<String, TypeDefinition>>,
+}
+
+/// A comment, including its delimiters, and its byte offset in the source.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Comment {
+    pub offset: usize,
+    pub text: String,
+}
+
+impl Comment {
+    pub fn end(&self) -> usize {
+        self.offset + self.text.len()
+    }
+}
+
+/// Where a binding in a block was written: the byte offsets of its name, and
+/// of the expression and closing brace that end its block.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Binding {
+    pub offset: usize,
+    pub body: usize,
+    pub end: usize,
+}
+
+/// What parsing found besides the tree: every comment in the source, and
+/// every binding in a block, each in order.
+#[derive(Debug, Default)]
+pub struct Layout {
+    pub comments: Vec<Comment>,
+    pub bindings: Vec<Binding>,
+}
+
+/// Runs a parser over the whole of `text`, reporting a syntax error with the
+/// line and column at which parsing stopped.
+pub fn parse<O>(
+    text: &str,
+    file: &Rc<str>,
+    parser: impl for<'s> Parser<Span<'s>, O, Error<Span<'s>>>,
+) -> anyhow::Result<O> {
+    parse_with_layout(text, file, parser).map(|(output, _)| output)
+}
+
+/// Like `parse`, but also returns where comments and bindings were written.
+pub fn parse_with_layout<O>(
+    text: &str,
+    file: &Rc<str>,
+    parser: impl for<'s> Parser<Span<'s>, O, Error<Span<'s>>>,
+) -> anyhow::Result<(O, Layout)> {
+    let source = Source {
+        file: file.clone(),
+        comments: RefCell::new(BTreeMap::new()),
+        bindings: RefCell::new(BTreeMap::new()),
+        types: RefCell::new(HashMap::new()),
+    };
+    let output = all_consuming(preceded(space, parser))
+        .parse(Span::new_extra(text, &source))
+        .map(|(_, output)| output)
+        .map_err(|e| match e {
+            nom::Err::Error(e) | nom::Err::Failure(e) => anyhow!(
+                "{}:{}:{}: syntax error",
+                file,
+                e.input.location_line(),
+                e.input.get_utf8_column()
+            ),
+            nom::Err::Incomplete(_) => anyhow!("{}: unexpected end of input", file),
+        })?;
+    let types = source.types.borrow();
+    let undeclared = types
+        .iter()
+        .filter(|(_, (definition, _))| definition.get().is_none())
+        .min_by_key(|(_, (_, location))| (location.line, location.column));
+    if let Some((name, (_, location))) = undeclared {
+        anyhow::bail!("{}: unknown type {}", location, name);
+    }
+    drop(types);
+    let comments = source
+        .comments
+        .into_inner()
+        .into_iter()
+        .map(|(offset, text)| Comment { offset, text })
+        .collect();
+    let bindings = source.bindings.into_inner().into_values().collect();
+    Ok((output, Layout { comments, bindings }))
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Item {
+    Global(String, sir::Global),
+    Extern(String, sir::Extern),
+    Type(String, DataType),
+}
+
+pub fn module(input: Span) -> IResult<Span, sir::Module> {
+    preceded(space, many0(item))
+        .map(collect_items)
+        .parse(input)
+}
+
+/// Parses a whole source file: its imports, followed by its globals and
+/// externs.
+pub fn source_file(input: Span) -> IResult<Span, (Vec<String>, sir::Module)> {
+    source_items
+        .map(|(imports, items)| {
+            let imports = imports.into_iter().map(|(_, import)| import).collect();
+            let items = items.into_iter().map(|(_, item)| item).collect();
+            (imports, collect_items(items))
         })
         .parse(input)
 }
 
-fn global(input: &str) -> IResult<&str, (String, sir::Global)> {
+/// Like `source_file`, but keeps the imports and items in the order in
+/// which they were written, along with the offset at which each starts.
+#[allow(clippy::type_complexity)]
+pub fn source_items(input: Span) -> IResult<Span, (Vec<(usize, String)>, Vec<(usize, Item)>)> {
+    preceded(space, many0(offset.and(import)).and(many0(offset.and(item)))).parse(input)
+}
+
+fn collect_items(items: Vec<Item>) -> sir::Module {
+    let mut module = sir::Module {
+        globals: HashMap::new(),
+        externs: HashMap::new(),
+    };
+    for item in items {
+        match item {
+            Item::Global(name, global) => {
+                module.globals.insert(name, global);
+            }
+            Item::Extern(name, declaration) => {
+                module.externs.insert(name, declaration);
+            }
+            // Types are resolved while parsing, so nothing refers to them by
+            // name any more.
+            Item::Type(..) => {}
+        }
+    }
+    module
+}
+
+fn import(input: Span) -> IResult<Span, String> {
+    preceded(
+        reserved_word("import"),
+        separated_list1(keyword("."), identifier),
+    )
+    .map(|path| path.join("."))
+    .parse(input)
+}
+
+pub fn item(input: Span) -> IResult<Span, Item> {
+    type_declaration
+        .map(|(name, data_type)| Item::Type(name, data_type))
+        .or(extern_declaration.map(|(name, declaration)| Item::Extern(name, declaration)))
+        .or(global.map(|(name, global)| Item::Global(name, global)))
+        .parse(input)
+}
+
+/// `type Name = T` names `T`, so that `Box<Name>` can be used in `T` and
+/// elsewhere in the file, before or after the declaration.
+fn type_declaration(input: Span) -> IResult<Span, (String, DataType)> {
+    let (rest, (name, data_type)) = preceded(
+        reserved_word("type"),
+        separated_pair(named_box, keyword("="), data_type),
+    )
+    .parse(input)?;
+    let name = name.name.clone().unwrap();
+    let (definition, _) = input.extra.types.borrow()[&name].clone();
+    // Backtracking may parse the same declaration twice.
+    if definition.get_or_init(|| data_type.clone()) != &data_type {
+        return Err(nom::Err::Failure(Error::new(input, ErrorKind::Verify)));
+    }
+    Ok((rest, (name, data_type)))
+}
+
+fn extern_declaration(input: Span) -> IResult<Span, (String, sir::Extern)> {
+    let arguments = separated_list0(keyword(","), argument);
     tuple((
+        doc_comments,
+        preceded(reserved_word("extern"), identifier),
+        delimited(keyword("("), arguments, keyword(")")),
+        preceded(keyword(":"), non_function_type),
+    ))
+    .map(|(doc, name, arguments, return_type)| {
+        (
+            name,
+            sir::Extern {
+                arguments,
+                return_type,
+                doc,
+            },
+        )
+    })
+    .parse(input)
+}
+
+/// A global: `[pub] [export] name[(arguments)]: type = body`. The markers
+/// are only reserved here, but a global named `pub` or `export` is read as
+/// a marker with the name missing, so neither can name a global.
+pub fn global(input: Span) -> IResult<Span, (String, sir::Global)> {
+    tuple((
+        doc_comments,
+        opt(reserved_word("pub")),
+        opt(reserved_word("export")),
+        location,
         identifier,
         opt(argument_list),
-        preceded(keyword(":"), non_function_type),
+        preceded(keyword(":"), data_type),
         preceded(keyword("="), expression),
     ))
-    .map(|(name, arguments, return_type, body)| {
+    .map(|(doc, public, export, location, name, arguments, return_type, mut body)| {
+        body.expect_type(&return_type);
         (
             name,
             sir::Global {
                 arguments: arguments.unwrap_or_default(),
                 return_type,
                 body,
+                public: public.is_some(),
+                export: export.is_some(),
+                location,
+                doc,
             },
         )
     })
     .parse(input)
 }
 
-fn identifier(input: &str) -> IResult<&str, String> {
+fn identifier(input: Span) -> IResult<Span, String> {
     let first_char = satisfy(|c| c.is_lowercase() || c == '_');
     let rest_char = satisfy(|c| c.is_lowercase() || c.is_dec_digit() || c == '_');
     let identifier_str = recognize(first_char.and(many0(rest_char)));
-    let identifier = identifier_str.map(|id: &str| id.to_string());
+    let identifier = identifier_str.map(|id: Span| id.fragment().to_string());
     ws_terminated(identifier).parse(input)
 }
 
-fn ws_terminated<I, O, E>(parser: impl Parser<I, O, E>) -> impl FnMut(I) -> IResult<I, O, E>
-where
-    I: InputTakeAtPosition,
-    <I as InputTakeAtPosition>::Item: AsChar + Clone,
-    E: ParseError<I>,
-{
-    terminated(parser, multispace0)
+/// The byte offset of the next token, consuming nothing.
+fn offset(input: Span) -> IResult<Span, usize> {
+    position.map(|span: Span| span.location_offset()).parse(input)
 }
 
-fn keyword(word: &str) -> impl Parser<&str, &str, nom::error::Error<&str>> {
+/// The location of the next token, consuming nothing.
+fn location(input: Span) -> IResult<Span, sir::Location> {
+    position
+        .map(|span: Span| sir::Location {
+            file: span.extra.file.clone(),
+            line: span.location_line(),
+            column: span.get_utf8_column() as u32,
+        })
+        .parse(input)
+}
+
+fn reserved_word<'a>(word: &'static str) -> impl FnMut(Span<'a>) -> IResult<Span<'a>, String> {
+    verify(identifier, move |id: &str| id == word)
+}
+
+fn ws_terminated<'a, O>(
+    parser: impl Parser<Span<'a>, O, Error<Span<'a>>>,
+) -> impl FnMut(Span<'a>) -> IResult<Span<'a>, O> {
+    terminated(parser, space)
+}
+
+/// Skips whitespace and comments: `//` to the end of the line, and `/* */`,
+/// which may nest. Doc comments are not skipped, since they belong to the
+/// item that follows them. Each comment is recorded in the `Source`.
+fn space(input: Span) -> IResult<Span, ()> {
+    let mut input = multispace0(input)?.0;
+    loop {
+        let (rest, comment) = opt(recognize(line_comment.or(block_comment)))(input)?;
+        let Some(comment) = comment else {
+            return Ok((input, ()));
+        };
+        input
+            .extra
+            .comments
+            .borrow_mut()
+            .entry(comment.location_offset())
+            .or_insert_with(|| comment.fragment().to_string());
+        input = multispace0(rest)?.0;
+    }
+}
+
+fn is_doc_comment(comment: &str) -> bool {
+    comment.starts_with("///") && !comment.starts_with("////")
+}
+
+fn line_comment(input: Span) -> IResult<Span, ()> {
+    verify(recognize(pair(tag("//"), not_line_ending)), |comment: &Span| {
+        !is_doc_comment(comment.fragment())
+    })
+    .map(|_| ())
+    .parse(input)
+}
+
+fn block_comment(input: Span) -> IResult<Span, ()> {
+    let (mut rest, _) = tag("/*")(input)?;
+    let mut depth = 1;
+    while depth > 0 {
+        if let Ok((after, _)) = tag::<_, _, Error<Span>>("*/")(rest) {
+            depth -= 1;
+            rest = after;
+        } else if let Ok((after, _)) = tag::<_, _, Error<Span>>("/*")(rest) {
+            depth += 1;
+            rest = after;
+        } else {
+            // An unterminated comment can't be anything else, so there is
+            // no point in backtracking.
+            rest = anychar(rest)
+                .map_err(|_: nom::Err<Error<Span>>| nom::Err::Failure(Error::new(input, ErrorKind::Eof)))?
+                .0;
+        }
+    }
+    Ok((rest, ()))
+}
+
+/// `///` comments, with the marker and one following space removed from
+/// each line.
+fn doc_comments(input: Span) -> IResult<Span, Option<String>> {
+    let line = verify(recognize(pair(tag("///"), not_line_ending)), |comment: &Span| {
+        is_doc_comment(comment.fragment())
+    })
+    .map(|comment: Span| {
+        let line = &comment.fragment()[3..];
+        line.strip_prefix(' ').unwrap_or(line).trim_end().to_string()
+    });
+    many0(ws_terminated(line))
+        .map(|lines| if lines.is_empty() { None } else { Some(lines.join("\n")) })
+        .parse(input)
+}
+
+fn keyword<'a>(word: &'static str) -> impl Parser<Span<'a>, Span<'a>, Error<Span<'a>>> {
     ws_terminated(tag(word))
 }
 
-fn data_type(input: &str) -> IResult<&str, DataType> {
+fn data_type(input: Span) -> IResult<Span, DataType> {
     function_type.or(non_function_type).parse(input)
 }
 
-fn function_type(input: &str) -> IResult<&str, DataType> {
-    let arguments = separated_list1(keyword(","), data_type);
+fn function_type(input: Span) -> IResult<Span, DataType> {
+    let arguments = separated_list0(keyword(","), data_type);
     let arguments = delimited(keyword("("), arguments, keyword(")"));
     separated_pair(arguments, keyword(":"), non_function_type)
         .map(|(argument_types, return_type)| {
@@ -76,37 +384,116 @@ fn function_type(input: &str) -> IResult<&str, DataType> {
         .parse(input)
 }
 
-fn non_function_type(input: &str) -> IResult<&str, DataType> {
+fn non_function_type(input: Span) -> IResult<Span, DataType> {
     keyword("I64")
         .map(|_| sir::DataType::Primitive(sir::PrimitiveDataType::I64))
+        .or(keyword("I32").map(|_| sir::DataType::Primitive(sir::PrimitiveDataType::I32)))
+        .or(box_type)
         .or(tuple_type)
         .parse(input)
 }
 
-fn tuple_type(input: &str) -> IResult<&str, DataType> {
+fn box_type(input: Span) -> IResult<Span, DataType> {
+    preceded(keyword("Box"), box_target)
+        .map(|box_type| sir::DataType::Primitive(sir::PrimitiveDataType::Box(box_type)))
+        .parse(input)
+}
+
+/// `<T>`, or `<Name>` for a type declared with `type`.
+fn box_target(input: Span) -> IResult<Span, sir::BoxType> {
+    let target = named_box.or(data_type.map(sir::BoxType::new));
+    delimited(keyword("<"), target, keyword(">")).parse(input)
+}
+
+/// A box of a declared type, which is looked up by name.
+fn named_box(input: Span) -> IResult<Span, sir::BoxType> {
+    let (rest, (location, name)) = location.and(type_name).parse(input)?;
+    let mut types = input.extra.types.borrow_mut();
+    let (definition, _) = types.entry(name.clone()).or_insert_with(|| (Rc::default(), location));
+    Ok((rest, sir::BoxType::named(name, definition.clone())))
+}
+
+fn type_name(input: Span) -> IResult<Span, String> {
+    let first_char = satisfy(|c| c.is_uppercase());
+    let rest_char = satisfy(|c| c.is_alphanumeric());
+    let name = recognize(first_char.and(many0(rest_char))).map(|name: Span| name.fragment().to_string());
+    let name = verify(name, |name: &str| !["Box", "I32", "I64"].contains(&name));
+    ws_terminated(name).parse(input)
+}
+
+fn tuple_type(input: Span) -> IResult<Span, DataType> {
     let elems = separated_list1(keyword(","), data_type).map(|elems| sir::DataType::Tuple(elems));
     let mut tuple = delimited(keyword("("), elems, keyword(")"));
     tuple.parse(input)
 }
 
-fn type_qualifier(input: &str) -> IResult<&str, DataType> {
+fn type_qualifier(input: Span) -> IResult<Span, DataType> {
     preceded(keyword(":"), data_type).parse(input)
 }
 
-fn argument_list(input: &str) -> IResult<&str, Vec<(String, sir::DataType)>> {
-    let argument = identifier.and(type_qualifier);
+fn argument(input: Span) -> IResult<Span, (String, sir::DataType)> {
+    identifier.and(type_qualifier).parse(input)
+}
+
+fn argument_list(input: Span) -> IResult<Span, Vec<(String, sir::DataType)>> {
     let arguments = separated_list1(keyword(","), argument);
     delimited(keyword("("), arguments, keyword(")")).parse(input)
 }
 
-fn i64_literal(input: &str) -> IResult<&str, sir::Expression> {
+fn i32_literal(input: Span) -> IResult<Span, sir::Expression> {
+    terminated(nom::character::complete::i32, keyword("i32"))
+        .map(sir::Expression::I32Literal)
+        .parse(input)
+}
+
+fn i64_literal(input: Span) -> IResult<Span, sir::Expression> {
     terminated(nom::character::complete::i64, keyword("i64"))
         .map(|val| sir::Expression::I64Literal(val))
         .parse(input)
 }
 
-fn expression(input: &str) -> IResult<&str, sir::Expression> {
-    add_expression.parse(input)
+/// Binary operators, from the loosest binding to the tightest. Operators on
+/// the same level associate to the left.
+///
+/// | Operators         | Meaning                                     |
+/// |-------------------|---------------------------------------------|
+/// | `\|`              | bitwise or                                  |
+/// | `^`               | bitwise exclusive or                        |
+/// | `&`               | bitwise and                                 |
+/// | `<<` `>>` `>>>`   | shift left, arithmetic and logical right    |
+/// | `+` `-`           | add, subtract                               |
+/// | `*` `/`           | multiply, divide                            |
+///
+/// Prefix `-`, `!` and `~` bind tighter than any of these, and calls and
+/// member accesses tighter still. `+`, `-` and `*` can be followed by `%` to
+/// wrap on overflow, or by `|` to saturate, whatever `--overflow` says.
+const BINARY_OPERATORS: &[&[(&str, sir::BinaryOperation)]] = &[
+    &[("|", sir::BinaryOperation::BitOr)],
+    &[("^", sir::BinaryOperation::BitXor)],
+    &[("&", sir::BinaryOperation::BitAnd)],
+    &[
+        ("<<", sir::BinaryOperation::ShiftLeft),
+        (">>>", sir::BinaryOperation::ShiftRightLogical),
+        (">>", sir::BinaryOperation::ShiftRight),
+    ],
+    &[
+        ("+%", sir::BinaryOperation::AddWrapping),
+        ("+|", sir::BinaryOperation::AddSaturating),
+        ("+", sir::BinaryOperation::Add),
+        ("-%", sir::BinaryOperation::SubtractWrapping),
+        ("-|", sir::BinaryOperation::SubtractSaturating),
+        ("-", sir::BinaryOperation::Subtract),
+    ],
+    &[
+        ("*%", sir::BinaryOperation::MultiplyWrapping),
+        ("*|", sir::BinaryOperation::MultiplySaturating),
+        ("*", sir::BinaryOperation::Multiply),
+        ("/", sir::BinaryOperation::Divide),
+    ],
+];
+
+pub fn expression(input: Span) -> IResult<Span, sir::Expression> {
+    binary_expression(0, input)
 }
 
 fn binary_operation<I: Clone, O: Clone + 'static, E: ParseError<I>, R: Parser<I, O, E>>(
@@ -132,24 +519,68 @@ fn binary_operation_step<'r, I: Clone, O: Clone + 'static, E: ParseError<I>, R:
     }
 }
 
-fn add_expression(input: &str) -> IResult<&str, sir::Expression> {
-    binary_operation(call_or_member_access, |left| {
-        preceded(keyword("+"), call_or_member_access).map(move |right| {
+/// An expression whose operators are all on the given level of
+/// `BINARY_OPERATORS` or tighter.
+fn binary_expression(level: usize, input: Span) -> IResult<Span, sir::Expression> {
+    let Some(operators) = BINARY_OPERATORS.get(level) else {
+        return unary_expression(input);
+    };
+    let operand = |input| binary_expression(level + 1, input);
+    binary_operation(operand, move |left| {
+        tuple((location, binary_operator(operators), operand)).map(move |(location, operation, right)| {
             sir::Expression::BinaryOperation {
-                operation: sir::BinaryOperation::Add,
+                operation,
                 left: Box::new(left.clone()),
                 right: Box::new(right),
+                location,
             }
         })
     })
     .parse(input)
 }
 
-fn call_or_member_access(input: &str) -> IResult<&str, sir::Expression> {
+/// The first of `operators` that comes next. Operators that are prefixes of
+/// others must come after them.
+fn binary_operator<'a>(
+    operators: &'static [(&'static str, sir::BinaryOperation)],
+) -> impl FnMut(Span<'a>) -> IResult<Span<'a>, sir::BinaryOperation> {
+    move |input| {
+        // Doc comments are left for the item that follows them, so `///`
+        // here starts one rather than a division.
+        if is_doc_comment(input.fragment()) {
+            return Err(nom::Err::Error(Error::new(input, ErrorKind::Tag)));
+        }
+        for (operator, operation) in operators {
+            if let Ok((rest, _)) = keyword(operator).parse(input) {
+                return Ok((rest, operation.clone()));
+            }
+        }
+        Err(nom::Err::Error(Error::new(input, ErrorKind::Tag)))
+    }
+}
+
+fn unary_expression(input: Span) -> IResult<Span, sir::Expression> {
+    let operator = keyword("-")
+        .map(|_| sir::UnaryOperation::Negate)
+        .or(keyword("!").map(|_| sir::UnaryOperation::Not))
+        .or(keyword("~").map(|_| sir::UnaryOperation::Complement));
+    let unary = tuple((location, operator, unary_expression)).map(|(location, operation, operand)| {
+        sir::Expression::UnaryOperation {
+            operation,
+            operand: Box::new(operand),
+            location,
+        }
+    });
+    // Negative literals are tried first, so that `-1i64` is a literal
+    // rather than a negation.
+    call_or_member_access.or(unary).parse(input)
+}
+
+fn call_or_member_access(input: Span) -> IResult<Span, sir::Expression> {
     binary_operation(atom, |left| call(left.clone()).or(member_access(left))).parse(input)
 }
 
-fn member_access(left: sir::Expression) -> impl FnMut(&str) -> IResult<&str, sir::Expression> {
+fn member_access(left: sir::Expression) -> impl FnMut(Span) -> IResult<Span, sir::Expression> {
     move |input| {
         preceded(keyword("."), identifier)
             .map(|member| sir::Expression::MemberAccess {
@@ -160,28 +591,97 @@ fn member_access(left: sir::Expression) -> impl FnMut(&str) -> IResult<&str, sir
     }
 }
 
-fn call(function: sir::Expression) -> impl FnMut(&str) -> IResult<&str, sir::Expression> {
+/// `function(arguments)`. An argument written `_` is a hole, which makes the
+/// call a partial application awaiting it.
+fn call(function: sir::Expression) -> impl FnMut(Span) -> IResult<Span, sir::Expression> {
     move |input| {
-        let arguments = separated_list1(keyword(","), expression);
-        delimited(keyword("("), arguments, keyword(")"))
-            .map(|arguments| sir::Expression::Call {
-                function: Box::new(function.clone()),
-                arguments,
+        let argument = reserved_word("_").map(|_| None).or(expression.map(Some));
+        let arguments = separated_list0(keyword(","), argument);
+        location
+            .and(delimited(keyword("("), arguments, keyword(")")))
+            .map(|(location, arguments)| {
+                let function = Box::new(function.clone());
+                if arguments.iter().any(Option::is_none) {
+                    sir::Expression::PartialApplication {
+                        function,
+                        arguments,
+                        location,
+                    }
+                } else {
+                    sir::Expression::Call {
+                        function,
+                        arguments: arguments.into_iter().flatten().collect(),
+                        location,
+                    }
+                }
             })
             .parse(input)
     }
 }
 
-fn atom(input: &str) -> IResult<&str, sir::Expression> {
+fn atom(input: Span) -> IResult<Span, sir::Expression> {
     tuple_val
         .or(parens)
         .or(block)
+        .or(builtin)
         .or(reference)
         .or(i64_literal)
+        .or(i32_literal)
         .parse(input)
 }
 
-fn tuple_val(input: &str) -> IResult<&str, sir::Expression> {
+/// `assert(condition)`, `box(value)`, `null<T>`, `panic("message")`,
+/// `print(value)` or `unbox(value)`.
+fn builtin(input: Span) -> IResult<Span, sir::Expression> {
+    let argument = || delimited(keyword("("), expression, keyword(")"));
+    let boxed = tuple((location, reserved_word("box"), argument())).map(|(location, _, value)| {
+        sir::Expression::Boxed {
+            value: Box::new(value),
+            location,
+        }
+    });
+    let unbox = tuple((location, reserved_word("unbox"), argument())).map(|(location, _, value)| {
+        sir::Expression::Unbox {
+            value: Box::new(value),
+            location,
+        }
+    });
+    let null = preceded(reserved_word("null"), box_target).map(|box_type| sir::Expression::Null {
+        data_type: DataType::Primitive(sir::PrimitiveDataType::Box(box_type)),
+    });
+    let assert = tuple((location, reserved_word("assert"), argument()))
+        .map(|(location, _, condition)| sir::Expression::Assert {
+            condition: Box::new(condition),
+            location,
+        });
+    let panic = tuple((location, reserved_word("panic"), delimited(keyword("("), string_literal, keyword(")"))))
+        .map(|(location, _, message)| sir::Expression::Panic {
+            message,
+            data_type: DataType::Primitive(sir::PrimitiveDataType::I64),
+            location,
+        });
+    let print = tuple((location, reserved_word("print"), argument())).map(|(location, _, value)| {
+        sir::Expression::Print {
+            value: Box::new(value),
+            location,
+        }
+    });
+    assert.or(boxed).or(null).or(panic).or(print).or(unbox).parse(input)
+}
+
+/// A double-quoted string, in which `\"`, `\\` and `\n` are escapes.
+fn string_literal(input: Span) -> IResult<Span, String> {
+    let escape = preceded(
+        char('\\'),
+        char('"').or(char('\\')).or(char('n').map(|_| '\n')),
+    );
+    let contents = many0(escape.or(satisfy(|c| c != '"' && c != '\\' && c != '\n')));
+    ws_terminated(delimited(char('"'), contents, char('"')))
+        .map(|chars| chars.into_iter().collect())
+        .parse(input)
+}
+
+fn tuple_val(input: Span) -> IResult<Span, sir::Expression> {
     let first = terminated(expression, keyword(","));
     let rest = separated_list0(keyword(","), expression);
     let contents = first.and(rest).map(|(first, rest)| {
@@ -192,26 +692,30 @@ fn tuple_val(input: &str) -> IResult<&str, sir::Expression> {
     delimited(keyword("("), contents, keyword(")")).parse(input)
 }
 
-fn parens(input: &str) -> IResult<&str, sir::Expression> {
+fn parens(input: Span) -> IResult<Span, sir::Expression> {
     delimited(keyword("("), expression, keyword(")")).parse(input)
 }
 
-fn reference(input: &str) -> IResult<&str, sir::Expression> {
-    identifier
-        .map(|name| sir::Expression::Reference { name })
+fn reference(input: Span) -> IResult<Span, sir::Expression> {
+    location
+        .and(identifier)
+        .map(|(location, name)| sir::Expression::Reference { name, location })
         .parse(input)
 }
 
-fn block(input: &str) -> IResult<&str, sir::Expression> {
+/// `{ name = value; ... body }`. Where each binding was written is
+/// recorded in the `Source`.
+fn block(input: Span) -> IResult<Span, sir::Expression> {
     let scope = terminated(
         tuple((
+            offset,
             identifier,
             opt(argument_list),
             preceded(keyword("="), expression),
         )),
         keyword(";"),
     )
-    .map(|(name, arguments, body)| match arguments {
+    .map(|(offset, name, arguments, body)| match arguments {
         Some(a) => (
             todo!()
             // name.to_string(),
@@ -220,18 +724,24 @@ fn block(input: &str) -> IResult<&str, sir::Expression> {
             //     body: Box::new(body),
             // },
         ),
-        None => (name.to_string(), body),
+        None => (offset, name.to_string(), body),
     });
 
-    let contents = many0(scope).and(expression).map(|(scopes, body)| {
-        scopes
-            .into_iter()
-            .rev()
-            .fold(body, |b, s| sir::Expression::Scope {
-                name: s.0,
-                value: Box::new(s.1),
-                body: Box::new(b),
-            })
+    let contents = tuple((many0(scope), offset, expression, offset));
+    let (rest, (scopes, body_offset, body, end)) = delimited(keyword("{"), contents, keyword("}")).parse(input)?;
+    let mut bindings = input.extra.bindings.borrow_mut();
+    for (offset, _, _) in &scopes {
+        let binding = Binding {
+            offset: *offset,
+            body: body_offset,
+            end,
+        };
+        bindings.insert(*offset, binding);
+    }
+    let block = scopes.into_iter().rev().fold(body, |b, s| sir::Expression::Scope {
+        name: s.1,
+        value: Box::new(s.2),
+        body: Box::new(b),
     });
-    delimited(keyword("{"), contents, keyword("}")).parse(input)
+    Ok((rest, block))
 }
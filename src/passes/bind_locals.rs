@@ -0,0 +1,102 @@
+use anyhow::{bail, Result};
+
+use crate::{
+    sir,
+    visit::{self, Bindings, VisitorMut, Walk},
+};
+
+use super::Pass;
+
+pub struct BindLocals;
+
+impl Pass for BindLocals {
+    fn name(&self) -> &'static str {
+        "bind_locals"
+    }
+
+    /// A local's type is the type of its value, so everything else in that
+    /// value must already be resolved.
+    fn prerequisites(&self) -> &'static [&'static str] {
+        &["build_global_references"]
+    }
+
+    fn run(&self, module: &mut sir::Module) -> Result<()> {
+        bind_locals(module)
+    }
+
+    fn run_expression(
+        &self,
+        expression: &mut sir::Expression,
+        _module: &sir::Module,
+    ) -> Result<()> {
+        bind_expression_locals(expression)
+    }
+}
+
+/// Turns each reference to a name bound by a `Scope` into a `Local`. The
+/// scopes themselves are kept, so that each value is computed once, when its
+/// scope is entered, rather than at every use. Any reference that is still
+/// unresolved after this names nothing, and is an error. Panics used as
+/// operands or arguments are given their types here too, as each value is
+/// bound, since that is when the types around them become known.
+pub fn bind_locals(module: &mut sir::Module) -> Result<()> {
+    for global in module.globals.values_mut() {
+        let mut bindings = Bindings::for_global(global);
+        bind(&mut global.body, &mut bindings)?;
+    }
+    Ok(())
+}
+
+pub fn bind_expression_locals(expression: &mut sir::Expression) -> Result<()> {
+    bind(expression, &mut Bindings::default())
+}
+
+fn bind(expression: &mut sir::Expression, bindings: &mut Bindings) -> Result<()> {
+    let mut binder = LocalBinder {
+        local_types: Vec::new(),
+    };
+    visit::walk_expression_mut(&mut binder, expression, bindings)?;
+    Ok(())
+}
+
+struct LocalBinder {
+    /// The types of the enclosing scopes' values, outermost first, as
+    /// `Local`s are numbered.
+    local_types: Vec<sir::DataType>,
+}
+
+impl VisitorMut for LocalBinder {
+    fn enter_scope(
+        &mut self,
+        _name: &str,
+        value: &mut sir::Expression,
+        _bindings: &Bindings,
+    ) -> Result<()> {
+        self.local_types.push(value.data_type().into_owned());
+        Ok(())
+    }
+
+    fn post_expression(
+        &mut self,
+        expression: &mut sir::Expression,
+        bindings: &Bindings,
+    ) -> Result<Walk> {
+        match expression {
+            sir::Expression::Reference { name, location } => {
+                let Some(index) = bindings.scope_index(name) else {
+                    bail!("{}: unknown name {}", location, name)
+                };
+                *expression = sir::Expression::Local {
+                    index,
+                    data_type: self.local_types[index as usize].clone(),
+                };
+            }
+            sir::Expression::Scope { .. } => {
+                self.local_types.pop();
+            }
+            _ => {}
+        }
+        expression.expect_operand_types();
+        Ok(Walk::Continue)
+    }
+}
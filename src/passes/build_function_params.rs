@@ -1,24 +1,42 @@
-use crate::sir;
+use anyhow::Result;
 
+use crate::{
+    sir,
+    visit::{self, Binding, Bindings},
+};
+
+use super::Pass;
+
+pub struct BuildFunctionParams;
+
+impl Pass for BuildFunctionParams {
+    fn name(&self) -> &'static str {
+        "build_function_params"
+    }
+
+    fn run(&self, module: &mut sir::Module) -> Result<()> {
+        build_function_params(module);
+        Ok(())
+    }
+}
+
+/// Resolves references to the parameters of each global, except where a
+/// scope binds the same name.
 pub fn build_function_params(module: &mut sir::Module) {
     for global in module.globals.values_mut() {
-        super::transform_expression(&mut global.body, &|expression| match expression {
-            sir::Expression::Reference { name } => {
-                let target = global
-                    .arguments
-                    .iter()
-                    .enumerate()
-                    .filter(|(_, (argument_name, _))| argument_name == name)
-                    .map(|(index, (_, data_type))| sir::Expression::FunctionParam {
-                        index: index as u32,
-                        data_type: data_type.clone(),
-                    })
-                    .next();
-                if let Some(argument) = target {
-                    *expression = argument
+        let mut bindings = Bindings::for_global(global);
+        let arguments = &global.arguments;
+        visit::try_for_each_mut(&mut global.body, &mut bindings, |expression, bindings| {
+            if let sir::Expression::Reference { name, .. } = expression {
+                if let Some(Binding::Param(index)) = bindings.lookup(name) {
+                    *expression = sir::Expression::FunctionParam {
+                        index: *index,
+                        data_type: arguments[*index as usize].1.clone(),
+                    };
                 }
             }
-            _ => {}
-        });
+            Ok(())
+        })
+        .unwrap();
     }
 }
@@ -1,41 +1,83 @@
 use std::collections::HashMap;
 
-use crate::sir;
+use anyhow::{bail, Result};
 
-pub fn build_global_references(module: &mut sir::Module) {
-    let global_types: HashMap<_, _> = module
-        .globals
-        .iter()
-        .map(|(name, global)| (name.clone(), global_type(global)))
-        .collect();
+use crate::{
+    sir,
+    visit::{self, Bindings},
+};
+
+use super::Pass;
+
+pub struct BuildGlobalReferences;
+
+impl Pass for BuildGlobalReferences {
+    fn name(&self) -> &'static str {
+        "build_global_references"
+    }
+
+    /// Parameters shadow globals, so they must be resolved first.
+    fn prerequisites(&self) -> &'static [&'static str] {
+        &["build_function_params"]
+    }
+
+    fn run(&self, module: &mut sir::Module) -> Result<()> {
+        build_global_references(module)
+    }
+
+    fn run_expression(&self, expression: &mut sir::Expression, module: &sir::Module) -> Result<()> {
+        resolve_global_references(expression, &global_types(module)?);
+        Ok(())
+    }
+}
+
+pub fn build_global_references(module: &mut sir::Module) -> Result<()> {
+    let global_types = global_types(module)?;
 
     for global in module.globals.values_mut() {
-        super::transform_expression(&mut global.body, &|expression| match expression {
-            sir::Expression::Reference { name } => {
-                if let Some(data_type) = global_types.get(&*name) {
-                    *expression = sir::Expression::GlobalReference {
-                        name: name.clone(),
-                        data_type: data_type.clone(),
-                    };
-                }
-            }
-            _ => {}
-        });
+        resolve_global_references(&mut global.body, &global_types);
     }
+    Ok(())
 }
 
-fn global_type(global: &sir::Global) -> sir::DataType {
-    if global.arguments.is_empty() {
-        global.return_type.clone()
-    } else {
-        let argument_types = global
-            .arguments
-            .iter()
-            .map(|(_, argument_type)| argument_type.clone())
-            .collect();
-        sir::DataType::Primitive(sir::PrimitiveDataType::Function {
-            argument_types,
-            return_type: Box::new(global.return_type.clone()),
-        })
+/// The types of every global and extern, which must not share names.
+pub fn global_types(module: &sir::Module) -> Result<HashMap<String, sir::DataType>> {
+    let mut duplicates: Vec<_> = module
+        .globals
+        .iter()
+        .filter(|(name, _)| module.externs.contains_key(*name))
+        .collect();
+    duplicates.sort_by_key(|(name, _)| name.as_str());
+    if let Some((name, global)) = duplicates.first() {
+        bail!("{}: {} is already declared as an extern", global.location, name);
     }
+
+    let globals = module
+        .globals
+        .iter()
+        .map(|(name, global)| (name.clone(), global.data_type()));
+    let externs = module
+        .externs
+        .iter()
+        .map(|(name, declaration)| (name.clone(), declaration.data_type()));
+    Ok(globals.chain(externs).collect())
+}
+
+/// Resolves references to globals, except where a scope binds the same name.
+pub fn resolve_global_references(
+    expression: &mut sir::Expression,
+    global_types: &HashMap<String, sir::DataType>,
+) {
+    visit::try_for_each_mut(expression, &mut Bindings::default(), |expression, bindings| {
+        if let sir::Expression::Reference { name, .. } = expression {
+            if let (false, Some(data_type)) = (bindings.is_bound(name), global_types.get(&*name)) {
+                *expression = sir::Expression::GlobalReference {
+                    name: name.clone(),
+                    data_type: data_type.clone(),
+                };
+            }
+        }
+        Ok(())
+    })
+    .unwrap();
 }
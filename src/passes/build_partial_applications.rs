@@ -0,0 +1,77 @@
+use anyhow::Result;
+
+use crate::sir;
+
+use super::{transform_expression, transform_module, Pass};
+
+pub struct BuildPartialApplications;
+
+impl Pass for BuildPartialApplications {
+    fn name(&self) -> &'static str {
+        "build_partial_applications"
+    }
+
+    /// Calls can only be counted short once the types of the functions they
+    /// call are known.
+    fn prerequisites(&self) -> &'static [&'static str] {
+        &["bind_locals"]
+    }
+
+    fn run(&self, module: &mut sir::Module) -> Result<()> {
+        build_partial_applications(module);
+        Ok(())
+    }
+
+    fn run_expression(
+        &self,
+        expression: &mut sir::Expression,
+        _module: &sir::Module,
+    ) -> Result<()> {
+        build_expression_partial_applications(expression);
+        Ok(())
+    }
+}
+
+/// Turns each call given fewer arguments than its function takes into a
+/// partial application awaiting the rest.
+pub fn build_partial_applications(module: &mut sir::Module) {
+    transform_module(module, build_partial_application);
+}
+
+pub fn build_expression_partial_applications(expression: &mut sir::Expression) {
+    transform_expression(expression, build_partial_application);
+}
+
+fn build_partial_application(expression: &mut sir::Expression) {
+    let sir::Expression::Call {
+        function,
+        arguments,
+        ..
+    } = expression
+    else {
+        return;
+    };
+    let function_type = function.data_type();
+    let sir::DataType::Primitive(sir::PrimitiveDataType::Function { argument_types, .. }) =
+        function_type.as_ref()
+    else {
+        return;
+    };
+    if arguments.len() >= argument_types.len() {
+        return;
+    }
+
+    let sir::Expression::Call {
+        function,
+        arguments,
+        location,
+    } = std::mem::replace(expression, sir::Expression::Tuple { values: Vec::new() })
+    else {
+        unreachable!()
+    };
+    *expression = sir::Expression::PartialApplication {
+        function,
+        arguments: arguments.into_iter().map(Some).collect(),
+        location,
+    };
+}
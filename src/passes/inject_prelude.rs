@@ -0,0 +1,130 @@
+use std::collections::HashSet;
+
+use anyhow::{Context, Result};
+
+use crate::{
+    parser::{parse, source_file},
+    sir,
+    visit::{self, Bindings},
+};
+
+use super::{qualify_names::qualify_names, Pass, SourceModule};
+
+/// The source of the prelude, which is compiled into the compiler.
+const PRELUDE: &str = include_str!("../prelude.scrap");
+
+/// The module path under which the prelude's globals are merged.
+pub const PRELUDE_MODULE: &str = "prelude";
+
+pub struct InjectPrelude;
+
+impl Pass for InjectPrelude {
+    fn name(&self) -> &'static str {
+        "inject_prelude"
+    }
+
+    /// Each file is pointed at the prelude before the files are merged, so
+    /// that shadowing a prelude global only affects the file that does it.
+    fn run_source(&self, source: &mut SourceModule) -> Result<()> {
+        use_source_prelude(source.module)
+    }
+
+    fn run(&self, module: &mut sir::Module) -> Result<()> {
+        inject_prelude(module)
+    }
+
+    fn run_expression(&self, expression: &mut sir::Expression, module: &sir::Module) -> Result<()> {
+        use_expression_prelude(expression, module);
+        Ok(())
+    }
+}
+
+/// Parses the prelude into a module of its own, with its globals qualified
+/// as `prelude.min` and so on.
+pub fn prelude() -> Result<sir::Module> {
+    let file = "<prelude>".into();
+    let (imports, mut module) = parse(PRELUDE, &file, source_file).context("Parsing the prelude")?;
+    qualify_names(&mut module, &qualify(""), &imports);
+    Ok(module)
+}
+
+/// Merges the prelude into a module, and makes the module's references to
+/// names it doesn't define itself refer to the prelude's globals of the same
+/// name. A global the module does define shadows the prelude's, as does a
+/// parameter or local. The loader's files have already been through
+/// `use_source_prelude`, so this only resolves what they left, such as the
+/// REPL's globals. Must run before `build_function_params`, so that the
+/// prelude's own parameters are resolved along with everything else.
+pub fn inject_prelude(module: &mut sir::Module) -> Result<()> {
+    for (name, global) in prelude()?.globals {
+        module.globals.entry(name).or_insert(global);
+    }
+    use_prelude(module);
+    Ok(())
+}
+
+/// Points the unresolved references in a module's globals at the prelude,
+/// which must already have been merged into it.
+pub fn use_prelude(module: &mut sir::Module) {
+    let defined = defined_names(module);
+    use_prelude_in(module, &defined);
+}
+
+/// Points the unresolved references in a file's globals at the prelude,
+/// before the file is merged into the program. Only the file's own globals
+/// and externs shadow the prelude's, so that a `less` defined in one file
+/// doesn't change what `less` means in another.
+pub fn use_source_prelude(module: &mut sir::Module) -> Result<()> {
+    let mut defined = defined_names(module);
+    defined.extend(prelude()?.globals.into_keys());
+    use_prelude_in(module, &defined);
+    Ok(())
+}
+
+fn use_prelude_in(module: &mut sir::Module, defined: &HashSet<String>) {
+    for global in module.globals.values_mut() {
+        let mut bindings = Bindings::for_global(global);
+        use_expression_prelude_in(&mut global.body, &mut bindings, defined);
+    }
+}
+
+/// Like `use_prelude`, but for an expression outside of any global.
+pub fn use_expression_prelude(expression: &mut sir::Expression, module: &sir::Module) {
+    use_expression_prelude_in(expression, &mut Bindings::default(), &defined_names(module));
+}
+
+fn defined_names(module: &sir::Module) -> HashSet<String> {
+    module.globals.keys().chain(module.externs.keys()).cloned().collect()
+}
+
+fn use_expression_prelude_in(expression: &mut sir::Expression, bindings: &mut Bindings, defined: &HashSet<String>) {
+    let is_prelude_global = |name: &str, bindings: &Bindings| {
+        !bindings.is_bound(name) && !defined.contains(name) && defined.contains(&qualify(name))
+    };
+    visit::try_for_each_mut(expression, bindings, |expression, bindings| {
+        match expression {
+            sir::Expression::Reference { name, .. } if is_prelude_global(name, bindings) => {
+                *name = qualify(name);
+            }
+            // A program that shadows a prelude global can still name it in
+            // full.
+            sir::Expression::MemberAccess { left, member } => {
+                if let sir::Expression::Reference { name, location } = left.as_ref() {
+                    if name == PRELUDE_MODULE && !bindings.is_bound(name) && defined.contains(&qualify(member)) {
+                        *expression = sir::Expression::Reference {
+                            name: qualify(member),
+                            location: location.clone(),
+                        };
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    })
+    .unwrap();
+}
+
+fn qualify(name: &str) -> String {
+    format!("{}.{}", PRELUDE_MODULE, name)
+}
@@ -1,46 +1,195 @@
-use crate::sir;
+use std::collections::HashSet;
 
+use anyhow::{bail, Result};
+
+use crate::{
+    options::Options,
+    printer::print_module,
+    sir,
+    visit::{self, Bindings},
+};
+
+pub mod bind_locals;
 pub mod build_function_params;
 pub mod build_global_references;
-pub mod remove_scopes;
+pub mod build_partial_applications;
+pub mod inject_prelude;
+pub mod qualify_names;
 
-pub fn transform_module(module: &mut sir::Module, f: &impl Fn(&mut sir::Expression)) {
-    for global in module.globals.values_mut() {
-        transform_expression(&mut global.body, f);
+/// A transformation over a whole program, run by a `Registry` once the
+/// program has been loaded.
+pub trait Pass {
+    /// The name used to refer to this pass in `--dump-after`, `--skip` and
+    /// `--print-passes`.
+    fn name(&self) -> &'static str;
+
+    /// The names of the passes that must have run before this one.
+    fn prerequisites(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Transforms each source file as the loader reads it, before its
+    /// globals are merged into the program. Only passes that need to know
+    /// which file a global came from do anything here.
+    fn run_source(&self, _source: &mut SourceModule) -> Result<()> {
+        Ok(())
     }
+
+    fn run(&self, module: &mut sir::Module) -> Result<()>;
+
+    /// Transforms an expression outside of any global, such as one typed
+    /// into the REPL, which refers to the globals of a module that has
+    /// already been through every pass.
+    fn run_expression(&self, _expression: &mut sir::Expression, _module: &sir::Module) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A source file, as the loader has parsed it.
+pub struct SourceModule<'a> {
+    /// The file's module path, such as `shapes.circle`.
+    pub name: &'a str,
+    /// Whether this is the entry module, whose globals keep their plain
+    /// names.
+    pub is_entry: bool,
+    /// The module paths that the file imports.
+    pub imports: &'a [String],
+    pub module: &'a mut sir::Module,
+}
+
+/// The passes that make up the pipeline, in the order in which they run.
+/// `Registry::new` contains the compiler's own passes; programs using scrap
+/// as a library can `register` more of their own.
+pub struct Registry {
+    passes: Vec<Box<dyn Pass>>,
 }
 
-pub fn transform_expression(expression: &mut sir::Expression, f: &impl Fn(&mut sir::Expression)) {
-    match expression {
-        sir::Expression::BinaryOperation { left, right, .. } => {
-            transform_expression(left, f);
-            transform_expression(right, f);
+impl Registry {
+    pub fn new() -> Self {
+        Self {
+            passes: vec![
+                Box::new(qualify_names::QualifyNames),
+                Box::new(inject_prelude::InjectPrelude),
+                Box::new(build_function_params::BuildFunctionParams),
+                Box::new(build_global_references::BuildGlobalReferences),
+                Box::new(bind_locals::BindLocals),
+                Box::new(build_partial_applications::BuildPartialApplications),
+            ],
+        }
+    }
+
+    /// Adds a pass to the end of the pipeline. Its prerequisites must already
+    /// have been registered.
+    pub fn register(&mut self, pass: Box<dyn Pass>) -> Result<()> {
+        if self.get(pass.name()).is_some() {
+            bail!("A pass named {} is already registered", pass.name());
         }
-        sir::Expression::Call {
-            function,
-            arguments,
-        } => {
-            transform_expression(function, f);
-            for argument in arguments {
-                transform_expression(argument, f);
+        for prerequisite in pass.prerequisites() {
+            if self.get(prerequisite).is_none() {
+                bail!("Pass {} requires unknown pass {}", pass.name(), prerequisite);
             }
         }
-        sir::Expression::I64Literal(_) => {}
-        sir::Expression::MemberAccess { left, .. } => {
-            transform_expression(left, f);
+        self.passes.push(pass);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn Pass> {
+        self.passes
+            .iter()
+            .find(|pass| pass.name() == name)
+            .map(|pass| pass.as_ref())
+    }
+
+    pub fn passes(&self) -> impl Iterator<Item = &dyn Pass> {
+        self.passes.iter().map(|pass| pass.as_ref())
+    }
+
+    /// Runs every pass not named by `--skip` on a source file, dumping the
+    /// SIR to stderr after each one named by `--dump-after`.
+    pub fn run_source(&self, source: &mut SourceModule, options: &Options) -> Result<()> {
+        self.check_names(options)?;
+        for pass in self.passes() {
+            if options.skip.iter().any(|name| name == pass.name()) {
+                continue;
+            }
+
+            pass.run_source(source)?;
+
+            if options.dump_after.iter().any(|name| name == pass.name()) {
+                eprintln!("// SIR of {} after {}", source.name, pass.name());
+                eprint!("{}", print_module(source.module));
+            }
         }
-        sir::Expression::Reference { .. } => {}
-        sir::Expression::Scope { value, body, .. } => {
-            transform_expression(value, f);
-            transform_expression(body, f);
+        Ok(())
+    }
+
+    /// Runs every pass not named by `--skip`, dumping the SIR to stderr
+    /// after each one named by `--dump-after`.
+    pub fn run(&self, module: &mut sir::Module, options: &Options) -> Result<()> {
+        self.check_names(options)?;
+
+        let mut ran = HashSet::new();
+        for pass in self.passes() {
+            if options.skip.iter().any(|name| name == pass.name()) {
+                continue;
+            }
+            for prerequisite in pass.prerequisites() {
+                if !ran.contains(prerequisite) {
+                    bail!(
+                        "Pass {} requires {}, which was skipped",
+                        pass.name(),
+                        prerequisite
+                    );
+                }
+            }
+
+            pass.run(module)?;
+            ran.insert(pass.name());
+
+            if options.dump_after.iter().any(|name| name == pass.name()) {
+                eprintln!("// SIR after {}", pass.name());
+                eprint!("{}", print_module(module));
+            }
         }
-        sir::Expression::Tuple { values } => {
-            for value in values {
-                transform_expression(value, f);
+        Ok(())
+    }
+
+    /// Runs every pass on an expression (see `Pass::run_expression`).
+    pub fn run_expression(&self, expression: &mut sir::Expression, module: &sir::Module) -> Result<()> {
+        for pass in self.passes() {
+            pass.run_expression(expression, module)?;
+        }
+        Ok(())
+    }
+
+    fn check_names(&self, options: &Options) -> Result<()> {
+        for name in options.skip.iter().chain(options.dump_after.iter()) {
+            if self.get(name).is_none() {
+                bail!("Unknown pass {}; see --print-passes", name);
             }
         }
-        _ => {}
+        Ok(())
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
     }
+}
+
+pub fn transform_module(module: &mut sir::Module, mut f: impl FnMut(&mut sir::Expression)) {
+    for global in module.globals.values_mut() {
+        transform_expression(&mut global.body, &mut f);
+    }
+}
 
-    f(expression);
+/// Calls `f` on every expression in a tree, children first. See `visit` for
+/// traversals that can fail, stop early or track scopes.
+pub fn transform_expression(expression: &mut sir::Expression, mut f: impl FnMut(&mut sir::Expression)) {
+    visit::try_for_each_mut(expression, &mut Bindings::default(), |expression, _| {
+        f(expression);
+        Ok(())
+    })
+    .unwrap()
 }
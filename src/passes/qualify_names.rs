@@ -0,0 +1,162 @@
+use std::{
+    cell::OnceCell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+use anyhow::Result;
+
+use crate::{
+    sir,
+    visit::{self, Bindings},
+};
+
+use super::{Pass, SourceModule};
+
+pub struct QualifyNames;
+
+impl Pass for QualifyNames {
+    fn name(&self) -> &'static str {
+        "qualify_names"
+    }
+
+    fn run_source(&self, source: &mut SourceModule) -> Result<()> {
+        let prefix = if source.is_entry {
+            String::new()
+        } else {
+            format!("{}.", source.name)
+        };
+        qualify_names(source.module, &prefix, source.imports);
+        Ok(())
+    }
+
+    /// By the time the files are merged, every name has been qualified.
+    fn run(&self, _module: &mut sir::Module) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Prefixes the names of a file's globals and types with its module path,
+/// and turns qualified references to imported modules, such as
+/// `geometry.area`, into references to the merged global of that name. Names
+/// bound by parameters or enclosing scopes are left alone.
+pub fn qualify_names(module: &mut sir::Module, prefix: &str, imports: &[String]) {
+    let locals: HashSet<_> = module.globals.keys().cloned().collect();
+    if !prefix.is_empty() {
+        qualify_types(module, prefix);
+    }
+
+    for global in module.globals.values_mut() {
+        let mut bindings = Bindings::for_global(global);
+        visit::try_for_each_mut(&mut global.body, &mut bindings, |expression, bindings| {
+            match expression {
+                sir::Expression::Reference { name, .. } if locals.contains(name) && !bindings.is_bound(name) => {
+                    *name = format!("{}{}", prefix, name);
+                }
+                sir::Expression::MemberAccess { left, member } => {
+                    if let sir::Expression::Reference { name, location } = left.as_ref() {
+                        let path = format!("{}.{}", name, member);
+                        let is_import = is_module_path(name, imports) || is_module_path_prefix(&path, imports);
+                        if is_import && !bindings.is_bound(name) {
+                            *expression = sir::Expression::Reference {
+                                name: path,
+                                location: location.clone(),
+                            };
+                        }
+                    }
+                }
+                _ => {}
+            }
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    module.globals = module
+        .globals
+        .drain()
+        .map(|(name, global)| (format!("{}{}", prefix, name), global))
+        .collect();
+}
+
+/// Renames the types that a file declares, which are only in scope in that
+/// file, so that two files' `Node`s stay different types once they are
+/// merged.
+fn qualify_types(module: &mut sir::Module, prefix: &str) {
+    let mut types = TypeQualifier {
+        prefix,
+        definitions: HashMap::new(),
+    };
+    for global in module.globals.values_mut() {
+        for (_, data_type) in global.arguments.iter_mut() {
+            *data_type = types.qualify(data_type);
+        }
+        global.return_type = types.qualify(&global.return_type);
+        visit::try_for_each_mut(&mut global.body, &mut Bindings::default(), |expression, _| {
+            if let sir::Expression::Null { data_type } | sir::Expression::Panic { data_type, .. } = expression {
+                *data_type = types.qualify(data_type);
+            }
+            Ok(())
+        })
+        .unwrap();
+    }
+    for declaration in module.externs.values_mut() {
+        for (_, data_type) in declaration.arguments.iter_mut() {
+            *data_type = types.qualify(data_type);
+        }
+        declaration.return_type = types.qualify(&declaration.return_type);
+    }
+}
+
+struct TypeQualifier<'a> {
+    prefix: &'a str,
+    /// The renamed definition of each type, by its old name. Each is shared
+    /// by every box of that type, its own included.
+    definitions: HashMap<String, Rc<OnceCell<sir::DataType>>>,
+}
+
+impl TypeQualifier<'_> {
+    fn qualify(&mut self, data_type: &sir::DataType) -> sir::DataType {
+        match data_type {
+            sir::DataType::Primitive(sir::PrimitiveDataType::Box(box_type)) => {
+                sir::DataType::Primitive(sir::PrimitiveDataType::Box(self.qualify_box(box_type)))
+            }
+            sir::DataType::Primitive(sir::PrimitiveDataType::Function {
+                argument_types,
+                return_type,
+            }) => sir::DataType::Primitive(sir::PrimitiveDataType::Function {
+                argument_types: argument_types.iter().map(|t| self.qualify(t)).collect(),
+                return_type: Box::new(self.qualify(return_type)),
+            }),
+            sir::DataType::Primitive(t) => sir::DataType::Primitive(t.clone()),
+            sir::DataType::Tuple(elements) => sir::DataType::Tuple(elements.iter().map(|t| self.qualify(t)).collect()),
+        }
+    }
+
+    fn qualify_box(&mut self, box_type: &sir::BoxType) -> sir::BoxType {
+        let Some(name) = &box_type.name else {
+            return sir::BoxType::new(self.qualify(box_type.target()));
+        };
+        let qualified = format!("{}{}", self.prefix, name);
+        if let Some(definition) = self.definitions.get(name) {
+            return sir::BoxType::named(qualified, definition.clone());
+        }
+        // The definition is registered before it is filled in, so that the
+        // boxes in it that refer back to the type find it.
+        let definition = Rc::new(OnceCell::new());
+        self.definitions.insert(name.clone(), definition.clone());
+        let target = self.qualify(box_type.target());
+        definition.set(target).unwrap();
+        sir::BoxType::named(qualified, definition)
+    }
+}
+
+fn is_module_path(name: &str, imports: &[String]) -> bool {
+    imports.iter().any(|import| import == name)
+}
+
+fn is_module_path_prefix(path: &str, imports: &[String]) -> bool {
+    imports
+        .iter()
+        .any(|import| import == path || import.starts_with(&format!("{}.", path)))
+}
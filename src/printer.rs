@@ -0,0 +1,704 @@
+use std::{collections::BTreeMap, fmt::Write, iter::Peekable, rc::Rc, slice, vec};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{
+    parser::{self, Item},
+    passes, sir, visit,
+};
+
+const INDENT: &str = "    ";
+
+/// Renders a module: the types declared with `type` that it uses, then
+/// externs and then globals, each sorted by name. Resolved expressions are
+/// printed using the names they were resolved from, so that the result means
+/// the same thing if it is parsed again.
+pub fn print_module(module: &sir::Module) -> String {
+    let mut externs: Vec<_> = module.externs.iter().collect();
+    externs.sort_by_key(|(name, _)| name.as_str());
+    let mut globals: Vec<_> = module.globals.iter().collect();
+    globals.sort_by_key(|(name, _)| name.as_str());
+
+    let items = declared_types(module)
+        .into_iter()
+        .map(|(name, data_type)| format!("type {} = {}", name, data_type))
+        .chain(
+            externs
+                .into_iter()
+                .map(|(name, declaration)| print_extern(name, declaration)),
+        )
+        .chain(globals.into_iter().map(|(name, global)| print_global(name, global)));
+    let mut out = String::new();
+    for (i, item) in items.enumerate() {
+        if i > 0 {
+            writeln!(out).unwrap();
+        }
+        writeln!(out, "{}", item).unwrap();
+    }
+    out
+}
+
+pub fn print_global(name: &str, global: &sir::Global) -> String {
+    let mut printer = Printer::new(None);
+    printer.global(name, global);
+    printer.out
+}
+
+pub fn print_extern(name: &str, declaration: &sir::Extern) -> String {
+    let mut printer = Printer::new(None);
+    printer.extern_declaration(name, declaration);
+    printer.out
+}
+
+/// Renders an expression outside of any global. Function parameters have no
+/// names here, so they are printed by index, which does not parse.
+pub fn print_expression(expression: &sir::Expression) -> String {
+    let mut printer = Printer::new(None);
+    printer.expression(expression, 0);
+    printer.out
+}
+
+/// The types declared with `type` that a module's declarations and
+/// expressions name, directly or through other such types, by name.
+fn declared_types(module: &sir::Module) -> BTreeMap<String, sir::DataType> {
+    let mut types = BTreeMap::new();
+    for declaration in module.externs.values() {
+        for (_, data_type) in &declaration.arguments {
+            collect_types(data_type, &mut types);
+        }
+        collect_types(&declaration.return_type, &mut types);
+    }
+    for global in module.globals.values() {
+        for (_, data_type) in &global.arguments {
+            collect_types(data_type, &mut types);
+        }
+        collect_types(&global.return_type, &mut types);
+        visit::try_for_each(&global.body, &mut Default::default(), |expression, _| {
+            if let sir::Expression::Null { data_type } | sir::Expression::Panic { data_type, .. } = expression {
+                collect_types(data_type, &mut types);
+            }
+            Ok(())
+        })
+        .unwrap();
+    }
+    types
+}
+
+fn collect_types(data_type: &sir::DataType, types: &mut BTreeMap<String, sir::DataType>) {
+    match data_type {
+        sir::DataType::Primitive(sir::PrimitiveDataType::Box(box_type)) => {
+            if let Some(name) = &box_type.name {
+                if types.contains_key(name) {
+                    return;
+                }
+                types.insert(name.clone(), box_type.target().clone());
+            }
+            collect_types(box_type.target(), types);
+        }
+        sir::DataType::Primitive(sir::PrimitiveDataType::Function {
+            argument_types,
+            return_type,
+        }) => {
+            for argument_type in argument_types {
+                collect_types(argument_type, types);
+            }
+            collect_types(return_type, types);
+        }
+        sir::DataType::Primitive(sir::PrimitiveDataType::I32 | sir::PrimitiveDataType::I64) => {}
+        sir::DataType::Tuple(elements) => {
+            for element in elements {
+                collect_types(element, types);
+            }
+        }
+    }
+}
+
+/// Formats a source file in the standard layout: imports first, then each
+/// item separated by a blank line, with each binding in a block on its own
+/// line. Comments between items are kept, attached to the item that follows
+/// them, or to the one before if they share its last line. Comments inside
+/// an item are written between the bindings of its blocks, on a line of
+/// their own or at the end of one as they were written, and any that can't
+/// be go before or after the item. The result is checked to parse back to
+/// the same tree, with the same comments, so that formatting can never
+/// change what a program means.
+pub fn format_source(text: &str, file: &Rc<str>) -> Result<String> {
+    let ((imports, mut items), layout) = parser::parse_with_layout(text, file, parser::source_items)?;
+    let comments = &layout.comments;
+
+    let mut units: Vec<(usize, Unit)> = imports
+        .iter()
+        .map(|(offset, import)| (*offset, Unit::Import(import)))
+        .collect();
+    units.extend(items.iter().map(|(offset, item)| (*offset, Unit::Item(item))));
+
+    let mut out = String::new();
+    let first_offset = units.first().map(|(offset, _)| *offset).unwrap_or(text.len());
+    for comment in comments.iter().take_while(|comment| comment.offset < first_offset) {
+        writeln!(out, "{}", comment.text).unwrap();
+    }
+    if !out.is_empty() && !units.is_empty() {
+        writeln!(out).unwrap();
+    }
+
+    for (i, (start, unit)) in units.iter().enumerate() {
+        let next = units.get(i + 1);
+        let end = next.map(|(offset, _)| *offset).unwrap_or(text.len());
+        let unit_comments: Vec<_> = comments
+            .iter()
+            .filter(|comment| comment.offset >= *start && comment.offset < end)
+            .collect();
+        let code_end = code_end(text, *start, end, &unit_comments);
+        let (inner, trailing): (Vec<_>, Vec<_>) =
+            unit_comments.into_iter().partition(|comment| comment.offset < code_end);
+        let bindings = &layout.bindings[layout.bindings.partition_point(|binding| binding.offset < *start)
+            ..layout.bindings.partition_point(|binding| binding.offset < code_end)];
+
+        let (printed, ends_in_comment) = format_unit(unit, text, inner, bindings);
+        out.push_str(&printed);
+
+        let mut trailing = trailing.into_iter().peekable();
+        if let Some(comment) =
+            trailing.next_if(|comment| !ends_in_comment && !text[code_end..comment.offset].contains('\n'))
+        {
+            write!(out, " {}", comment.text).unwrap();
+        }
+        writeln!(out).unwrap();
+
+        let between_imports = matches!(unit, Unit::Import(_)) && matches!(next, Some((_, Unit::Import(_))));
+        if next.is_some() && !between_imports || trailing.peek().is_some() {
+            writeln!(out).unwrap();
+        }
+        for comment in trailing {
+            writeln!(out, "{}", comment.text).unwrap();
+        }
+    }
+
+    let (mut reparsed, reparsed_layout) = parser::parse_with_layout(&out, file, parser::source_items)
+        .map_err(|e| anyhow!("internal error: formatting produced invalid source: {}", e))?;
+    // Formatting moves everything, so only what the items say is compared.
+    for (_, item) in items.iter_mut().chain(reparsed.1.iter_mut()) {
+        if let Item::Global(_, global) = item {
+            erase_locations(global);
+        }
+    }
+    let unchanged = |(a, b): (&(usize, Item), &(usize, Item))| a.1 == b.1;
+    let same_tree = reparsed.0.iter().map(|(_, import)| import).eq(imports.iter().map(|(_, import)| import))
+        && reparsed.1.len() == items.len()
+        && reparsed.1.iter().zip(items.iter()).all(unchanged);
+    let same_comments = reparsed_layout
+        .comments
+        .iter()
+        .map(|comment| comment.text.as_str())
+        .eq(comments.iter().map(|comment| comment.text.as_str()));
+    if !same_tree || !same_comments {
+        bail!("{}: internal error: formatting changed the meaning of this file", file);
+    }
+    Ok(out)
+}
+
+/// An import or item in a source file.
+enum Unit<'a> {
+    Import(&'a str),
+    Item(&'a Item),
+}
+
+/// Prints an import or item for `format_source`, with the comments written
+/// inside it. Those that come after the last binding's block go on lines of
+/// their own after it, or before it if there were no comments in its blocks.
+/// Returns the unit, and whether it ends in a comment.
+fn format_unit<'a>(
+    unit: &Unit<'a>,
+    text: &'a str,
+    comments: Vec<&'a parser::Comment>,
+    bindings: &'a [parser::Binding],
+) -> (String, bool) {
+    let mut printer = Printer::new(Some(ItemComments {
+        text,
+        comments: comments.into_iter().peekable(),
+        bindings: bindings.iter(),
+        written: false,
+        after_comment: false,
+    }));
+    match unit {
+        Unit::Import(import) => write!(printer.out, "import {}", import).unwrap(),
+        Unit::Item(Item::Global(name, global)) => printer.global(name, global),
+        Unit::Item(Item::Extern(name, declaration)) => printer.extern_declaration(name, declaration),
+        Unit::Item(Item::Type(name, data_type)) => write!(printer.out, "type {} = {}", name, data_type).unwrap(),
+    }
+
+    let layout = printer.layout.take().unwrap();
+    let rest: Vec<_> = layout.comments.collect();
+    if !layout.written {
+        let mut out = String::new();
+        for comment in rest {
+            writeln!(out, "{}", comment.text).unwrap();
+        }
+        out.push_str(&printer.out);
+        return (out, false);
+    }
+    for comment in &rest {
+        write!(printer.out, "\n{}", comment.text).unwrap();
+    }
+    (printer.out, !rest.is_empty())
+}
+
+/// Resets every location in a global, so that globals parsed from
+/// differently formatted source compare equal.
+fn erase_locations(global: &mut sir::Global) {
+    let nowhere = sir::Location {
+        file: global.location.file.clone(),
+        line: 0,
+        column: 0,
+    };
+    global.location = nowhere.clone();
+    passes::transform_expression(&mut global.body, |expression| match expression {
+        sir::Expression::Assert { location, .. }
+        | sir::Expression::BinaryOperation { location, .. }
+        | sir::Expression::Boxed { location, .. }
+        | sir::Expression::Call { location, .. }
+        | sir::Expression::PartialApplication { location, .. }
+        | sir::Expression::Panic { location, .. }
+        | sir::Expression::Print { location, .. }
+        | sir::Expression::Reference { location, .. }
+        | sir::Expression::UnaryOperation { location, .. }
+        | sir::Expression::Unbox { location, .. } => *location = nowhere.clone(),
+        _ => {}
+    });
+}
+
+/// The end of the last piece of code between `start` and `end`, ignoring
+/// whitespace and the given comments.
+fn code_end(text: &str, start: usize, end: usize, comments: &[&parser::Comment]) -> usize {
+    let mut code_end = start;
+    let mut offset = start;
+    while offset < end {
+        if let Some(comment) = comments.iter().find(|comment| comment.offset == offset) {
+            offset = comment.end();
+            continue;
+        }
+        let c = text[offset..].chars().next().unwrap();
+        offset += c.len_utf8();
+        if !c.is_whitespace() {
+            code_end = offset;
+        }
+    }
+    code_end
+}
+
+/// How tightly an expression binds, following `parser::BINARY_OPERATORS`. An
+/// expression printed where a higher precedence is required is wrapped in
+/// parentheses.
+const BIT_OR: u8 = 1;
+const BIT_XOR: u8 = 2;
+const BIT_AND: u8 = 3;
+const SHIFT: u8 = 4;
+const ADDITIVE: u8 = 5;
+const MULTIPLICATIVE: u8 = 6;
+const PREFIX: u8 = 7;
+const POSTFIX: u8 = u8::MAX;
+
+fn precedence(expression: &sir::Expression) -> u8 {
+    match expression {
+        sir::Expression::BinaryOperation { operation, .. } => binary_operator(operation).1,
+        sir::Expression::UnaryOperation { .. } => PREFIX,
+        _ => POSTFIX,
+    }
+}
+
+fn binary_operator(operation: &sir::BinaryOperation) -> (&'static str, u8) {
+    match operation {
+        sir::BinaryOperation::Add => ("+", ADDITIVE),
+        sir::BinaryOperation::AddSaturating => ("+|", ADDITIVE),
+        sir::BinaryOperation::AddWrapping => ("+%", ADDITIVE),
+        sir::BinaryOperation::BitAnd => ("&", BIT_AND),
+        sir::BinaryOperation::BitOr => ("|", BIT_OR),
+        sir::BinaryOperation::BitXor => ("^", BIT_XOR),
+        sir::BinaryOperation::Divide => ("/", MULTIPLICATIVE),
+        sir::BinaryOperation::Multiply => ("*", MULTIPLICATIVE),
+        sir::BinaryOperation::MultiplySaturating => ("*|", MULTIPLICATIVE),
+        sir::BinaryOperation::MultiplyWrapping => ("*%", MULTIPLICATIVE),
+        sir::BinaryOperation::ShiftLeft => ("<<", SHIFT),
+        sir::BinaryOperation::ShiftRight => (">>", SHIFT),
+        sir::BinaryOperation::ShiftRightLogical => (">>>", SHIFT),
+        sir::BinaryOperation::Subtract => ("-", ADDITIVE),
+        sir::BinaryOperation::SubtractSaturating => ("-|", ADDITIVE),
+        sir::BinaryOperation::SubtractWrapping => ("-%", ADDITIVE),
+    }
+}
+
+struct Printer<'a> {
+    out: String,
+    indent: usize,
+    params: Vec<&'a str>,
+    /// The names bound by the enclosing scopes, outermost first.
+    locals: Vec<&'a str>,
+    /// Where the comments go when formatting a source file.
+    layout: Option<ItemComments<'a>>,
+}
+
+/// The comments inside an item being formatted, and where the bindings in
+/// its blocks were written, which the comments are put between.
+struct ItemComments<'a> {
+    text: &'a str,
+    /// The comments not yet written, in order.
+    comments: Peekable<vec::IntoIter<&'a parser::Comment>>,
+    /// The bindings not yet printed, in order.
+    bindings: slice::Iter<'a, parser::Binding>,
+    /// Whether any comment has been written.
+    written: bool,
+    /// Whether the last line written ends in a comment, so that nothing else
+    /// can go on it.
+    after_comment: bool,
+}
+
+impl<'a> Printer<'a> {
+    fn new(layout: Option<ItemComments<'a>>) -> Self {
+        Self {
+            out: String::new(),
+            indent: 0,
+            params: Vec::new(),
+            locals: Vec::new(),
+            layout,
+        }
+    }
+
+    fn global(&mut self, name: &str, global: &'a sir::Global) {
+        self.params = global.arguments.iter().map(|(name, _)| name.as_str()).collect();
+        self.doc(&global.doc);
+        if global.public {
+            self.out.push_str("pub ");
+        }
+        if global.export {
+            self.out.push_str("export ");
+        }
+        self.out.push_str(name);
+        if !global.arguments.is_empty() {
+            self.arguments(&global.arguments);
+        }
+        write!(self.out, ": {} = ", global.return_type).unwrap();
+        self.expression(&global.body, 0);
+    }
+
+    fn extern_declaration(&mut self, name: &str, declaration: &sir::Extern) {
+        self.doc(&declaration.doc);
+        write!(self.out, "extern {}", name).unwrap();
+        self.arguments(&declaration.arguments);
+        write!(self.out, ": {}", declaration.return_type).unwrap();
+    }
+
+    /// Writes the comments that come before `offset`. One that follows code
+    /// on its line goes at the end of the last line, unless that ends in a
+    /// comment already, and any other on a line of its own.
+    fn comments_before(&mut self, offset: usize) {
+        let Some(layout) = &mut self.layout else {
+            return;
+        };
+        let text = layout.text;
+        let comments: Vec<_> =
+            std::iter::from_fn(|| layout.comments.next_if(|comment| comment.offset < offset)).collect();
+        for comment in comments {
+            let line_start = text[..comment.offset].rfind('\n').map_or(0, |i| i + 1);
+            let follows_code = !text[line_start..comment.offset].trim().is_empty();
+            let after_comment = self.layout.as_ref().is_some_and(|layout| layout.after_comment);
+            if follows_code && self.out.ends_with('\n') && !after_comment {
+                self.out.pop();
+                writeln!(self.out, " {}", comment.text).unwrap();
+            } else {
+                self.write_indent();
+                writeln!(self.out, "{}", comment.text).unwrap();
+            }
+            let layout = self.layout.as_mut().unwrap();
+            layout.written = true;
+            layout.after_comment = true;
+        }
+    }
+
+    fn doc(&mut self, doc: &Option<String>) {
+        for line in doc.iter().flat_map(|doc| doc.lines()) {
+            if line.is_empty() {
+                self.out.push_str("///\n");
+            } else {
+                writeln!(self.out, "/// {}", line).unwrap();
+            }
+        }
+    }
+
+    fn arguments(&mut self, arguments: &[(String, sir::DataType)]) {
+        self.out.push('(');
+        for (i, (name, data_type)) in arguments.iter().enumerate() {
+            if i > 0 {
+                self.out.push_str(", ");
+            }
+            write!(self.out, "{}: {}", name, data_type).unwrap();
+        }
+        self.out.push(')');
+    }
+
+    fn expression(&mut self, expression: &'a sir::Expression, min_precedence: u8) {
+        if precedence(expression) < min_precedence {
+            self.out.push('(');
+            self.expression(expression, 0);
+            self.out.push(')');
+            return;
+        }
+
+        match expression {
+            sir::Expression::Assert { condition, .. } => {
+                self.out.push_str("assert(");
+                self.expression(condition, 0);
+                self.out.push(')');
+            }
+            sir::Expression::BinaryOperation {
+                operation,
+                left,
+                right,
+                ..
+            } => {
+                let (operator, precedence) = binary_operator(operation);
+                // Binary operators associate to the left.
+                self.expression(left, precedence);
+                write!(self.out, " {} ", operator).unwrap();
+                self.expression(right, precedence + 1);
+            }
+            sir::Expression::Boxed { value, .. } => {
+                self.out.push_str("box(");
+                self.expression(value, 0);
+                self.out.push(')');
+            }
+            sir::Expression::Call {
+                function,
+                arguments,
+                ..
+            } => {
+                self.expression(function, POSTFIX);
+                self.out.push('(');
+                for (i, argument) in arguments.iter().enumerate() {
+                    if i > 0 {
+                        self.out.push_str(", ");
+                    }
+                    self.expression(argument, 0);
+                }
+                self.out.push(')');
+            }
+            sir::Expression::PartialApplication {
+                function,
+                arguments,
+                ..
+            } => {
+                self.expression(function, POSTFIX);
+                self.out.push('(');
+                for (i, argument) in arguments.iter().enumerate() {
+                    if i > 0 {
+                        self.out.push_str(", ");
+                    }
+                    match argument {
+                        Some(argument) => self.expression(argument, 0),
+                        None => self.out.push('_'),
+                    }
+                }
+                self.out.push(')');
+            }
+            sir::Expression::FunctionParam { index, .. } => match self.params.get(*index as usize) {
+                Some(name) => self.out.push_str(name),
+                None => write!(self.out, "${}", index).unwrap(),
+            },
+            sir::Expression::GlobalReference { name, .. } => self.out.push_str(name),
+            sir::Expression::I32Literal(val) => write!(self.out, "{}i32", val).unwrap(),
+            sir::Expression::I64Literal(val) => write!(self.out, "{}i64", val).unwrap(),
+            sir::Expression::Local { index, .. } => self.out.push_str(self.locals[*index as usize]),
+            sir::Expression::MemberAccess { left, member } => {
+                self.expression(left, POSTFIX);
+                write!(self.out, ".{}", member).unwrap();
+            }
+            sir::Expression::Null {
+                data_type: sir::DataType::Primitive(sir::PrimitiveDataType::Box(box_type)),
+            } => match &box_type.name {
+                Some(name) => write!(self.out, "null<{}>", name).unwrap(),
+                None => write!(self.out, "null<{}>", box_type.target()).unwrap(),
+            },
+            sir::Expression::Null { data_type } => write!(self.out, "null<{}>", data_type).unwrap(),
+            sir::Expression::Panic { message, .. } => {
+                self.out.push_str("panic(\"");
+                for c in message.chars() {
+                    match c {
+                        '"' => self.out.push_str("\\\""),
+                        '\\' => self.out.push_str("\\\\"),
+                        '\n' => self.out.push_str("\\n"),
+                        c => self.out.push(c),
+                    }
+                }
+                self.out.push_str("\")");
+            }
+            sir::Expression::Print { value, .. } => {
+                self.out.push_str("print(");
+                self.expression(value, 0);
+                self.out.push(')');
+            }
+            sir::Expression::Reference { name, .. } => self.out.push_str(name),
+            sir::Expression::Scope { .. } => self.block(expression),
+            sir::Expression::Tuple { values } => {
+                self.out.push('(');
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        self.out.push_str(", ");
+                    }
+                    self.expression(value, 0);
+                }
+                if values.len() == 1 {
+                    self.out.push(',');
+                }
+                self.out.push(')');
+            }
+            sir::Expression::UnaryOperation { operation, operand, .. } => {
+                self.out.push(match operation {
+                    sir::UnaryOperation::Complement => '~',
+                    sir::UnaryOperation::Negate => '-',
+                    sir::UnaryOperation::Not => '!',
+                });
+                match operand.as_ref() {
+                    // `-1i64` would parse as a negative literal.
+                    sir::Expression::I32Literal(_) | sir::Expression::I64Literal(_)
+                        if *operation == sir::UnaryOperation::Negate =>
+                    {
+                        self.out.push('(');
+                        self.expression(operand, 0);
+                        self.out.push(')');
+                    }
+                    operand => self.expression(operand, PREFIX),
+                }
+            }
+            sir::Expression::Unbox { value, .. } => {
+                self.out.push_str("unbox(");
+                self.expression(value, 0);
+                self.out.push(')');
+            }
+        }
+    }
+
+    /// Prints a chain of nested scopes as a single block, which parses back
+    /// to the same chain. When formatting, comments are written before each
+    /// binding, before the body, and before the closing brace.
+    fn block(&mut self, mut expression: &'a sir::Expression) {
+        let depth = self.locals.len();
+        let (mut body_offset, mut end) = (None, None);
+        self.out.push_str("{\n");
+        self.indent += 1;
+        while let sir::Expression::Scope { name, value, body } = expression {
+            // Nested blocks that end a chain are printed as part of it, so
+            // its body is the innermost block's, and its end the outermost's.
+            if let Some(binding) = self.layout.as_mut().and_then(|layout| layout.bindings.next()) {
+                self.comments_before(binding.offset);
+                body_offset = Some(binding.body);
+                end.get_or_insert(binding.end);
+            }
+            self.write_indent();
+            write!(self.out, "{} = ", name).unwrap();
+            self.expression(value, 0);
+            self.out.push_str(";\n");
+            self.locals.push(name);
+            expression = body;
+        }
+        if let Some(offset) = body_offset {
+            self.comments_before(offset);
+        }
+        self.write_indent();
+        self.expression(expression, 0);
+        self.out.push('\n');
+        if let Some(offset) = end {
+            self.comments_before(offset);
+        }
+        self.indent -= 1;
+        self.locals.truncate(depth);
+        self.write_indent();
+        self.out.push('}');
+    }
+
+    fn write_indent(&mut self) {
+        if let Some(layout) = &mut self.layout {
+            layout.after_comment = false;
+        }
+        for _ in 0..self.indent {
+            self.out.push_str(INDENT);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "
+/// Documented.
+pub twice(x: I64): I64 = x * 2i64
+extern puts(s: I64): I32
+pair(a: I64, b: I64): (I64, I64) = { sum = a + b; (sum, -(a - b)) }
+boxed: Box<(I64, I32)> = box((1i64, ~2i32))
+unboxed: I64 = { b = box(3i64); unbox(b) >> 1i64 }
+partial(x: I64): I64 = { f = twice(_); f(x) }
+checked(x: I64): I64 = assert(x) + print(x)
+failing: I64 = panic(\"no\")
+type List = (I64, Box<List>)
+empty: I64 = { list = null<List>; 0i64 }
+";
+
+    fn parse_module(text: &str) -> sir::Module {
+        parser::parse(text, &"test.scrap".into(), parser::source_file).unwrap().1
+    }
+
+    #[test]
+    fn printed_module_parses_to_the_same_tree() {
+        let mut module = parse_module(SOURCE);
+        let printed = print_module(&module);
+        let mut reparsed = parse_module(&printed);
+        for global in module.globals.values_mut().chain(reparsed.globals.values_mut()) {
+            erase_locations(global);
+        }
+        assert_eq!(module.globals, reparsed.globals, "{}", printed);
+        assert_eq!(module.externs, reparsed.externs, "{}", printed);
+        assert!(printed.starts_with("type List = (I64, Box<List>)\n"), "{}", printed);
+    }
+
+    #[test]
+    fn comments_inside_items_are_formatted_in_place() {
+        let source = "main: I64 = { // start
+  a = 1i64;   /* one */ /* two */
+      // The result.
+  b = { c = a + /* inner */ 2i64; c }; a + b // sum
+  // done
+}
+f: I64 = 1i64 + /* x */ 2i64 // end
+";
+        let formatted = format_source(source, &"test.scrap".into()).unwrap();
+        assert_eq!(
+            formatted,
+            "main: I64 = { // start
+    a = 1i64; /* one */
+    /* two */
+    // The result.
+    b = {
+        c = a + 2i64; /* inner */
+        c
+    };
+    a + b // sum
+    // done
+}
+
+/* x */
+f: I64 = 1i64 + 2i64 // end
+"
+        );
+        assert_eq!(format_source(&formatted, &"test.scrap".into()).unwrap(), formatted);
+    }
+
+    #[test]
+    fn locations_are_only_ignored_once_erased() {
+        let mut first = parse_module("a: I64 = 1i64 + 2i64");
+        let mut second = parse_module("a: I64 =   1i64   +   2i64");
+        assert_ne!(first.globals, second.globals);
+        for global in first.globals.values_mut().chain(second.globals.values_mut()) {
+            erase_locations(global);
+        }
+        assert_eq!(first.globals, second.globals);
+    }
+}
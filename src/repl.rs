@@ -0,0 +1,391 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{self, BufRead, Write},
+    rc::Rc,
+};
+
+use anyhow::{anyhow, bail, Result};
+use inkwell::{
+    context::Context,
+    execution_engine::ExecutionEngine,
+    module::Module,
+    targets::{InitializationConfig, Target},
+    types::BasicTypeEnum,
+    OptimizationLevel,
+};
+
+use crate::{
+    generator::{self, Generator},
+    interpreter::{PrimitiveValue, Value},
+    options::{Options, Overflow},
+    parser,
+    passes::{self, Registry},
+    sir,
+};
+
+const HELP: &str = "\
+name(args): Type = expr   define a global
+expr                      evaluate an expression
+:type expr                show the type of an expression
+:sir expr|name            show the resolved SIR of an expression or global
+:llvm expr|name           show the LLVM IR generated for an expression or global
+:load file                define every global in a file
+:quit                     exit";
+
+pub fn run() -> Result<()> {
+    Target::initialize_native(&InitializationConfig::default()).map_err(|e| anyhow!(e))?;
+
+    let context = Context::create();
+    let mut repl = Repl::new(&context)?;
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("> ");
+        io::stdout().flush()?;
+
+        let Some(line) = lines.next() else { break };
+        let line = line?;
+        let line = line.trim();
+        match line {
+            "" => continue,
+            ":quit" | ":q" => break,
+            _ => {
+                if let Err(e) = repl.handle(line) {
+                    eprintln!("error: {}", e);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Every input is compiled into its own LLVM module, which declares all of
+/// the previously defined globals and is added to a single JIT engine so that
+/// those declarations link against the earlier modules.
+struct Repl<'ctx> {
+    context: &'ctx Context,
+    engine: ExecutionEngine<'ctx>,
+    registry: Registry,
+    module: sir::Module,
+    counter: usize,
+    /// The file name given to locations in typed input.
+    file: Rc<str>,
+}
+
+impl<'ctx> Repl<'ctx> {
+    fn new(context: &'ctx Context) -> Result<Self> {
+        let engine = context
+            .create_module("repl")
+            .create_jit_execution_engine(OptimizationLevel::None)
+            .map_err(|e| anyhow!(e.to_string()))?;
+        let mut repl = Self {
+            context,
+            engine,
+            registry: Registry::new(),
+            module: sir::Module {
+                globals: HashMap::new(),
+                externs: HashMap::new(),
+            },
+            counter: 0,
+            file: "<repl>".into(),
+        };
+        // The first run of the passes brings in the prelude.
+        repl.compile(Vec::new())?;
+        Ok(repl)
+    }
+
+    fn handle(&mut self, line: &str) -> Result<()> {
+        let (command, input) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let input = input.trim();
+        match command {
+            ":type" => println!("{}", self.resolve_expression(input)?.data_type()),
+            ":sir" => match self.module.globals.get(input) {
+                Some(global) => println!("{:#?}", global),
+                None => println!("{:#?}", self.resolve_expression(input)?),
+            },
+            ":llvm" => {
+                let module = match self.module.globals.get(input) {
+                    Some(global) => self.generate(&[(input, global)])?.0,
+                    None => {
+                        let (name, global) = self.expression_global(input)?;
+                        self.generate(&[(&name, &global)])?.0
+                    }
+                };
+                print!("{}", module.to_string());
+            }
+            ":load" => {
+                let text = fs::read_to_string(input)?;
+                let module = parser::parse(&text, &input.into(), parser::module)?;
+                for (name, declaration) in module.externs {
+                    self.define_extern(name, declaration)?;
+                }
+                self.define(module.globals.into_iter().collect())?;
+            }
+            ":help" => println!("{}", HELP),
+            command if command.starts_with(':') => bail!("Unknown command {}; try :help", command),
+            _ => match parser::parse(line, &self.file, parser::item) {
+                Ok(parser::Item::Global(name, global)) => self.define(vec![(name, global)])?,
+                Ok(parser::Item::Extern(name, declaration)) => {
+                    self.define_extern(name, declaration)?
+                }
+                Err(_) => self.evaluate(line)?,
+            },
+        }
+        Ok(())
+    }
+
+    fn is_defined(&self, name: &str) -> bool {
+        self.module.globals.contains_key(name) || self.module.externs.contains_key(name)
+    }
+
+    fn define_extern(&mut self, name: String, declaration: sir::Extern) -> Result<()> {
+        if self.is_defined(&name) {
+            bail!("{} is already defined", name);
+        }
+        println!("{}: {}", name, declaration.data_type());
+        self.module.externs.insert(name, declaration);
+        Ok(())
+    }
+
+    fn define(&mut self, globals: Vec<(String, sir::Global)>) -> Result<()> {
+        for (name, _) in globals.iter() {
+            if self.is_defined(name) {
+                bail!("{} is already defined", name);
+            }
+        }
+
+        for name in self.compile(globals)? {
+            println!("{}: {}", name, self.module.globals[&name].data_type());
+        }
+        Ok(())
+    }
+
+    /// Resolves, checks and JIT-compiles new globals, along with any that
+    /// the passes add, returning their names in order. Nothing is kept if
+    /// any of them fails.
+    fn compile(&mut self, globals: Vec<(String, sir::Global)>) -> Result<Vec<String>> {
+        let defined: HashSet<_> = self.module.globals.keys().cloned().collect();
+        self.module.globals.extend(globals);
+        // The globals defined so far have been through the passes already,
+        // which leave them as they are.
+        let ran = self.registry.run(&mut self.module, &Options::default());
+        let mut names: Vec<_> = self
+            .module
+            .globals
+            .keys()
+            .filter(|name| !defined.contains(*name))
+            .cloned()
+            .collect();
+        names.sort();
+        if let Err(e) = ran {
+            for name in names.iter() {
+                self.module.globals.remove(name);
+            }
+            return Err(e);
+        }
+        // Later inputs live in other LLVM modules, so everything must be
+        // visible to the linker.
+        for name in names.iter() {
+            self.module.globals.get_mut(name).unwrap().public = true;
+        }
+
+        for name in names.iter() {
+            let global = self.module.globals.get_mut(name).unwrap();
+            if let Err(e) = check_resolved(&mut global.body) {
+                for name in names.iter() {
+                    self.module.globals.remove(name);
+                }
+                return Err(e);
+            }
+        }
+
+        let new_globals: Vec<_> = names
+            .iter()
+            .map(|name| (name.as_str(), &self.module.globals[name]))
+            .collect();
+        let generated = self.generate(&new_globals).and_then(|(module, _)| {
+            self.engine
+                .add_module(&module)
+                .map_err(|_| anyhow!("Could not add module to the JIT"))
+        });
+        if let Err(e) = generated {
+            for name in names.iter() {
+                self.module.globals.remove(name);
+            }
+            return Err(e);
+        }
+
+        Ok(names)
+    }
+
+    fn evaluate(&mut self, input: &str) -> Result<()> {
+        let (name, global) = self.expression_global(input)?;
+        let (module, llvm_type) = self.generate(&[(&name, &global)])?;
+        self.engine
+            .add_module(&module)
+            .map_err(|_| anyhow!("Could not add module to the JIT"))?;
+
+        let address = self
+            .engine
+            .get_function_address(&global.symbol(&name))
+            .map_err(|e| anyhow!("{:?}", e))?;
+        let value = unsafe {
+            match &global.return_type {
+                sir::DataType::Primitive(sir::PrimitiveDataType::I32) => {
+                    let function: unsafe extern "C" fn() -> i32 = std::mem::transmute(address);
+                    Value::Primitive(PrimitiveValue::I32(function()))
+                }
+                sir::DataType::Primitive(t) => {
+                    let function: unsafe extern "C" fn() -> u64 = std::mem::transmute(address);
+                    let raw = function();
+                    self.read_primitive(t, &raw as *const u64 as *const u8)?
+                }
+                t => {
+                    let size = self.engine.get_target_data().get_abi_size(&llvm_type);
+                    let mut buffer = vec![0u64; (size as usize + 7) / 8];
+                    let function: unsafe extern "C" fn(*mut u8) = std::mem::transmute(address);
+                    function(buffer.as_mut_ptr() as *mut u8);
+                    self.read_value(t, llvm_type, buffer.as_ptr() as *const u8)?
+                }
+            }
+        };
+
+        println!("{}: {}", value, global.return_type);
+        Ok(())
+    }
+
+    fn resolve_expression(&self, input: &str) -> Result<sir::Expression> {
+        let mut expression = parser::parse(input, &self.file, parser::expression)?;
+        self.registry.run_expression(&mut expression, &self.module)?;
+        check_resolved(&mut expression)?;
+        Ok(expression)
+    }
+
+    /// Wraps an expression in a fresh constant global so that it can be
+    /// compiled and called like any other.
+    fn expression_global(&mut self, input: &str) -> Result<(String, sir::Global)> {
+        let body = self.resolve_expression(input)?;
+        self.counter += 1;
+        let global = sir::Global {
+            arguments: Vec::new(),
+            return_type: body.data_type().into_owned(),
+            body,
+            public: true,
+            export: false,
+            location: sir::Location {
+                file: self.file.clone(),
+                line: 1,
+                column: 1,
+            },
+            doc: None,
+        };
+        Ok((format!("$it{}", self.counter), global))
+    }
+
+    fn generate(
+        &self,
+        globals: &[(&str, &sir::Global)],
+    ) -> Result<(Module<'ctx>, BasicTypeEnum<'ctx>)> {
+        let mut generator = Generator::new(self.context, &format!("repl{}", self.counter))?;
+        // A trap would take the REPL down with it.
+        generator.set_overflow(Overflow::Wrap);
+        for (name, declaration) in self.module.externs.iter() {
+            generator.declare_extern(name.clone(), declaration)?;
+        }
+        for (name, global) in self.module.globals.iter() {
+            generator.declare_global(name.clone(), global)?;
+        }
+        for (name, global) in globals.iter() {
+            if !self.module.globals.contains_key(*name) {
+                generator.declare_global(name.to_string(), global)?;
+            }
+        }
+        for (name, global) in globals.iter() {
+            generator.write_global(name, global)?;
+        }
+        let llvm_type = generator.type_to_llvm(&globals[0].1.return_type);
+        Ok((generator.build()?, llvm_type))
+    }
+
+    unsafe fn read_value(
+        &self,
+        data_type: &sir::DataType,
+        llvm_type: BasicTypeEnum<'ctx>,
+        ptr: *const u8,
+    ) -> Result<Value> {
+        match data_type {
+            sir::DataType::Primitive(t) => self.read_primitive(t, ptr),
+            sir::DataType::Tuple(elems) => {
+                let struct_type = llvm_type.into_struct_type();
+                let target_data = self.engine.get_target_data();
+                Ok(Value::Tuple(
+                    elems
+                        .iter()
+                        .enumerate()
+                        .map(|(i, elem)| {
+                            let offset = target_data
+                                .offset_of_element(&struct_type, i as u32)
+                                .unwrap();
+                            let field_type = struct_type.get_field_type_at_index(i as u32).unwrap();
+                            self.read_value(elem, field_type, ptr.add(offset as usize))
+                        })
+                        .collect::<Result<_>>()?,
+                ))
+            }
+        }
+    }
+
+    unsafe fn read_primitive(&self, data_type: &sir::PrimitiveDataType, ptr: *const u8) -> Result<Value> {
+        let value = match data_type {
+            sir::PrimitiveDataType::Function { .. } => {
+                // Function values point at a closure, which starts with the
+                // address of its code. Plain globals' closures use their
+                // `code_symbol`.
+                let closure = (ptr as *const *const usize).read_unaligned();
+                let address = closure.read_unaligned();
+                let name = self
+                    .module
+                    .globals
+                    .iter()
+                    .find(|(name, global)| {
+                        let symbol = generator::code_symbol(&global.symbol(name));
+                        self.engine.get_function_address(&symbol).ok() == Some(address)
+                    })
+                    .map(|(name, _)| name.clone())
+                    .unwrap_or_else(|| format!("<function at {:#x}>", address));
+                Value::Primitive(PrimitiveValue::Function(name))
+            }
+            sir::PrimitiveDataType::I32 => {
+                Value::Primitive(PrimitiveValue::I32((ptr as *const i32).read_unaligned()))
+            }
+            sir::PrimitiveDataType::I64 => Value::i64((ptr as *const i64).read_unaligned()),
+            sir::PrimitiveDataType::Box(box_type) => {
+                let address = (ptr as *const *const u8).read_unaligned();
+                if address.is_null() {
+                    return Ok(Value::Primitive(PrimitiveValue::Box(None)));
+                }
+                let target = box_type.target();
+                let llvm_type = Generator::new(self.context, "types")?.type_to_llvm(target);
+                let value = self.read_value(target, llvm_type, address)?;
+                Value::Primitive(PrimitiveValue::Box(Some(Rc::new(value))))
+            }
+        };
+        Ok(value)
+    }
+}
+
+fn check_resolved(expression: &mut sir::Expression) -> Result<()> {
+    let mut unresolved = Vec::new();
+    passes::transform_expression(expression, |expression| {
+        if let sir::Expression::Reference { name, .. } = expression {
+            unresolved.push(name.clone());
+        }
+    });
+    match unresolved.first() {
+        Some(name) => bail!("Unknown name {}", name),
+        None => Ok(()),
+    }
+}
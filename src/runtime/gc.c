@@ -0,0 +1,315 @@
+/*
+ * The garbage collector that programs compiled with --gc are linked against.
+ *
+ * It is a precise, non-moving mark-sweep collector. Its roots are the boxes
+ * that LLVM's stack maps say are live at each call into the collector, and
+ * the stack slots that generated code registers with scrap_gc_push_root,
+ * since the stack maps only cover values in registers. Boxes are traced
+ * using the layouts that the compiler derives from their types (see
+ * Generator::layout). Only x86-64 is supported.
+ *
+ * The backend compiles this file with SCRAP_HEAP_LIMIT set to the most
+ * memory the collector may hand out, and with frame pointers, which
+ * scrap_gc_alloc uses to find the frame that called it.
+ */
+
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#ifndef SCRAP_HEAP_LIMIT
+#define SCRAP_HEAP_LIMIT (64 << 20)
+#endif
+
+/* The status a program exits with when it panics. */
+#define PANIC_EXIT_STATUS 101
+
+/* Where the boxes in a value are: each of the offsets holds one. */
+struct scrap_layout {
+    uint64_t size;
+    uint64_t count;
+    uint64_t offsets[];
+};
+
+/* A box's header. Generated code points just past it, at the contents. */
+struct object {
+    struct object *next;
+    const struct scrap_layout *layout;
+    uint64_t marked;
+};
+
+/* Every box allocated and not yet swept, newest first. */
+static struct object *objects;
+static uint64_t heap_size;
+
+struct root {
+    void *slot;
+    const struct scrap_layout *layout;
+};
+
+static struct root *roots;
+static size_t root_count, root_capacity;
+
+/* The boxes that have been marked but whose contents haven't. */
+static struct object **mark_stack;
+static size_t mark_count, mark_capacity;
+
+/* LLVM labels the start of the stack map section with this. It is missing if
+ * nothing ever calls the collector. */
+extern const uint8_t __LLVM_StackMaps[] __attribute__((weak));
+
+/* The stack map record for the call that returns to an address. */
+struct call_site {
+    uintptr_t return_address;
+    uint64_t frame_size;
+    const uint8_t *record;
+};
+
+static struct call_site *call_sites;
+static size_t call_site_count;
+static int call_sites_read;
+
+enum location_type {
+    LOCATION_REGISTER = 1,
+    LOCATION_DIRECT = 2,
+    LOCATION_INDIRECT = 3,
+    LOCATION_CONSTANT = 4,
+    LOCATION_CONSTANT_INDEX = 5,
+};
+
+#define LOCATION_SIZE 12
+#define DWARF_RSP 7
+
+static void fail(const char *message) {
+    fprintf(stderr, "scrap gc: %s\n", message);
+    exit(PANIC_EXIT_STATUS);
+}
+
+static void *grow(void *array, size_t *capacity, size_t element_size) {
+    *capacity = *capacity ? *capacity * 2 : 64;
+    array = realloc(array, *capacity * element_size);
+    if (!array) {
+        fail("out of memory");
+    }
+    return array;
+}
+
+static uint16_t read16(const uint8_t *p) {
+    uint16_t value;
+    memcpy(&value, p, sizeof value);
+    return value;
+}
+
+static uint32_t read32(const uint8_t *p) {
+    uint32_t value;
+    memcpy(&value, p, sizeof value);
+    return value;
+}
+
+static uint64_t read64(const uint8_t *p) {
+    uint64_t value;
+    memcpy(&value, p, sizeof value);
+    return value;
+}
+
+static const uint8_t *align8(const uint8_t *p) {
+    return (const uint8_t *)(((uintptr_t)p + 7) & ~(uintptr_t)7);
+}
+
+/* Skips a stack map record: its header, locations and live-outs. */
+static const uint8_t *next_record(const uint8_t *record) {
+    uint16_t locations = read16(record + 14);
+    const uint8_t *p = align8(record + 16 + locations * LOCATION_SIZE);
+    uint16_t live_outs = read16(p + 2);
+    return align8(p + 4 + live_outs * 4);
+}
+
+static int compare_call_sites(const void *a, const void *b) {
+    uintptr_t left = ((const struct call_site *)a)->return_address;
+    uintptr_t right = ((const struct call_site *)b)->return_address;
+    return (left > right) - (left < right);
+}
+
+/* Indexes the stack map section (version 3) by return address. */
+static void read_stack_maps(void) {
+    call_sites_read = 1;
+    const uint8_t *p = __LLVM_StackMaps;
+    if (!p) {
+        return;
+    }
+    if (p[0] != 3) {
+        fail("unsupported stack map version");
+    }
+
+    uint32_t functions = read32(p + 4);
+    uint32_t constants = read32(p + 8);
+    uint32_t records = read32(p + 12);
+    const uint8_t *function = p + 16;
+    const uint8_t *record = function + functions * 24 + constants * 8;
+
+    call_sites = malloc(records * sizeof *call_sites);
+    if (records && !call_sites) {
+        fail("out of memory");
+    }
+    for (uint32_t i = 0; i < functions; i++, function += 24) {
+        uint64_t address = read64(function);
+        uint64_t frame_size = read64(function + 8);
+        uint64_t count = read64(function + 16);
+        for (uint64_t j = 0; j < count; j++) {
+            struct call_site *site = &call_sites[call_site_count++];
+            site->return_address = address + read32(record + 8);
+            site->frame_size = frame_size;
+            site->record = record;
+            record = next_record(record);
+        }
+    }
+    qsort(call_sites, call_site_count, sizeof *call_sites, compare_call_sites);
+}
+
+static const struct call_site *find_call_site(uintptr_t return_address) {
+    struct call_site key = {return_address, 0, NULL};
+    return bsearch(&key, call_sites, call_site_count, sizeof *call_sites, compare_call_sites);
+}
+
+static void mark(void *box) {
+    if (!box) {
+        return;
+    }
+    struct object *object = (struct object *)box - 1;
+    if (object->marked) {
+        return;
+    }
+    object->marked = 1;
+    if (object->layout->count == 0) {
+        return;
+    }
+    if (mark_count == mark_capacity) {
+        mark_stack = grow(mark_stack, &mark_capacity, sizeof *mark_stack);
+    }
+    mark_stack[mark_count++] = object;
+}
+
+static void mark_value(const uint8_t *value, const struct scrap_layout *layout) {
+    for (uint64_t i = 0; i < layout->count; i++) {
+        void *box;
+        memcpy(&box, value + layout->offsets[i], sizeof box);
+        mark(box);
+    }
+}
+
+static void mark_location(const uint8_t *location, const uint8_t *sp) {
+    switch (location[0]) {
+    case LOCATION_CONSTANT:
+    case LOCATION_CONSTANT_INDEX:
+        /* Only null boxes are constants. */
+        return;
+    case LOCATION_INDIRECT: {
+        if (read16(location + 4) != DWARF_RSP) {
+            fail("unsupported stack map location");
+        }
+        int32_t offset = (int32_t)read32(location + 8);
+        void *box;
+        memcpy(&box, sp + offset, sizeof box);
+        mark(box);
+        return;
+    }
+    default:
+        fail("unsupported stack map location");
+    }
+}
+
+/* Marks the boxes live in each scrap frame, starting with the one whose
+ * stack pointer is `sp` and which is waiting for a call to return to
+ * `return_address`, and stopping at the first frame the stack maps don't
+ * know. */
+static void mark_stack_frames(const uint8_t *sp, uintptr_t return_address) {
+    if (!call_sites_read) {
+        read_stack_maps();
+    }
+    const struct call_site *site;
+    while ((site = find_call_site(return_address))) {
+        if (site->frame_size == UINT64_MAX) {
+            fail("a frame has a dynamic size");
+        }
+        /* A statepoint's locations start with three constants, the last of
+         * which counts the deoptimization locations after them. Then come
+         * the live boxes, as pairs of base and derived pointers. Since
+         * nothing moves, only the bases matter. */
+        const uint8_t *locations = site->record + 16;
+        uint16_t count = read16(site->record + 14);
+        uint64_t first = 3 + read32(locations + 2 * LOCATION_SIZE + 8);
+        for (uint64_t i = first; i + 1 < count; i += 2) {
+            mark_location(locations + i * LOCATION_SIZE, sp);
+        }
+
+        /* The return address sits just above the frame. */
+        memcpy(&return_address, sp + site->frame_size, sizeof return_address);
+        sp += site->frame_size + sizeof return_address;
+    }
+}
+
+static void collect(const uint8_t *sp, uintptr_t return_address) {
+    mark_stack_frames(sp, return_address);
+    for (size_t i = 0; i < root_count; i++) {
+        mark_value(roots[i].slot, roots[i].layout);
+    }
+    while (mark_count > 0) {
+        struct object *object = mark_stack[--mark_count];
+        mark_value((const uint8_t *)(object + 1), object->layout);
+    }
+
+    struct object **link = &objects;
+    while (*link) {
+        struct object *object = *link;
+        if (object->marked) {
+            object->marked = 0;
+            link = &object->next;
+        } else {
+            *link = object->next;
+            heap_size -= sizeof *object + object->layout->size;
+            free(object);
+        }
+    }
+}
+
+/* Allocates a box laid out as `layout` says, collecting first if it would
+ * take the heap over its limit. Returns null if there is no room even then.
+ * It must have a frame of its own, so that it can find its caller's. */
+__attribute__((noinline)) void *scrap_gc_alloc(const struct scrap_layout *layout) {
+    uint64_t size = sizeof(struct object) + layout->size;
+    if (heap_size + size > SCRAP_HEAP_LIMIT) {
+        /* The caller's stack pointer is just above this frame's saved
+         * frame pointer and return address. */
+        const uint8_t *frame = __builtin_frame_address(0);
+        collect(frame + 16, (uintptr_t)__builtin_return_address(0));
+        if (heap_size + size > SCRAP_HEAP_LIMIT) {
+            return NULL;
+        }
+    }
+
+    struct object *object = malloc(size);
+    if (!object) {
+        return NULL;
+    }
+    object->next = objects;
+    object->layout = layout;
+    object->marked = 0;
+    objects = object;
+    heap_size += size;
+    return object + 1;
+}
+
+/* Makes the boxes in a stack slot roots, until the matching pop. */
+void scrap_gc_push_root(void *slot, const struct scrap_layout *layout) {
+    if (root_count == root_capacity) {
+        roots = grow(roots, &root_capacity, sizeof *roots);
+    }
+    roots[root_count].slot = slot;
+    roots[root_count].layout = layout;
+    root_count++;
+}
+
+void scrap_gc_pop_roots(uint64_t count) {
+    root_count -= count;
+}
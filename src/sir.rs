@@ -1,21 +1,44 @@
-use std::{borrow::Cow, collections::HashMap, fmt::{Write, self}};
+use std::{borrow::Cow, cell::OnceCell, collections::HashMap, fmt::{Write, self}, rc::Rc};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Expression {
+    /// Evaluates to `condition`, after panicking if it is 0.
+    Assert {
+        condition: Box<Expression>,
+        location: Location,
+    },
     BinaryOperation {
         operation: BinaryOperation,
         left: Box<Expression>,
         right: Box<Expression>,
+        /// The location of the operator.
+        location: Location,
+    },
+    /// Copies a value to the heap, giving a `PrimitiveDataType::Box`.
+    Boxed {
+        value: Box<Expression>,
+        location: Location,
     },
+    /// Calls a function. Until `build_partial_applications` has run, there
+    /// may be fewer arguments than the function takes.
     Call {
         function: Box<Expression>,
         arguments: Vec<Expression>,
+        /// The location of the opening parenthesis.
+        location: Location,
     },
     GlobalReference {
         name: String,
         data_type: DataType,
     },
+    I32Literal(i32),
     I64Literal(i64),
+    /// The value of an enclosing `Scope`. Scopes are numbered outermost
+    /// first, from 0.
+    Local {
+        index: u32,
+        data_type: DataType,
+    },
     MemberAccess {
         left: Box<Expression>,
         member: String,
@@ -24,8 +47,36 @@ pub enum Expression {
         index: u32,
         data_type: DataType,
     },
+    /// A function value that calls `function` with `arguments`, once it is
+    /// given the arguments that are `None` here and any after the end of
+    /// them, in that order.
+    PartialApplication {
+        function: Box<Expression>,
+        arguments: Vec<Option<Expression>>,
+        /// The location of the opening parenthesis.
+        location: Location,
+    },
+    /// A box with nothing in it, of type `data_type`.
+    Null {
+        data_type: DataType,
+    },
+    /// Stops the program, reporting `message` and the panic's location.
+    /// Panics take the type that is expected where they are used: their
+    /// global's return type, the other operand's type, or a parameter's type
+    /// (see `Expression::expect_type`). Anywhere else, they are I64.
+    Panic {
+        message: String,
+        data_type: DataType,
+        location: Location,
+    },
+    /// Writes `value` to stdout, followed by a newline, and evaluates to it.
+    Print {
+        value: Box<Expression>,
+        location: Location,
+    },
     Reference {
         name: String,
+        location: Location,
     },
     Scope {
         name: String,
@@ -35,31 +86,163 @@ pub enum Expression {
     Tuple {
         values: Vec<Expression>,
     },
+    UnaryOperation {
+        operation: UnaryOperation,
+        operand: Box<Expression>,
+        /// The location of the operator.
+        location: Location,
+    },
+    /// The value in a box, which panics if the box is null.
+    Unbox {
+        value: Box<Expression>,
+        location: Location,
+    },
 }
 
 impl Expression {
     pub fn data_type(&self) -> Cow<DataType> {
         match self {
+            Expression::Assert { condition, .. } => condition.data_type(),
             Expression::BinaryOperation { left, .. } => left.data_type(),
-            Expression::Call { function, .. } => {
-                let return_type = function.data_type();
-                let DataType::Primitive(PrimitiveDataType::Function { return_type, .. }) = return_type.as_ref() else {panic!("Non-function")};
-                Cow::Owned(return_type.as_ref().clone())
+            Expression::Boxed { value, .. } => Cow::Owned(DataType::Primitive(PrimitiveDataType::Box(
+                BoxType::new(value.data_type().into_owned()),
+            ))),
+            Expression::Call { function, arguments, .. } => {
+                let function_type = function.data_type();
+                let DataType::Primitive(PrimitiveDataType::Function {
+                    argument_types,
+                    return_type,
+                }) = function_type.as_ref()
+                else {
+                    panic!("Non-function")
+                };
+                if arguments.len() < argument_types.len() {
+                    Cow::Owned(awaited_function_type(argument_types, return_type, arguments.len(), |_| false))
+                } else {
+                    Cow::Owned(return_type.as_ref().clone())
+                }
             }
             Expression::GlobalReference { data_type, .. } => Cow::Borrowed(data_type),
+            Expression::I32Literal(_) => Cow::Owned(DataType::Primitive(PrimitiveDataType::I32)),
             Expression::I64Literal(_) => Cow::Owned(DataType::Primitive(PrimitiveDataType::I64)),
+            Expression::Local { data_type, .. } => Cow::Borrowed(data_type),
             Expression::MemberAccess { left, member } => Cow::Owned(left.data_type().field_type(&member).unwrap().clone()),
             Expression::FunctionParam { data_type, .. } => Cow::Borrowed(data_type),
+            Expression::Null { data_type } => Cow::Borrowed(data_type),
+            Expression::PartialApplication { function, arguments, .. } => {
+                let function_type = function.data_type();
+                let DataType::Primitive(PrimitiveDataType::Function {
+                    argument_types,
+                    return_type,
+                }) = function_type.as_ref()
+                else {
+                    panic!("Non-function")
+                };
+                Cow::Owned(awaited_function_type(argument_types, return_type, arguments.len(), |i| {
+                    arguments[i].is_none()
+                }))
+            }
+            Expression::Panic { data_type, .. } => Cow::Borrowed(data_type),
+            Expression::Print { value, .. } => value.data_type(),
             Expression::Reference { .. } => todo!(),
             Expression::Scope { body, .. } => body.data_type(),
             Expression::Tuple { values } => Cow::Owned(DataType::Tuple(
                 values.iter().map(|value| value.data_type().into_owned()).collect(),
             )),
+            Expression::UnaryOperation { operand, .. } => operand.data_type(),
+            Expression::Unbox { value, .. } => {
+                let data_type = value.data_type();
+                let DataType::Primitive(PrimitiveDataType::Box(box_type)) = data_type.as_ref() else {panic!("Non-box")};
+                Cow::Owned(box_type.target().clone())
+            }
+        }
+    }
+
+    /// Where the expression is in the source, if it is one that keeps track.
+    pub fn location(&self) -> Option<&Location> {
+        match self {
+            Expression::Assert { location, .. }
+            | Expression::BinaryOperation { location, .. }
+            | Expression::Boxed { location, .. }
+            | Expression::Call { location, .. }
+            | Expression::PartialApplication { location, .. }
+            | Expression::Panic { location, .. }
+            | Expression::Print { location, .. }
+            | Expression::Reference { location, .. }
+            | Expression::UnaryOperation { location, .. }
+            | Expression::Unbox { location, .. } => Some(location),
+            _ => None,
+        }
+    }
+
+    /// Gives the panics that this expression may evaluate to the type that
+    /// it is expected to have, including those in the parts of a tuple or
+    /// box, and the operands of an arithmetic expression.
+    pub fn expect_type(&mut self, expected: &DataType) {
+        match (self, expected) {
+            (Expression::Panic { data_type, .. }, _) => *data_type = expected.clone(),
+            (Expression::Assert { condition: value, .. }, _)
+            | (Expression::Print { value, .. }, _)
+            | (Expression::UnaryOperation { operand: value, .. }, _)
+            | (Expression::Scope { body: value, .. }, _) => value.expect_type(expected),
+            (Expression::BinaryOperation { left, right, .. }, _) => {
+                left.expect_type(expected);
+                right.expect_type(expected);
+            }
+            (Expression::Boxed { value, .. }, DataType::Primitive(PrimitiveDataType::Box(box_type))) => {
+                value.expect_type(box_type.target())
+            }
+            (Expression::Tuple { values }, DataType::Tuple(expected)) if values.len() == expected.len() => {
+                for (value, expected) in values.iter_mut().zip(expected) {
+                    value.expect_type(expected);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Gives the panics among this expression's operands the types that it
+    /// needs them to have, which are known once its operands are resolved:
+    /// the other operand's for a binary operation, and the parameters' for a
+    /// call.
+    pub fn expect_operand_types(&mut self) {
+        match self {
+            Expression::BinaryOperation { left, right, .. } => {
+                let left_type = left.data_type().into_owned();
+                right.expect_type(&left_type);
+                let right_type = right.data_type().into_owned();
+                left.expect_type(&right_type);
+            }
+            Expression::Call {
+                function, arguments, ..
+            } => {
+                if let DataType::Primitive(PrimitiveDataType::Function { argument_types, .. }) =
+                    function.data_type().as_ref()
+                {
+                    for (argument, expected) in arguments.iter_mut().zip(argument_types) {
+                        argument.expect_type(expected);
+                    }
+                }
+            }
+            Expression::PartialApplication {
+                function, arguments, ..
+            } => {
+                if let DataType::Primitive(PrimitiveDataType::Function { argument_types, .. }) =
+                    function.data_type().as_ref()
+                {
+                    for (argument, expected) in arguments.iter_mut().zip(argument_types) {
+                        if let Some(argument) = argument {
+                            argument.expect_type(expected);
+                        }
+                    }
+                }
+            }
+            _ => {}
         }
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum DataType {
     Primitive(PrimitiveDataType),
     Tuple(Vec<DataType>),
@@ -70,6 +253,17 @@ impl DataType {
         matches!(self, DataType::Primitive(_))
     }
 
+    /// Whether a value of this type holds any boxes or function values
+    /// itself, which point at memory on the heap, not counting what they
+    /// hold.
+    pub fn contains_references(&self) -> bool {
+        match self {
+            DataType::Primitive(PrimitiveDataType::Box(_) | PrimitiveDataType::Function { .. }) => true,
+            DataType::Primitive(_) => false,
+            DataType::Tuple(elements) => elements.iter().any(DataType::contains_references),
+        }
+    }
+
     pub fn mangle(&self, out: &mut impl Write) -> fmt::Result {
         match self {
             DataType::Primitive(t) => t.mangle(out),
@@ -114,40 +308,285 @@ impl DataType {
     }
 }
 
-#[derive(Clone, Debug)]
+impl fmt::Display for DataType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DataType::Primitive(t) => t.fmt(f),
+            DataType::Tuple(elements) => {
+                write!(f, "(")?;
+                write_list(f, elements)?;
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum PrimitiveDataType {
     Function {
         argument_types: Vec<DataType>,
         return_type: Box<DataType>,
     },
+    I32,
     I64,
+    /// A pointer to a value on the heap, or null.
+    Box(BoxType),
 }
 
 impl PrimitiveDataType {
     fn mangle(&self, out: &mut impl Write) -> fmt::Result {
         match self {
-            PrimitiveDataType::Function { .. } => todo!(),
+            PrimitiveDataType::Function {
+                argument_types,
+                return_type,
+            } => {
+                write!(out, "F(")?;
+                let mut first = true;
+                for argument_type in argument_types {
+                    if first {
+                        first = false;
+                    } else {
+                        write!(out, ",")?;
+                    }
+                    argument_type.mangle(out)?;
+                }
+                write!(out, ")")?;
+                return_type.mangle(out)
+            }
+            PrimitiveDataType::I32 => write!(out, "I32"),
             PrimitiveDataType::I64 => write!(out, "I64"),
+            // Named types are length-prefixed, so that they can't be
+            // confused with anything else.
+            PrimitiveDataType::Box(BoxType { name: Some(name), .. }) => write!(out, "BN{}{}", name.len(), name),
+            PrimitiveDataType::Box(box_type) => {
+                write!(out, "B")?;
+                box_type.target().mangle(out)
+            }
+        }
+    }
+}
+
+impl fmt::Display for PrimitiveDataType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PrimitiveDataType::Function {
+                argument_types,
+                return_type,
+            } => {
+                write!(f, "(")?;
+                write_list(f, argument_types)?;
+                write!(f, "): {}", return_type)
+            }
+            PrimitiveDataType::I32 => write!(f, "I32"),
+            PrimitiveDataType::I64 => write!(f, "I64"),
+            PrimitiveDataType::Box(BoxType { name: Some(name), .. }) => write!(f, "Box<{}>", name),
+            PrimitiveDataType::Box(box_type) => write!(f, "Box<{}>", box_type.target()),
+        }
+    }
+}
+
+/// What a box holds. Boxes of a type declared with `type` are named after
+/// it, qualified by its module's path like a global, and share its
+/// definition, which may itself contain such boxes. This is how types refer
+/// to themselves.
+#[derive(Clone)]
+pub struct BoxType {
+    pub name: Option<String>,
+    /// Set once the named type's declaration has been parsed.
+    target: Rc<OnceCell<DataType>>,
+}
+
+impl BoxType {
+    pub fn new(target: DataType) -> Self {
+        Self {
+            name: None,
+            target: Rc::new(OnceCell::from(target)),
         }
     }
+
+    /// A box of the type declared as `name`. Its definition is put in
+    /// `target` once it has been parsed.
+    pub fn named(name: String, target: Rc<OnceCell<DataType>>) -> Self {
+        Self {
+            name: Some(name),
+            target,
+        }
+    }
+
+    pub fn target(&self) -> &DataType {
+        self.target.get().expect("Undefined type")
+    }
 }
 
-#[derive(Clone, Debug)]
+/// Named boxes are the same if they have the same name. Otherwise, the
+/// contents are compared, which ends because unnamed types are finite.
+impl PartialEq for BoxType {
+    fn eq(&self, other: &Self) -> bool {
+        match (&self.name, &other.name) {
+            (Some(name), Some(other_name)) => name == other_name,
+            _ => self.target() == other.target(),
+        }
+    }
+}
+
+/// Named boxes are printed by name, since their contents may contain them.
+impl fmt::Debug for BoxType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "Box<{}>", name),
+            None => write!(f, "Box<{:?}>", self.target.get()),
+        }
+    }
+}
+
+fn write_list(f: &mut fmt::Formatter<'_>, elements: &[DataType]) -> fmt::Result {
+    for (i, element) in elements.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{}", element)?;
+    }
+    Ok(())
+}
+
+/// A position in a source file. Lines and columns start at 1.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Location {
+    pub file: Rc<str>,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum BinaryOperation {
+    /// Overflows as `Options::overflow` says, as do `Divide`, `Multiply`,
+    /// `Subtract` and `UnaryOperation::Negate`.
     Add,
+    AddSaturating,
+    AddWrapping,
+    BitAnd,
+    BitOr,
+    BitXor,
     Divide,
     Multiply,
+    MultiplySaturating,
+    MultiplyWrapping,
+    /// Shifting by the width of the type or more gives 0.
+    ShiftLeft,
+    /// An arithmetic shift. Shifting by the width of the type or more gives 0
+    /// or -1, depending on the sign.
+    ShiftRight,
+    /// A logical shift. Shifting by the width of the type or more gives 0.
+    ShiftRightLogical,
     Subtract,
+    SubtractSaturating,
+    SubtractWrapping,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
+pub enum UnaryOperation {
+    /// Bitwise not.
+    Complement,
+    Negate,
+    /// Logical not: 1 if the operand is 0, and 0 otherwise.
+    Not,
+}
+
+#[derive(Debug, PartialEq)]
 pub struct Global {
     pub arguments: Vec<(String, DataType)>,
     pub return_type: DataType,
     pub body: Expression,
+    /// Whether other modules may refer to this global. Private globals are
+    /// emitted with internal linkage.
+    pub public: bool,
+    pub export: bool,
+    /// The location of the global's name.
+    pub location: Location,
+    /// The text of the `///` comments before the global, if any.
+    pub doc: Option<String>,
+}
+
+impl Global {
+    pub fn data_type(&self) -> DataType {
+        if self.arguments.is_empty() {
+            self.return_type.clone()
+        } else {
+            function_type(&self.arguments, &self.return_type)
+        }
+    }
+
+    /// The linker symbol for this global. Exported globals keep their source
+    /// name so that C can call them, except that the dots in the names of
+    /// imported modules' globals become underscores, so `geometry.area` is
+    /// `geometry_area`. Everything else is prefixed with `_S` and suffixed
+    /// with its mangled type, so that it can never collide with a C symbol
+    /// such as `malloc` or `main`.
+    pub fn symbol(&self, name: &str) -> String {
+        if self.export {
+            return name.replace('.', "_");
+        }
+
+        let mut symbol = format!("_S{}$", name);
+        self.data_type().mangle(&mut symbol).unwrap();
+        symbol
+    }
+}
+
+/// A function implemented in C, declared with `extern`. Unlike a `Global`,
+/// an extern with no arguments is still a function.
+#[derive(Debug, PartialEq)]
+pub struct Extern {
+    pub arguments: Vec<(String, DataType)>,
+    pub return_type: DataType,
+    pub doc: Option<String>,
+}
+
+impl Extern {
+    pub fn data_type(&self) -> DataType {
+        function_type(&self.arguments, &self.return_type)
+    }
+}
+
+/// The type of what is left of a function once it has been given its first
+/// `given` arguments, apart from those that `is_hole` says are missing.
+fn awaited_function_type(
+    argument_types: &[DataType],
+    return_type: &DataType,
+    given: usize,
+    is_hole: impl Fn(usize) -> bool,
+) -> DataType {
+    let argument_types = argument_types
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i >= given || is_hole(*i))
+        .map(|(_, argument_type)| argument_type.clone())
+        .collect();
+    DataType::Primitive(PrimitiveDataType::Function {
+        argument_types,
+        return_type: Box::new(return_type.clone()),
+    })
+}
+
+fn function_type(arguments: &[(String, DataType)], return_type: &DataType) -> DataType {
+    let argument_types = arguments
+        .iter()
+        .map(|(_, argument_type)| argument_type.clone())
+        .collect();
+    DataType::Primitive(PrimitiveDataType::Function {
+        argument_types,
+        return_type: Box::new(return_type.clone()),
+    })
 }
 
 #[derive(Debug)]
 pub struct Module {
     pub globals: HashMap<String, Global>,
+    pub externs: HashMap<String, Extern>,
 }
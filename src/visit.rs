@@ -0,0 +1,530 @@
+//! Traversals over SIR. `Visitor` walks it by reference, and `VisitorMut`
+//! rewrites it in place. Both have pre- and post-order hooks that see the
+//! names bound around the expression, and can fail, which ends the walk.
+
+use anyhow::Result;
+
+use crate::sir;
+
+/// What a hook wants the walk to do next.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Walk {
+    Continue,
+    /// From a pre-order hook, skips the expression's children and its
+    /// post-order hook. From a post-order hook, this is the same as
+    /// `Continue`.
+    SkipChildren,
+    /// Ends the whole walk without an error.
+    Stop,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Binding {
+    /// The parameter of the enclosing global at this index.
+    Param(u32),
+    /// The name bound by an enclosing `Scope`.
+    Scope,
+}
+
+/// The names in scope at an expression: the enclosing global's parameters,
+/// then the names bound by enclosing `Scope`s, innermost last.
+#[derive(Clone, Debug, Default)]
+pub struct Bindings {
+    bound: Vec<(String, Binding)>,
+}
+
+impl Bindings {
+    pub fn for_global(global: &sir::Global) -> Self {
+        Self {
+            bound: global
+                .arguments
+                .iter()
+                .enumerate()
+                .map(|(index, (name, _))| (name.clone(), Binding::Param(index as u32)))
+                .collect(),
+        }
+    }
+
+    /// What a name refers to here, if it is bound locally at all. Inner
+    /// bindings shadow outer ones.
+    pub fn lookup(&self, name: &str) -> Option<&Binding> {
+        self.bound
+            .iter()
+            .rev()
+            .find(|(bound_name, _)| bound_name == name)
+            .map(|(_, binding)| binding)
+    }
+
+    /// Where the `Scope` that binds a name here is among the enclosing
+    /// scopes, outermost first, which is how `Local`s are numbered. `None` if
+    /// the name is unbound, or is a parameter.
+    pub fn scope_index(&self, name: &str) -> Option<u32> {
+        let position = self.bound.iter().rposition(|(bound_name, _)| bound_name == name)?;
+        if self.bound[position].1 != Binding::Scope {
+            return None;
+        }
+        Some(
+            self.bound[..position]
+                .iter()
+                .filter(|(_, binding)| *binding == Binding::Scope)
+                .count() as u32,
+        )
+    }
+
+    pub fn is_bound(&self, name: &str) -> bool {
+        self.lookup(name).is_some()
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.bound.iter().map(|(name, _)| name.as_str())
+    }
+
+    fn push(&mut self, name: &str, binding: Binding) {
+        self.bound.push((name.to_string(), binding));
+    }
+
+    fn pop(&mut self) {
+        self.bound.pop();
+    }
+}
+
+macro_rules! walk {
+    ($walk:expr) => {
+        if $walk? == Walk::Stop {
+            return Ok(Walk::Stop);
+        }
+    };
+}
+
+pub trait Visitor {
+    fn visit_module(&mut self, module: &sir::Module) -> Result<Walk> {
+        walk_module(self, module)
+    }
+
+    fn visit_global(&mut self, name: &str, global: &sir::Global) -> Result<Walk> {
+        walk_global(self, name, global)
+    }
+
+    fn pre_expression(&mut self, _expression: &sir::Expression, _bindings: &Bindings) -> Result<Walk> {
+        Ok(Walk::Continue)
+    }
+
+    fn post_expression(&mut self, _expression: &sir::Expression, _bindings: &Bindings) -> Result<Walk> {
+        Ok(Walk::Continue)
+    }
+}
+
+/// Visits the globals in order of their names, so that walks are
+/// deterministic.
+pub fn walk_module<V: Visitor + ?Sized>(visitor: &mut V, module: &sir::Module) -> Result<Walk> {
+    let mut names: Vec<_> = module.globals.keys().collect();
+    names.sort();
+    for name in names {
+        walk!(visitor.visit_global(name, &module.globals[name]));
+    }
+    Ok(Walk::Continue)
+}
+
+pub fn walk_global<V: Visitor + ?Sized>(visitor: &mut V, _name: &str, global: &sir::Global) -> Result<Walk> {
+    walk_expression(visitor, &global.body, &mut Bindings::for_global(global))
+}
+
+pub fn walk_expression<V: Visitor + ?Sized>(
+    visitor: &mut V,
+    expression: &sir::Expression,
+    bindings: &mut Bindings,
+) -> Result<Walk> {
+    match visitor.pre_expression(expression, bindings)? {
+        Walk::Continue => {}
+        Walk::SkipChildren => return Ok(Walk::Continue),
+        Walk::Stop => return Ok(Walk::Stop),
+    }
+
+    match expression {
+        sir::Expression::Assert { condition, .. } => {
+            walk!(walk_expression(visitor, condition, bindings));
+        }
+        sir::Expression::BinaryOperation { left, right, .. } => {
+            walk!(walk_expression(visitor, left, bindings));
+            walk!(walk_expression(visitor, right, bindings));
+        }
+        sir::Expression::Boxed { value, .. } => {
+            walk!(walk_expression(visitor, value, bindings));
+        }
+        sir::Expression::Call {
+            function,
+            arguments,
+            ..
+        } => {
+            walk!(walk_expression(visitor, function, bindings));
+            for argument in arguments {
+                walk!(walk_expression(visitor, argument, bindings));
+            }
+        }
+        sir::Expression::FunctionParam { .. } => {}
+        sir::Expression::GlobalReference { .. } => {}
+        sir::Expression::I32Literal(_) => {}
+        sir::Expression::I64Literal(_) => {}
+        sir::Expression::Local { .. } => {}
+        sir::Expression::MemberAccess { left, .. } => {
+            walk!(walk_expression(visitor, left, bindings));
+        }
+        sir::Expression::Null { .. } => {}
+        sir::Expression::PartialApplication {
+            function,
+            arguments,
+            ..
+        } => {
+            walk!(walk_expression(visitor, function, bindings));
+            for argument in arguments.iter().flatten() {
+                walk!(walk_expression(visitor, argument, bindings));
+            }
+        }
+        sir::Expression::Panic { .. } => {}
+        sir::Expression::Print { value, .. } => {
+            walk!(walk_expression(visitor, value, bindings));
+        }
+        sir::Expression::Reference { .. } => {}
+        sir::Expression::Scope { name, value, body } => {
+            walk!(walk_expression(visitor, value, bindings));
+            bindings.push(name, Binding::Scope);
+            let walk = walk_expression(visitor, body, bindings);
+            bindings.pop();
+            walk!(walk);
+        }
+        sir::Expression::Tuple { values } => {
+            for value in values {
+                walk!(walk_expression(visitor, value, bindings));
+            }
+        }
+        sir::Expression::UnaryOperation { operand, .. } => {
+            walk!(walk_expression(visitor, operand, bindings));
+        }
+        sir::Expression::Unbox { value, .. } => {
+            walk!(walk_expression(visitor, value, bindings));
+        }
+    }
+
+    match visitor.post_expression(expression, bindings)? {
+        Walk::Stop => Ok(Walk::Stop),
+        _ => Ok(Walk::Continue),
+    }
+}
+
+pub trait VisitorMut {
+    fn visit_module(&mut self, module: &mut sir::Module) -> Result<Walk> {
+        walk_module_mut(self, module)
+    }
+
+    fn visit_global(&mut self, name: &str, global: &mut sir::Global) -> Result<Walk> {
+        walk_global_mut(self, name, global)
+    }
+
+    fn pre_expression(&mut self, _expression: &mut sir::Expression, _bindings: &Bindings) -> Result<Walk> {
+        Ok(Walk::Continue)
+    }
+
+    fn post_expression(&mut self, _expression: &mut sir::Expression, _bindings: &Bindings) -> Result<Walk> {
+        Ok(Walk::Continue)
+    }
+
+    /// Called once a `Scope`'s value has been walked, just before its name
+    /// is bound and its body walked. Unlike in `pre_expression`, the value
+    /// has been rewritten by then.
+    fn enter_scope(&mut self, _name: &str, _value: &mut sir::Expression, _bindings: &Bindings) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub fn walk_module_mut<V: VisitorMut + ?Sized>(visitor: &mut V, module: &mut sir::Module) -> Result<Walk> {
+    let mut names: Vec<_> = module.globals.keys().cloned().collect();
+    names.sort();
+    for name in names {
+        let global = module.globals.get_mut(&name).unwrap();
+        walk!(visitor.visit_global(&name, global));
+    }
+    Ok(Walk::Continue)
+}
+
+pub fn walk_global_mut<V: VisitorMut + ?Sized>(
+    visitor: &mut V,
+    _name: &str,
+    global: &mut sir::Global,
+) -> Result<Walk> {
+    let mut bindings = Bindings::for_global(global);
+    walk_expression_mut(visitor, &mut global.body, &mut bindings)
+}
+
+/// Like `walk_expression`, except that a pre-order hook may replace the
+/// expression, in which case the replacement's children are walked.
+pub fn walk_expression_mut<V: VisitorMut + ?Sized>(
+    visitor: &mut V,
+    expression: &mut sir::Expression,
+    bindings: &mut Bindings,
+) -> Result<Walk> {
+    match visitor.pre_expression(expression, bindings)? {
+        Walk::Continue => {}
+        Walk::SkipChildren => return Ok(Walk::Continue),
+        Walk::Stop => return Ok(Walk::Stop),
+    }
+
+    match expression {
+        sir::Expression::Assert { condition, .. } => {
+            walk!(walk_expression_mut(visitor, condition, bindings));
+        }
+        sir::Expression::BinaryOperation { left, right, .. } => {
+            walk!(walk_expression_mut(visitor, left, bindings));
+            walk!(walk_expression_mut(visitor, right, bindings));
+        }
+        sir::Expression::Boxed { value, .. } => {
+            walk!(walk_expression_mut(visitor, value, bindings));
+        }
+        sir::Expression::Call {
+            function,
+            arguments,
+            ..
+        } => {
+            walk!(walk_expression_mut(visitor, function, bindings));
+            for argument in arguments {
+                walk!(walk_expression_mut(visitor, argument, bindings));
+            }
+        }
+        sir::Expression::FunctionParam { .. } => {}
+        sir::Expression::GlobalReference { .. } => {}
+        sir::Expression::I32Literal(_) => {}
+        sir::Expression::I64Literal(_) => {}
+        sir::Expression::Local { .. } => {}
+        sir::Expression::MemberAccess { left, .. } => {
+            walk!(walk_expression_mut(visitor, left, bindings));
+        }
+        sir::Expression::Null { .. } => {}
+        sir::Expression::PartialApplication {
+            function,
+            arguments,
+            ..
+        } => {
+            walk!(walk_expression_mut(visitor, function, bindings));
+            for argument in arguments.iter_mut().flatten() {
+                walk!(walk_expression_mut(visitor, argument, bindings));
+            }
+        }
+        sir::Expression::Panic { .. } => {}
+        sir::Expression::Print { value, .. } => {
+            walk!(walk_expression_mut(visitor, value, bindings));
+        }
+        sir::Expression::Reference { .. } => {}
+        sir::Expression::Scope { name, value, body } => {
+            walk!(walk_expression_mut(visitor, value, bindings));
+            visitor.enter_scope(name, value, bindings)?;
+            bindings.push(name, Binding::Scope);
+            let walk = walk_expression_mut(visitor, body, bindings);
+            bindings.pop();
+            walk!(walk);
+        }
+        sir::Expression::Tuple { values } => {
+            for value in values {
+                walk!(walk_expression_mut(visitor, value, bindings));
+            }
+        }
+        sir::Expression::UnaryOperation { operand, .. } => {
+            walk!(walk_expression_mut(visitor, operand, bindings));
+        }
+        sir::Expression::Unbox { value, .. } => {
+            walk!(walk_expression_mut(visitor, value, bindings));
+        }
+    }
+
+    match visitor.post_expression(expression, bindings)? {
+        Walk::Stop => Ok(Walk::Stop),
+        _ => Ok(Walk::Continue),
+    }
+}
+
+struct PostOrder<F>(F);
+
+impl<F: FnMut(&sir::Expression, &Bindings) -> Result<()>> Visitor for PostOrder<F> {
+    fn post_expression(&mut self, expression: &sir::Expression, bindings: &Bindings) -> Result<Walk> {
+        (self.0)(expression, bindings)?;
+        Ok(Walk::Continue)
+    }
+}
+
+struct PostOrderMut<F>(F);
+
+impl<F: FnMut(&mut sir::Expression, &Bindings) -> Result<()>> VisitorMut for PostOrderMut<F> {
+    fn post_expression(&mut self, expression: &mut sir::Expression, bindings: &Bindings) -> Result<Walk> {
+        (self.0)(expression, bindings)?;
+        Ok(Walk::Continue)
+    }
+}
+
+/// Calls `f` on every expression in a tree, children first, stopping at the
+/// first error.
+pub fn try_for_each(
+    expression: &sir::Expression,
+    bindings: &mut Bindings,
+    f: impl FnMut(&sir::Expression, &Bindings) -> Result<()>,
+) -> Result<()> {
+    walk_expression(&mut PostOrder(f), expression, bindings).map(|_| ())
+}
+
+/// Like `try_for_each`, but `f` may rewrite each expression after its
+/// children have been rewritten.
+pub fn try_for_each_mut(
+    expression: &mut sir::Expression,
+    bindings: &mut Bindings,
+    f: impl FnMut(&mut sir::Expression, &Bindings) -> Result<()>,
+) -> Result<()> {
+    walk_expression_mut(&mut PostOrderMut(f), expression, bindings).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    fn parse_module(text: &str) -> sir::Module {
+        parser::parse(text, &"test.scrap".into(), parser::source_file).unwrap().1
+    }
+
+    /// Records the literals it sees after their children, and stops or
+    /// skips where it is told to.
+    #[derive(Default)]
+    struct Literals {
+        seen: Vec<i64>,
+        stop_at: Option<i64>,
+        skip_tuples: bool,
+        tuples_finished: usize,
+    }
+
+    impl Visitor for Literals {
+        fn pre_expression(&mut self, expression: &sir::Expression, _bindings: &Bindings) -> Result<Walk> {
+            match expression {
+                sir::Expression::Tuple { .. } if self.skip_tuples => Ok(Walk::SkipChildren),
+                _ => Ok(Walk::Continue),
+            }
+        }
+
+        fn post_expression(&mut self, expression: &sir::Expression, _bindings: &Bindings) -> Result<Walk> {
+            match expression {
+                sir::Expression::I64Literal(value) => {
+                    self.seen.push(*value);
+                    if self.stop_at == Some(*value) {
+                        return Ok(Walk::Stop);
+                    }
+                }
+                sir::Expression::Tuple { .. } => self.tuples_finished += 1,
+                _ => {}
+            }
+            Ok(Walk::Continue)
+        }
+    }
+
+    #[test]
+    fn stop_ends_the_whole_walk() {
+        let module = parse_module("a: I64 = (1i64 + 2i64) * 3i64\nb: I64 = 4i64");
+        let mut literals = Literals {
+            stop_at: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(literals.visit_module(&module).unwrap(), Walk::Stop);
+        assert_eq!(literals.seen, [1, 2]);
+    }
+
+    #[test]
+    fn skip_children_skips_the_post_order_hook_too() {
+        let module = parse_module("a: I64 = { t = (1i64, 2i64); t.elem_0 + 3i64 }");
+        let mut literals = Literals {
+            skip_tuples: true,
+            ..Default::default()
+        };
+        assert_eq!(literals.visit_module(&module).unwrap(), Walk::Continue);
+        assert_eq!(literals.seen, [3]);
+        assert_eq!(literals.tuples_finished, 0);
+    }
+
+    #[test]
+    fn errors_end_the_walk() {
+        let mut module = parse_module("a: I64 = 1i64 + 2i64");
+        let body = &mut module.globals.get_mut("a").unwrap().body;
+        let mut seen = Vec::new();
+        let result = try_for_each_mut(body, &mut Bindings::default(), |expression, _| {
+            if let sir::Expression::I64Literal(value) = expression {
+                seen.push(*value);
+                anyhow::bail!("no");
+            }
+            Ok(())
+        });
+        assert!(result.is_err());
+        assert_eq!(seen, [1]);
+    }
+
+    /// Doubles every literal, and records what each scope it enters is
+    /// given, with the names already bound there.
+    #[derive(Default)]
+    struct Doubler {
+        entered: Vec<(String, String, String)>,
+    }
+
+    impl VisitorMut for Doubler {
+        fn post_expression(
+            &mut self,
+            expression: &mut sir::Expression,
+            _bindings: &Bindings,
+        ) -> Result<Walk> {
+            if let sir::Expression::I64Literal(value) = expression {
+                *value *= 2;
+            }
+            Ok(Walk::Continue)
+        }
+
+        fn enter_scope(
+            &mut self,
+            name: &str,
+            value: &mut sir::Expression,
+            bindings: &Bindings,
+        ) -> Result<()> {
+            let names: Vec<_> = bindings.names().collect();
+            self.entered.push((name.to_string(), format!("{:?}", value), names.join(" ")));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn scopes_are_entered_once_their_values_are_rewritten() {
+        let mut module = parse_module("f(x: I64): I64 = { a = 1i64; b = 2i64; a + b }");
+        let mut doubler = Doubler::default();
+        doubler.visit_module(&mut module).unwrap();
+        assert_eq!(
+            doubler.entered,
+            [
+                ("a".to_string(), "I64Literal(2)".to_string(), "x".to_string()),
+                ("b".to_string(), "I64Literal(4)".to_string(), "x a".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn scopes_bind_their_names_in_their_bodies_only() {
+        let module = parse_module("f(x: I64): I64 = { a = x; { b = a; b } } + { c = 1i64; c }");
+        let global = &module.globals["f"];
+        let mut seen = Vec::new();
+        try_for_each(&global.body, &mut Bindings::for_global(global), |expression, bindings| {
+            if let sir::Expression::Reference { name, .. } = expression {
+                let names: Vec<_> = bindings.names().collect();
+                seen.push((name.clone(), names.join(" "), bindings.scope_index(name)));
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            seen,
+            [
+                ("x".to_string(), "x".to_string(), None),
+                ("a".to_string(), "x a".to_string(), Some(0)),
+                ("b".to_string(), "x a b".to_string(), Some(1)),
+                ("c".to_string(), "x c".to_string(), Some(0)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,62 @@
+//! The same programs run by the interpreter and compiled with LLVM, which
+//! must print the same things.
+
+#![cfg(feature = "llvm")]
+
+mod common;
+
+use common::{compile_and_run, scrap, stdout};
+
+const SOURCE: &str = "
+subtract(a: I64, b: I64): I64 = a - b
+main: I64 = {
+    from_ten = subtract(10i64, _);
+    shifts = print((1i32 << 31i32, -8i32 >> 1i32, -8i32 >>> 28i32, 1i64 << 64i64));
+    overflow = print((9223372036854775807i64 + 1i64, 2147483647i32 *| 2i32, -2147483647i32 -% 2i32));
+    divisions = print((7i64 / -2i64, -7i64 / 2i64));
+    applied = print((from_ten(3i64), subtract(_, 10i64)(3i64)));
+    unboxed = print(unbox(box((shifts, overflow))));
+    from_ten(-32i64)
+}
+";
+
+/// Turns the interpreter's `main = 42i64` line into the `42` that the
+/// compiled program prints for its result.
+fn as_compiled(interpreted: &str) -> String {
+    let (printed, main) = interpreted.trim_end().rsplit_once('\n').unwrap();
+    let value = main
+        .strip_prefix("main = ")
+        .unwrap()
+        .strip_suffix("i64")
+        .unwrap();
+    format!("{}\n{}\n", printed, value)
+}
+
+#[test]
+fn interpreted_and_compiled_programs_print_the_same() {
+    for overflow in ["wrap", "saturate"] {
+        let test = format!(
+            "interpreted_and_compiled_programs_print_the_same_{}",
+            overflow
+        );
+        let interpreted = scrap(&test, SOURCE, &["--overflow", overflow, "--interpret"]);
+        let compiled = compile_and_run(&test, SOURCE, &["--overflow", overflow]);
+        assert_eq!(as_compiled(&stdout(&interpreted)), stdout(&compiled));
+    }
+}
+
+#[test]
+fn interpreted_and_compiled_programs_trap_at_the_same_place() {
+    let test = "interpreted_and_compiled_programs_trap_at_the_same_place";
+    let interpreted = scrap(test, SOURCE, &["--overflow", "trap", "--interpret"]);
+    let compiled = compile_and_run(test, SOURCE, &["--overflow", "trap"]);
+    for output in [&interpreted, &compiled] {
+        assert_eq!(output.status.code(), Some(101));
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.ends_with("main.scrap:6:46: arithmetic overflow\n"),
+            "{}",
+            stderr
+        );
+    }
+}
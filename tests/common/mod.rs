@@ -0,0 +1,85 @@
+//! Helpers shared by the integration tests, which compile programs with the
+//! `scrap` binary and run the executables it links.
+
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    path::PathBuf,
+    process::{Command, Output},
+};
+
+/// An empty directory for one test's files.
+pub fn scratch_dir(test: &str) -> PathBuf {
+    let dir = test_dir(test);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Runs `scrap` with the given arguments in the test's directory, after
+/// writing `source` to `main.scrap` there.
+pub fn scrap(test: &str, source: &str, args: &[&str]) -> Output {
+    let dir = scratch_dir(test);
+    fs::write(dir.join("main.scrap"), source).unwrap();
+    Command::new(env!("CARGO_BIN_EXE_scrap"))
+        .current_dir(&dir)
+        .args(args)
+        .arg("main.scrap")
+        .output()
+        .unwrap()
+}
+
+/// Writes each of `modules` under its path in an empty directory for the
+/// test, which it returns. The entry module is `main.scrap`.
+pub fn write_modules(test: &str, modules: &[(&str, &str)]) -> PathBuf {
+    let dir = scratch_dir(test);
+    for (path, source) in modules {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, source).unwrap();
+    }
+    dir
+}
+
+/// Runs `scrap` with the given arguments on the test's directory, after
+/// writing `modules` there (see `write_modules`).
+pub fn scrap_modules(test: &str, modules: &[(&str, &str)], args: &[&str]) -> Output {
+    let dir = write_modules(test, modules);
+    Command::new(env!("CARGO_BIN_EXE_scrap"))
+        .current_dir(&dir)
+        .args(args)
+        .arg(".")
+        .output()
+        .unwrap()
+}
+
+/// Compiles `source` into an executable with the given options, and runs
+/// it.
+pub fn compile_and_run(test: &str, source: &str, options: &[&str]) -> Output {
+    let mut args = options.to_vec();
+    args.extend(["-o", "main"]);
+    let compiled = scrap(test, source, &args);
+    assert!(
+        compiled.status.success(),
+        "compiling failed: {}",
+        String::from_utf8_lossy(&compiled.stderr)
+    );
+    Command::new(test_dir(test).join("main")).output().unwrap()
+}
+
+/// What a program wrote to stdout, which must have succeeded.
+pub fn stdout(output: &Output) -> String {
+    assert!(
+        output.status.success(),
+        "exited with {}: {}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+/// The directory holding a test's files.
+pub fn test_dir(test: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("scrap-{}-{}", std::process::id(), test))
+}
@@ -0,0 +1,47 @@
+//! The C `main` generated for the entry point, and how it parses its
+//! command-line arguments.
+
+#![cfg(feature = "llvm")]
+
+mod common;
+
+use std::process::Command;
+
+use common::{scrap, stdout, test_dir};
+
+const ADD: &str = "add(a: I64, b: I64): (I64, I64) = (a + b, a - b)\n";
+
+/// Compiles `ADD` with it as the entry point, and runs it with the given
+/// arguments.
+fn run_add(test: &str, args: &[&str]) -> std::process::Output {
+    let compiled = scrap(test, ADD, &["--entry", "add", "-o", "main"]);
+    assert!(
+        compiled.status.success(),
+        "compiling failed: {}",
+        String::from_utf8_lossy(&compiled.stderr)
+    );
+    Command::new(test_dir(test).join("main")).args(args).output().unwrap()
+}
+
+#[test]
+fn arguments_are_parsed() {
+    let output = run_add("arguments_are_parsed", &["7", "-2"]);
+    assert_eq!(stdout(&output), "(5, 9)\n");
+}
+
+#[test]
+fn invalid_arguments_print_the_usage() {
+    for args in [&["7"][..], &["7", "2x"], &["7", ""], &["7", "99999999999999999999"]] {
+        let output = run_add("invalid_arguments_print_the_usage", args);
+        assert_eq!(output.status.code(), Some(2), "for {:?}", args);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.starts_with("usage: ") && stderr.ends_with(" <a> <b>\n"), "{}", stderr);
+    }
+}
+
+#[test]
+fn missing_entry_point_is_an_error() {
+    let output = scrap("missing_entry_point_is_an_error", ADD, &["-o", "main"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no global named main"));
+}
@@ -0,0 +1,117 @@
+//! Globals exported to C, including those from imported modules, whose
+//! names have dots in them.
+
+mod common;
+
+use std::{fs, process::Command};
+
+use common::{scrap, scratch_dir};
+
+/// Writes the given modules under a fresh root directory, and emits the C
+/// header for the program there.
+fn c_header(test: &str, modules: &[(&str, &str)]) -> std::process::Output {
+    let dir = scratch_dir(test);
+    for (path, source) in modules {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, source).unwrap();
+    }
+    Command::new(env!("CARGO_BIN_EXE_scrap"))
+        .args(["--emit", "c-header"])
+        .arg(&dir)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn dots_become_underscores() {
+    let output = c_header(
+        "dots_become_underscores",
+        &[
+            ("main.scrap", "import geometry.shapes\nexport twice(x: I64): I64 = geometry.shapes.area(x) * 2i64\n"),
+            ("geometry/shapes.scrap", "pub export area(x: I64): I64 = x * x\n"),
+        ],
+    );
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let header = String::from_utf8(output.stdout).unwrap();
+    assert!(header.contains("int64_t geometry_shapes_area(int64_t x);\n"), "{}", header);
+    assert!(header.contains("int64_t twice(int64_t x);\n"), "{}", header);
+}
+
+#[test]
+fn colliding_symbols_are_an_error() {
+    let output = c_header(
+        "colliding_symbols_are_an_error",
+        &[
+            ("main.scrap", "import geometry\nexport geometry_area(x: I64): I64 = geometry.area(x)\n"),
+            ("geometry.scrap", "pub export area(x: I64): I64 = x * x\n"),
+        ],
+    );
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("geometry.area and geometry_area are both exported as geometry_area"));
+}
+
+#[test]
+fn function_values_can_be_released_from_c() {
+    let output = c_header(
+        "function_values_can_be_released_from_c",
+        &[(
+            "main.scrap",
+            "add(a: I64, b: I64): I64 = a + b\nexport adder(x: I64): (I64): I64 = add(x, _)\n",
+        )],
+    );
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let header = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        header.contains(
+            "typedef struct scrap_FAI64RI64 {
+    int64_t (*code)(int64_t arg_0, const struct scrap_FAI64RI64 *closure);
+    void (*release)(const struct scrap_FAI64RI64 *closure);
+} scrap_FAI64RI64;
+"
+        ),
+        "{}",
+        header
+    );
+    assert!(header.contains("if (f->release) f->release(f);"), "{}", header);
+}
+
+#[test]
+fn export_is_not_a_global_name() {
+    let output = scrap(
+        "export_is_not_a_global_name",
+        "export: I64 = 1i64\nmain: I64 = export\n",
+        &["--interpret"],
+    );
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("main.scrap:1:1: syntax error"));
+}
+
+#[test]
+fn main_is_not_an_export_symbol() {
+    let output = scrap(
+        "main_is_not_an_export_symbol",
+        "export main: I64 = 1i64\n",
+        &["--interpret"],
+    );
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("main can't be exported as main, which is the entry point's symbol"));
+}
+
+#[cfg(feature = "llvm")]
+#[test]
+fn libraries_have_no_entry_point() {
+    let output = scrap(
+        "libraries_have_no_entry_point",
+        "export twice(x: I64): I64 = x * 2i64\n",
+        &[],
+    );
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("@main("));
+}
@@ -0,0 +1,20 @@
+//! Declarations of C functions.
+
+mod common;
+
+use common::scrap;
+
+#[test]
+fn extern_with_the_name_of_a_global_is_an_error() {
+    let output = scrap(
+        "extern_with_the_name_of_a_global_is_an_error",
+        "extern abs(a: I64): I64\nabs(a: I64): I64 = a\nmain: I64 = abs(1i64)\n",
+        &["--interpret"],
+    );
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8_lossy(&output.stderr).contains("abs is already declared as an extern"),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
@@ -0,0 +1,65 @@
+//! Programs linked against the collector with a heap limit small enough
+//! that they collect many times, so that the stack map walk has to find
+//! every live box in every frame.
+
+#![cfg(all(feature = "llvm", target_arch = "x86_64"))]
+
+mod common;
+
+use common::{compile_and_run, scrap, stdout};
+
+/// Each `churn` allocates a box that is garbage as soon as it is unboxed,
+/// 8^n times over.
+const CHURN: &str = "
+churn(x: I64): I64 = unbox(box((x, x + 1i64))).elem_1
+churn8(x: I64): I64 = churn(churn(churn(churn(churn(churn(churn(churn(x))))))))
+churn64(x: I64): I64 = churn8(churn8(churn8(churn8(churn8(churn8(churn8(churn8(x))))))))
+churn512(x: I64): I64 = churn64(churn64(churn64(churn64(churn64(churn64(churn64(churn64(x))))))))
+churn4096(x: I64): I64 = churn512(churn512(churn512(churn512(churn512(churn512(churn512(churn512(x))))))))
+";
+
+const GC: &[&str] = &["--gc", "--heap-limit", "4096"];
+
+#[test]
+fn garbage_is_collected() {
+    let source = format!("{}\nmain: (I64, I64) = (churn4096(0i64), churn4096(1i64))\n", CHURN);
+    let output = compile_and_run("garbage_is_collected", &source, GC);
+    assert_eq!(stdout(&output), "(4096, 4097)\n");
+}
+
+#[test]
+fn live_boxes_survive_collection() {
+    // Each level holds a box across the collections in the levels below
+    // it, so live boxes are spread over several frames.
+    let source = format!(
+        "{}
+hold1(x: I64): I64 = {{ kept = box((x, box(x * 3i64))); churn512(x) - x + unbox(unbox(kept).elem_1) }}
+hold2(x: I64): I64 = {{ kept = box(x); hold1(x) + hold1(x + 1i64) + unbox(kept) }}
+hold3(x: I64): I64 = {{ kept = box(x); hold2(x) + churn4096(x) - x + unbox(kept) }}
+main: (I64, I64) = (hold3(1i64), hold3(10i64))
+",
+        CHURN
+    );
+    let output = compile_and_run("live_boxes_survive_collection", &source, GC);
+    // hold1(x) = 512 + 3x, hold2(x) = 1027 + 7x, hold3(x) = 5123 + 8x.
+    assert_eq!(stdout(&output), "(5131, 5203)\n");
+}
+
+#[test]
+fn exceeding_the_heap_limit_panics() {
+    let source = "main: (I64, I64) = unbox(box((1i64, 2i64)))\n";
+    let output = compile_and_run("exceeding_the_heap_limit_panics", source, &["--gc", "--heap-limit", "16"]);
+    assert_eq!(output.status.code(), Some(101));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("out of memory"));
+}
+
+#[test]
+fn gc_requires_x86_64() {
+    let output = scrap(
+        "gc_requires_x86_64",
+        "main: I64 = 0i64\n",
+        &["--gc", "--target", "aarch64-unknown-linux-gnu", "-o", "main"],
+    );
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("only supported on x86-64"));
+}
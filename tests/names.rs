@@ -0,0 +1,69 @@
+//! Resolving the names used in expressions.
+
+mod common;
+
+use common::{scrap, scrap_modules, stdout};
+
+#[test]
+fn unknown_names_are_reported_where_they_are_used() {
+    let output = scrap(
+        "unknown_names_are_reported_where_they_are_used",
+        "main: I64 = {\n  a = 1i64;\n  a + b\n}\n",
+        &["--interpret"],
+    );
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("main.scrap:3:7: unknown name b"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn shadowing_a_prelude_global_does_not_change_the_others() {
+    let output = scrap(
+        "shadowing_a_prelude_global_does_not_change_the_others",
+        "less(a: I64, b: I64): I64 = 7i64\nmain: (I64, I64) = (less(1i64, 2i64), greater(2i64, 1i64))\n",
+        &["--interpret"],
+    );
+    assert!(stdout(&output).contains("main = (7i64, 1i64)\n"));
+}
+
+#[test]
+fn shadowing_a_prelude_global_only_affects_its_own_module() {
+    let output = scrap_modules(
+        "shadowing_a_prelude_global_only_affects_its_own_module",
+        &[
+            (
+                "main.scrap",
+                "import util\nless(a: I64, b: I64): I64 = 7i64\nmain: (I64, I64) = (less(1i64, 2i64), util.greater_than(2i64, 1i64))\n",
+            ),
+            ("util.scrap", "pub greater_than(a: I64, b: I64): I64 = less(b, a)\n"),
+        ],
+        &["--interpret"],
+    );
+    assert!(stdout(&output).contains("main = (7i64, 1i64)\n"));
+}
+
+#[test]
+fn imported_modules_cannot_name_the_entry_modules_globals() {
+    let output = scrap_modules(
+        "imported_modules_cannot_name_the_entry_modules_globals",
+        &[
+            (
+                "main.scrap",
+                "import util\nhelper: I64 = 1i64\nmain: I64 = util.neg(5i64)\n",
+            ),
+            ("util.scrap", "pub neg(a: I64): I64 = a + helper\n"),
+        ],
+        &["--interpret"],
+    );
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("util.scrap:1:28: unknown name helper"),
+        "{}",
+        stderr
+    );
+}
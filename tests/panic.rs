@@ -0,0 +1,73 @@
+//! Panics, which take their type from where they are used, and stop the
+//! program with status 101 whether it is compiled or interpreted.
+
+mod common;
+
+use scrap::{options::Options, parser, passes::Registry, sir, visit};
+
+use common::scrap;
+
+/// The types of the panics in a program, in the order they appear in its
+/// globals, sorted by name.
+fn panic_types(source: &str) -> Vec<String> {
+    let (_, mut module) = parser::parse(source, &"main.scrap".into(), parser::source_file).unwrap();
+    Registry::new().run(&mut module, &Options::default()).unwrap();
+    let mut names: Vec<_> = module.globals.keys().filter(|name| !name.starts_with("prelude.")).collect();
+    names.sort();
+
+    let mut types = Vec::new();
+    for name in names {
+        visit::try_for_each(&module.globals[name].body, &mut Default::default(), |expression, _| {
+            if let sir::Expression::Panic { data_type, .. } = expression {
+                types.push(data_type.to_string());
+            }
+            Ok(())
+        })
+        .unwrap();
+    }
+    types
+}
+
+#[test]
+fn panics_take_the_type_of_their_context() {
+    let types = panic_types(
+        "
+pair: (I64, I32) = (1i64, panic(\"element\"))
+narrow: I32 = 1i32 + panic(\"operand\")
+twice(a: I32): I32 = a * 2i32
+argument: I32 = twice(panic(\"argument\"))
+nested: I32 = { x = 2i32; x * -panic(\"nested\") }
+",
+    );
+    assert_eq!(types, ["I32", "I32", "I32", "I32"]);
+}
+
+#[test]
+fn panics_without_a_context_are_i64() {
+    assert_eq!(panic_types("main: I32 = { x = panic(\"unused\"); 1i32 }"), ["I64"]);
+}
+
+#[test]
+fn interpreted_panics_exit_with_101() {
+    let output = scrap(
+        "interpreted_panics_exit_with_101",
+        "main: I32 = 1i32 + panic(\"boom\")\n",
+        &["--interpret"],
+    );
+    assert_eq!(output.status.code(), Some(101));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.ends_with("main.scrap:1:20: boom\n"), "{}", stderr);
+}
+
+#[cfg(feature = "llvm")]
+#[test]
+fn compiled_panics_exit_with_101() {
+    let output = common::compile_and_run(
+        "compiled_panics_exit_with_101",
+        "twice(a: I32): I32 = a * 2i32\nmain: (I32, I64) = (twice(panic(\"boom\")), 1i64)\n",
+        &[],
+    );
+    assert_eq!(output.status.code(), Some(101));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.ends_with("main.scrap:2:27: boom\n"), "{}", stderr);
+}
@@ -0,0 +1,29 @@
+//! Globals of function type, named partial applications.
+
+mod common;
+
+#[cfg(feature = "llvm")]
+use common::compile_and_run;
+use common::{scrap, stdout};
+
+const SOURCE: &str = "add(a: I64, b: I64): I64 = a + b
+inc: (I64): I64 = add(1i64, _)
+main: I64 = inc(41i64)
+";
+
+#[test]
+fn interpreted_globals_can_be_partial_applications() {
+    let output = scrap(
+        "interpreted_globals_can_be_partial_applications",
+        SOURCE,
+        &["--interpret"],
+    );
+    assert!(stdout(&output).contains("main = 42i64\n"));
+}
+
+#[cfg(feature = "llvm")]
+#[test]
+fn compiled_globals_can_be_partial_applications() {
+    let output = compile_and_run("compiled_globals_can_be_partial_applications", SOURCE, &[]);
+    assert_eq!(stdout(&output), "42\n");
+}
@@ -0,0 +1,118 @@
+//! Programs relinked so that the generated code's calls to `malloc` and
+//! `free` are counted, to check that every box is released exactly once,
+//! and that rebuilding a box in place doesn't allocate.
+
+#![cfg(all(feature = "llvm", target_os = "linux"))]
+
+mod common;
+
+use std::{fs, process::Command};
+
+use common::{scrap, stdout, test_dir};
+
+/// Stands in for `malloc` and `free` in the generated code, through the
+/// linker's `--wrap`, and reports the counts when the program exits.
+const COUNTING_SHIM: &str = r#"
+#include <stdio.h>
+#include <stdlib.h>
+
+void *__real_malloc(size_t size);
+void __real_free(void *memory);
+
+static long allocated, freed;
+
+void *__wrap_malloc(size_t size) {
+    allocated++;
+    return __real_malloc(size);
+}
+
+void __wrap_free(void *memory) {
+    freed++;
+    __real_free(memory);
+}
+
+__attribute__((destructor)) static void report(void) {
+    fprintf(stderr, "allocated %ld freed %ld\n", allocated, freed);
+}
+"#;
+
+/// Compiles `source`, links it with the counting shim and runs it. Returns
+/// what it printed, and how many boxes it allocated and freed.
+fn run_counted(test: &str, source: &str) -> (String, u64, u64) {
+    let compiled = scrap(test, source, &["-o", "main"]);
+    assert!(
+        compiled.status.success(),
+        "compiling failed: {}",
+        String::from_utf8_lossy(&compiled.stderr)
+    );
+    let dir = test_dir(test);
+    fs::write(dir.join("shim.c"), COUNTING_SHIM).unwrap();
+    let linked = Command::new("cc")
+        .current_dir(&dir)
+        .args(["main.o", "shim.c", "-Wl,--wrap=malloc", "-Wl,--wrap=free", "-o", "counted"])
+        .status()
+        .unwrap();
+    assert!(linked.success());
+
+    let output = Command::new(dir.join("counted")).output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let counts: Vec<u64> = stderr
+        .lines()
+        .last()
+        .and_then(|line| line.strip_prefix("allocated "))
+        .map(|line| line.split(" freed ").map(|count| count.parse().unwrap()).collect())
+        .unwrap_or_else(|| panic!("no counts in {:?}", stderr));
+    (stdout(&output), counts[0], counts[1])
+}
+
+#[test]
+fn every_box_is_freed() {
+    let source = "
+add(a: I64, b: I64): I64 = a + b
+apply(f: (I64): I64, x: I64): I64 = f(x)
+nested(x: I64): Box<(I64, Box<I64>)> = box((x, box(x * 2i64)))
+main: (I64, I64) = {
+    kept = nested(3i64);
+    unused = box(1i64);
+    shared = box((4i64, box(5i64)));
+    (apply(add(unbox(unbox(kept).elem_1), _), unbox(shared).elem_0), unbox(unbox(shared).elem_1))
+}
+";
+    let (output, allocated, freed) = run_counted("every_box_is_freed", source);
+    assert_eq!(output, "(10, 5)\n");
+    assert!(allocated >= 6, "only {} allocations", allocated);
+    assert_eq!(allocated, freed);
+}
+
+#[test]
+fn rebuilt_box_reuses_memory() {
+    // Each box is only used to build the next, so they can all share the
+    // first one's memory.
+    let source = "
+main: I64 = {
+    a = box((0i64, 0i64));
+    b = box((unbox(a).elem_0 + 1i64, 0i64));
+    c = box((unbox(b).elem_0 + 1i64, 0i64));
+    unbox(c).elem_0
+}
+";
+    let (output, allocated, freed) = run_counted("rebuilt_box_reuses_memory", source);
+    assert_eq!(output, "2\n");
+    assert_eq!((allocated, freed), (1, 1));
+}
+
+#[test]
+fn shared_box_is_not_reused() {
+    // `a` is still used after `b` is built from it, so `b` needs memory of
+    // its own.
+    let source = "
+main: (I64, I64) = {
+    a = box((1i64, 2i64));
+    b = box((unbox(a).elem_0 + 10i64, 0i64));
+    (unbox(b).elem_0, unbox(a).elem_1)
+}
+";
+    let (output, allocated, freed) = run_counted("shared_box_is_not_reused", source);
+    assert_eq!(output, "(11, 2)\n");
+    assert_eq!((allocated, freed), (2, 2));
+}
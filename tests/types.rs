@@ -0,0 +1,60 @@
+//! Types declared with `type`, which are only in scope in the file that
+//! declares them.
+
+mod common;
+
+use scrap::{loader::load_program, options::Options, passes::Registry};
+
+use common::write_modules;
+#[cfg(feature = "llvm")]
+use common::{scrap_modules, stdout, test_dir};
+
+/// Two modules that each declare a `Node` of their own, laid out
+/// differently.
+const NODES: &[(&str, &str)] = &[
+    (
+        "main.scrap",
+        "import util
+type Node = (I32, I32, I32, Box<Node>)
+node: Box<Node> = box((1i32, 2i32, 3i32, null<Node>))
+main: (I64, I32) = (util.first(5i64), unbox(node).elem_2)
+",
+    ),
+    (
+        "util.scrap",
+        "type Node = (I64, Box<Node>)
+node(x: I64): Box<Node> = box((x, null<Node>))
+pub first(x: I64): I64 = unbox(node(x)).elem_0
+",
+    ),
+];
+
+#[test]
+fn types_of_the_same_name_in_different_modules_are_different() {
+    let dir = write_modules(
+        "types_of_the_same_name_in_different_modules_are_different",
+        NODES,
+    );
+    let program = load_program(&dir, &Registry::new(), &Options::default()).unwrap();
+    let main_node = &program.globals["node"].return_type;
+    let util_node = &program.globals["util.node"].return_type;
+    assert_eq!(main_node.to_string(), "Box<Node>");
+    assert_eq!(util_node.to_string(), "Box<util.Node>");
+    assert_ne!(main_node, util_node);
+}
+
+#[cfg(feature = "llvm")]
+#[test]
+fn types_of_the_same_name_in_different_modules_are_compiled_separately() {
+    let test = "types_of_the_same_name_in_different_modules_are_compiled_separately";
+    let compiled = scrap_modules(test, NODES, &["-o", "main"]);
+    assert!(
+        compiled.status.success(),
+        "compiling failed: {}",
+        String::from_utf8_lossy(&compiled.stderr)
+    );
+    let output = std::process::Command::new(test_dir(test).join("main"))
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output), "(5, 3)\n");
+}